@@ -1,10 +1,11 @@
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
 use rand::{rng, RngCore};
+use redact::Secret;
 use std::{env, fs, path::PathBuf};
 use storage_backend::{
     error::StorageError,
-    storage::Storage,
-    storage_config::StorageConfig,
+    storage::{RestoreMode, Storage},
+    storage_config::{KdfParams, StorageConfig, ValueCompression},
 };
 
 fn temp_storage() -> PathBuf {
@@ -22,12 +23,33 @@ fn backup_temp_storage() -> PathBuf {
 }
 
 fn create_path_and_storage(
+) -> Result<(PathBuf, StorageConfig, Storage), StorageError> {
+    create_path_and_storage_with_prefix(None)
+}
+
+fn create_path_and_storage_with_prefix(
+    prefix_length: Option<usize>,
 ) -> Result<(PathBuf, StorageConfig, Storage), StorageError> {
     let path = &temp_storage();
 
     let config = StorageConfig {
         path: path.to_string_lossy().to_string(),
         password: None,
+        kdf_params: KdfParams::default(),
+        encrypt_keys: false,
+        password_policy: None,
+        rocksdb: None,
+        prefix_length,
+        cache_capacity: None,
+        backup_batch_size: None,
+        metrics: false,
+        max_key_length: None,
+        max_value_bytes: None,
+        compression: ValueCompression::None,
+        sync_writes: false,
+        disable_wal: false,
+        merge_operator: None,
+        lossy_utf8: false,
     };
     let storage = Storage::new(&config)?;
 
@@ -67,7 +89,7 @@ fn bench_create_backup(c: &mut Criterion) {
     let number_of_items = 1_000_000;
     let backup_path = backup_temp_storage();
     let dek_path = backup_temp_storage();
-    let password = "password".to_string();
+    let password = Secret::from("password".to_string());
 
     let (_, _, storage) = create_path_and_storage().unwrap();
     write_db(&storage, number_of_items);
@@ -91,7 +113,7 @@ fn bench_restore_backup(c: &mut Criterion) {
     let number_of_items = 1_000_000;
     let backup_path = backup_temp_storage();
     let dek_path = backup_temp_storage();
-    let password = "password".to_string();
+    let password = Secret::from("password".to_string());
 
     let (_, _, storage) = create_path_and_storage().unwrap();
     write_db(&storage, number_of_items);
@@ -103,7 +125,9 @@ fn bench_restore_backup(c: &mut Criterion) {
         BenchmarkId::new("restore_backup", number_of_items),
         |b| {
             b.iter(|| {
-                store.restore_backup(&backup_path, &dek_path, password.clone()).unwrap();
+                store
+                    .restore_backup(&backup_path, &dek_path, password.clone(), RestoreMode::Merge)
+                    .unwrap();
             });
         },
     );
@@ -114,10 +138,130 @@ fn bench_restore_backup(c: &mut Criterion) {
     group.finish();
 }
 
+fn bench_restore_backup_replace(c: &mut Criterion) {
+    let mut group = c.benchmark_group("backup");
+    let number_of_items = 1_000_000;
+    let backup_path = backup_temp_storage();
+    let dek_path = backup_temp_storage();
+    let password = Secret::from("password".to_string());
+
+    let (_, _, storage) = create_path_and_storage().unwrap();
+    write_db(&storage, number_of_items);
+    storage.backup(backup_path.clone(), dek_path.clone(), password.clone()).unwrap();
+
+    group.sample_size(10).bench_function(
+        BenchmarkId::new("restore_backup_replace", number_of_items),
+        |b| {
+            b.iter(|| {
+                storage
+                    .restore_backup(&backup_path, &dek_path, password.clone(), RestoreMode::Replace)
+                    .unwrap();
+            });
+        },
+    );
+
+    Storage::delete_db_files(storage).unwrap();
+    fs::remove_file(backup_path).unwrap();
+    fs::remove_file(dek_path).unwrap();
+    group.finish();
+}
+
+fn bench_partial_compare(c: &mut Criterion) {
+    let mut group = c.benchmark_group("partial_compare");
+
+    for (name, prefix_length) in [
+        ("without_prefix_extractor", None),
+        ("with_prefix_extractor", Some(9)),
+    ] {
+        let (_, _, storage) = create_path_and_storage_with_prefix(prefix_length).unwrap();
+        let tx = storage.begin_transaction();
+        for i in 0..100 {
+            for j in 0..1_000 {
+                storage
+                    .transactional_write(&format!("bitvmx/{}/topic_{}", i % 10, j), "value", tx)
+                    .unwrap();
+            }
+        }
+        storage.commit_transaction(tx).unwrap();
+
+        group.sample_size(10).bench_function(name, |b| {
+            b.iter(|| {
+                storage.partial_compare("bitvmx/7/topic_99").unwrap();
+            });
+        });
+
+        Storage::delete_db_files(storage).unwrap();
+    }
+
+    group.finish();
+}
+
+fn bench_change_password(c: &mut Criterion) {
+    let mut group = c.benchmark_group("password");
+    let config = StorageConfig::new(
+        temp_storage().to_string_lossy().to_string(),
+        Some(Secret::from("ABC!@#123pass".to_string())),
+    );
+    let storage = Storage::new(&config).unwrap();
+    let passwords = [
+        Secret::from("ABC!@#123pass".to_string()),
+        Secret::from("XYZ$%^456word".to_string()),
+    ];
+    let mut current = 0;
+
+    group.sample_size(10).bench_function("change_password", |b| {
+        b.iter(|| {
+            storage
+                .change_password(passwords[current].clone(), passwords[1 - current].clone())
+                .unwrap();
+            current = 1 - current;
+        });
+    });
+
+    Storage::delete_db_files(storage).unwrap();
+    group.finish();
+}
+
+fn bench_write_durability(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_durability");
+    let number_of_items = 100;
+
+    for (name, sync_writes, disable_wal) in [
+        ("default", false, false),
+        ("sync_writes", true, false),
+        ("disable_wal", false, true),
+    ] {
+        let mut config = StorageConfig::new(temp_storage().to_string_lossy().to_string(), None);
+        config.sync_writes = sync_writes;
+        config.disable_wal = disable_wal;
+        let storage = Storage::new(&config).unwrap();
+
+        group.sample_size(10).bench_function(
+            BenchmarkId::new(name, number_of_items),
+            |b| {
+                b.iter(|| {
+                    for i in 0..number_of_items {
+                        storage
+                            .write(&format!("key_{}", i), &format!("value_{}", i))
+                            .unwrap();
+                    }
+                });
+            },
+        );
+
+        Storage::delete_db_files(storage).unwrap();
+    }
+    group.finish();
+}
+
 criterion_group!(
     benches,
     bench_create_storage,
     bench_create_backup,
-    bench_restore_backup
+    bench_restore_backup,
+    bench_restore_backup_replace,
+    bench_partial_compare,
+    bench_change_password,
+    bench_write_durability
 );
 criterion_main!(benches);
@@ -0,0 +1,118 @@
+use crate::{
+    codec::{Codec, JsonCodec},
+    error::StorageError,
+    storage::{KeyValueStore, Storage},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Runs the operations of a `Storage` on the Tokio blocking thread pool, so they do not block the
+/// async runtime. Clones share the same storage, and its operations run one at a time.
+///
+/// A transaction is only identified by its id, so any clone can use it. Drive each transaction
+/// from a single task: writes from tasks racing on the same transaction end up in it in whatever
+/// order they happen to run.
+pub struct AsyncStorage<C = JsonCodec> {
+    storage: Arc<Mutex<Storage<C>>>,
+}
+
+impl<C> Clone for AsyncStorage<C> {
+    fn clone(&self) -> Self {
+        AsyncStorage {
+            storage: Arc::clone(&self.storage),
+        }
+    }
+}
+
+impl<C: Codec + Send + 'static> AsyncStorage<C> {
+    pub fn new(storage: Storage<C>) -> Self {
+        AsyncStorage {
+            storage: Arc::new(Mutex::new(storage)),
+        }
+    }
+
+    pub async fn read(&self, key: &str) -> Result<Option<String>, StorageError> {
+        let key = key.to_string();
+        self.run(move |storage| storage.read(&key)).await
+    }
+
+    pub async fn write(&self, key: &str, value: &str) -> Result<(), StorageError> {
+        let (key, value) = (key.to_string(), value.to_string());
+        self.run(move |storage| storage.write(&key, &value)).await
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let key = key.to_string();
+        self.run(move |storage| storage.delete(&key)).await
+    }
+
+    pub async fn has_key(&self, key: &str) -> Result<bool, StorageError> {
+        let key = key.to_string();
+        self.run(move |storage| storage.has_key(&key)).await
+    }
+
+    pub async fn get<V>(&self, key: &str) -> Result<Option<V>, StorageError>
+    where
+        V: DeserializeOwned + Send + 'static,
+    {
+        let key = key.to_string();
+        self.run(move |storage| storage.get(key)).await
+    }
+
+    pub async fn set<V>(
+        &self,
+        key: &str,
+        value: V,
+        transaction_id: Option<Uuid>,
+    ) -> Result<(), StorageError>
+    where
+        V: Serialize + Send + 'static,
+    {
+        let key = key.to_string();
+        self.run(move |storage| storage.set(key, value, transaction_id))
+            .await
+    }
+
+    pub async fn transactional_write(
+        &self,
+        key: &str,
+        value: &str,
+        transaction_id: Uuid,
+    ) -> Result<(), StorageError> {
+        let (key, value) = (key.to_string(), value.to_string());
+        self.run(move |storage| storage.transactional_write(&key, &value, transaction_id))
+            .await
+    }
+
+    pub async fn begin_transaction(&self) -> Result<Uuid, StorageError> {
+        self.run(|storage| Ok(storage.begin_transaction())).await
+    }
+
+    pub async fn commit_transaction(&self, transaction_id: Uuid) -> Result<(), StorageError> {
+        self.run(move |storage| storage.commit_transaction(transaction_id))
+            .await
+    }
+
+    pub async fn rollback_transaction(&self, transaction_id: Uuid) -> Result<(), StorageError> {
+        self.run(move |storage| storage.rollback_transaction(transaction_id))
+            .await
+    }
+
+    /// Runs `f` on the blocking thread pool with the storage locked.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, StorageError>
+    where
+        F: FnOnce(&Storage<C>) -> Result<T, StorageError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let storage = Arc::clone(&self.storage);
+        tokio::task::spawn_blocking(move || {
+            let storage = storage.lock().map_err(|_| {
+                StorageError::TaskFailed("a previous operation panicked".to_string())
+            })?;
+            f(&storage)
+        })
+        .await
+        .map_err(|error| StorageError::TaskFailed(error.to_string()))?
+    }
+}
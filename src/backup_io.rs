@@ -1,22 +1,144 @@
-use age::{Decryptor, Encryptor, scrypt::Identity, secrecy::SecretString, stream::{StreamReader, StreamWriter}};
-use std::io::{self, BufRead, Read, Write};
+use age::{Decryptor, Encryptor, IdentityFile, scrypt, secrecy::SecretString, stream::{StreamReader, StreamWriter}, x25519};
+use crate::error::StorageError;
+use sha2::{Digest, Sha256};
+use std::io::{self, BufRead, Chain, Cursor, Read, Write};
+use std::iter;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version byte written at the start of every binary backup stream. Legacy backups start directly
+/// with hex-encoded data, so their first byte is always an ASCII hex digit.
+pub const BACKUP_FORMAT_VERSION: u8 = 3;
+/// Version byte of backups whose manifest only holds the creation time.
+pub const BACKUP_FORMAT_VERSION_WITHOUT_SEQUENCE: u8 = 2;
+/// Version byte of binary backups written before the manifest was added.
+pub const BACKUP_FORMAT_VERSION_WITHOUT_MANIFEST: u8 = 1;
+/// Written in place of a key length to start the trailer.
+const TRAILER_MARKER: u32 = u32::MAX;
+/// Written in plaintext before the age stream of a backup and before the encrypted DEK of its DEK
+/// file, followed by the `BackupId` they share. Neither an age header nor a cocoon container
+/// starts with it, so files written without an id are still told apart.
+pub const BACKUP_ID_MAGIC: [u8; 8] = *b"bvmx-id\n";
+
+/// Random id shared by a backup and its DEK file, so a DEK file from another backup is caught
+/// before anything is decrypted.
+pub type BackupId = [u8; 16];
+
+/// Rest of a stream after `read_backup_id`, with any bytes it read that were not an id in front.
+pub type AfterBackupId<R> = Chain<Cursor<Vec<u8>>, R>;
+
+/// Reads the backup id at the start of `reader`, returning it along with the rest of the stream.
+/// A stream that does not start with an id comes back whole, with no id.
+pub fn read_backup_id<R: Read>(mut reader: R) -> io::Result<(Option<BackupId>, AfterBackupId<R>)> {
+    let mut header = [0u8; BACKUP_ID_MAGIC.len() + 16];
+    let mut read = 0;
+    while read < header.len() {
+        match reader.read(&mut header[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+
+    if read == header.len() && header.starts_with(&BACKUP_ID_MAGIC) {
+        let mut backup_id = BackupId::default();
+        backup_id.copy_from_slice(&header[BACKUP_ID_MAGIC.len()..]);
+        Ok((Some(backup_id), Cursor::new(Vec::new()).chain(reader)))
+    } else {
+        Ok((None, Cursor::new(header[..read].to_vec()).chain(reader)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackupFormat {
+    /// `hex(key),hex(value);` records without a version header.
+    LegacyHex,
+    /// `[u32 key_len][key][u32 value_len][value]` records after the version byte.
+    Binary,
+    /// `Binary` records between a manifest holding the `u64` creation time, in unix milliseconds,
+    /// and the `u64` RocksDB sequence number of the snapshot backed up, and a trailer holding the
+    /// `u64` entry count and the SHA-256 of the records. Both counts are only known once every
+    /// record is written, hence the trailer. Older manifests lack the sequence number.
+    BinaryWithManifest,
+}
 
 pub struct BackupFileWriter<W: Write> {
     inner: StreamWriter<W>,
+    created_at: u64,
+    entries: u64,
+    checksum: Sha256,
 }
 
 impl <W: Write> BackupFileWriter<W> {
-    pub fn new(writer: W, password: Vec<u8>) -> io::Result<Self> {
+    /// Encrypts the backup with `password`. `work_factor` overrides the scrypt work factor age
+    /// would otherwise calibrate for this machine. `sequence` is recorded in the manifest and
+    /// `backup_id` in plaintext before the encrypted stream.
+    pub fn new(writer: W, password: &[u8], work_factor: Option<u8>, sequence: u64, backup_id: &BackupId) -> Result<Self, StorageError> {
         let passphrase = SecretString::new(hex::encode(password).into());
-        let encryptor = Encryptor::with_user_passphrase(passphrase);
+        let mut recipient = scrypt::Recipient::new(passphrase);
+        if let Some(work_factor) = work_factor {
+            check_work_factor(work_factor)?;
+            recipient.set_work_factor(work_factor);
+        }
+        let encryptor = Encryptor::with_recipients(iter::once(&recipient as &dyn age::Recipient))
+            .map_err(StorageError::BackupEncryptFailed)?;
+        Self::from_encryptor(writer, encryptor, sequence, backup_id)
+    }
+
+    /// Encrypts the backup to age X25519 public keys instead of a passphrase.
+    pub fn new_with_recipients(writer: W, recipients: Vec<x25519::Recipient>, sequence: u64, backup_id: &BackupId) -> Result<Self, StorageError> {
+        let encryptor = Encryptor::with_recipients(
+            recipients.iter().map(|recipient| recipient as &dyn age::Recipient),
+        )
+        .map_err(StorageError::BackupEncryptFailed)?;
+        Self::from_encryptor(writer, encryptor, sequence, backup_id)
+    }
+
+    fn from_encryptor(mut writer: W, encryptor: Encryptor, sequence: u64, backup_id: &BackupId) -> Result<Self, StorageError> {
+        writer.write_all(&BACKUP_ID_MAGIC)?;
+        writer.write_all(backup_id)?;
         let stream_writer = encryptor.wrap_output(writer)?;
-        Ok(BackupFileWriter {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let mut backup_writer = BackupFileWriter {
             inner: stream_writer,
-        })
+            created_at,
+            entries: 0,
+            checksum: Sha256::new(),
+        };
+        backup_writer.write_all(&[BACKUP_FORMAT_VERSION])?;
+        backup_writer.write_all(&created_at.to_le_bytes())?;
+        backup_writer.write_all(&sequence.to_le_bytes())?;
+        Ok(backup_writer)
+    }
+
+    /// Creation time recorded in the manifest, in unix milliseconds.
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    pub fn write_record(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let key_len = field_len(key)?;
+        let value_len = field_len(value)?;
+        self.inner.write_all(&key_len)?;
+        self.inner.write_all(key)?;
+        self.inner.write_all(&value_len)?;
+        self.inner.write_all(value)?;
+
+        self.entries += 1;
+        update_checksum(&mut self.checksum, &key_len, key, &value_len, value);
+        Ok(())
     }
 
+    /// Writes the trailer and finishes the encrypted stream.
     pub fn finish(self) -> io::Result<W> {
-        self.inner.finish()
+        let BackupFileWriter { mut inner, entries, checksum, .. } = self;
+        inner.write_all(&TRAILER_MARKER.to_le_bytes())?;
+        inner.write_all(&entries.to_le_bytes())?;
+        inner.write_all(&checksum.finalize())?;
+        inner.finish()
     }
 }
 
@@ -36,25 +158,158 @@ pub struct BackupFileReader<R: Read> {
     buf: Vec<u8>,
     pos: usize,
     cap: usize,
+    created_at: Option<u64>,
+    sequence: Option<u64>,
+    entries: u64,
+    // Set while the records of a backup with a manifest are read, until its trailer is checked.
+    checksum: Option<Sha256>,
 }
 
 impl <R: Read> BackupFileReader<R> {
-    pub fn new(reader: R, password: Vec<u8>) -> io::Result<Self> {
+    /// Decrypts a backup encrypted with `password`. `max_work_factor` overrides the highest scrypt
+    /// work factor accepted, which age otherwise derives from the speed of this machine.
+    pub fn new(reader: R, password: &[u8], max_work_factor: Option<u8>) -> Result<Self, StorageError> {
         let passphrase = SecretString::new(hex::encode(password).into());
+        let mut identity = scrypt::Identity::new(passphrase);
+        if let Some(max_work_factor) = max_work_factor {
+            check_work_factor(max_work_factor)?;
+            identity.set_max_work_factor(max_work_factor);
+        }
+        Self::from_identities(reader, iter::once(&identity as &dyn age::Identity))
+    }
+
+    /// Decrypts a backup that was encrypted to the public key of `identity`.
+    pub fn new_with_identity(reader: R, identity: &x25519::Identity) -> Result<Self, StorageError> {
+        Self::from_identities(reader, iter::once(identity as &dyn age::Identity))
+    }
+
+    /// Decrypts a backup with any of the identities stored in an age identity file.
+    pub fn new_with_identity_file<P: AsRef<Path>>(reader: R, identity_file: P) -> Result<Self, StorageError> {
+        let identities = IdentityFile::from_file(identity_file.as_ref().to_string_lossy().to_string())?
+            .into_identities()
+            .map_err(StorageError::BackupDecryptFailed)?;
+        Self::from_identities(reader, identities.iter().map(|identity| identity.as_ref()))
+    }
+
+    fn from_identities<'a>(
+        reader: R,
+        identities: impl Iterator<Item = &'a dyn age::Identity>,
+    ) -> Result<Self, StorageError> {
         let decryptor = Decryptor::new(reader)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            .map_err(StorageError::BackupDecryptFailed)?;
 
-        let identities: Vec<Box<dyn age::Identity>> = vec![Box::new(Identity::new(passphrase))];
-        let stream_reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref()))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let stream_reader = decryptor.decrypt(identities)
+            .map_err(StorageError::BackupDecryptFailed)?;
         
         Ok(BackupFileReader {
             inner: stream_reader,
             buf: vec![0; 8192],
             pos: 0,
             cap: 0,
+            created_at: None,
+            sequence: None,
+            entries: 0,
+            checksum: None,
         })
     }
+
+    /// Detects the backup format, consuming the version byte and the manifest of binary backups.
+    pub fn read_format(&mut self) -> io::Result<BackupFormat> {
+        match self.fill_buf()?.first() {
+            Some(&version @ (BACKUP_FORMAT_VERSION | BACKUP_FORMAT_VERSION_WITHOUT_SEQUENCE)) => {
+                self.consume(1);
+                let mut created_at = [0u8; 8];
+                self.read_exact(&mut created_at)?;
+                self.created_at = Some(u64::from_le_bytes(created_at));
+                if version == BACKUP_FORMAT_VERSION {
+                    let mut sequence = [0u8; 8];
+                    self.read_exact(&mut sequence)?;
+                    self.sequence = Some(u64::from_le_bytes(sequence));
+                }
+                self.checksum = Some(Sha256::new());
+                Ok(BackupFormat::BinaryWithManifest)
+            }
+            Some(&BACKUP_FORMAT_VERSION_WITHOUT_MANIFEST) => {
+                self.consume(1);
+                Ok(BackupFormat::Binary)
+            }
+            _ => Ok(BackupFormat::LegacyHex),
+        }
+    }
+
+    /// Creation time of the backup in unix milliseconds, once `read_format` has read its manifest.
+    pub fn created_at(&self) -> Option<u64> {
+        self.created_at
+    }
+
+    /// RocksDB sequence number of the snapshot the backup was written from, once `read_format`
+    /// has read a manifest that records it.
+    pub fn sequence(&self) -> Option<u64> {
+        self.sequence
+    }
+
+    /// Reads the next binary record, returning `None` at the end of the stream. With a manifest,
+    /// the end is the trailer, and a count or checksum that does not match the records read fails
+    /// with `InvalidData`.
+    pub fn read_record(&mut self) -> io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if self.fill_buf()?.is_empty() {
+            if self.checksum.is_some() {
+                return Err(invalid_data("backup trailer is missing".to_string()));
+            }
+            return Ok(None);
+        }
+
+        let key_len = self.read_len()?;
+        if key_len == TRAILER_MARKER.to_le_bytes() {
+            if let Some(checksum) = self.checksum.take() {
+                self.read_trailer(checksum)?;
+                return Ok(None);
+            }
+        }
+        let key = self.read_field(key_len)?;
+        let value_len = self.read_len()?;
+        let value = self.read_field(value_len)?;
+
+        if let Some(checksum) = &mut self.checksum {
+            self.entries += 1;
+            update_checksum(checksum, &key_len, &key, &value_len, &value);
+        }
+        Ok(Some((key, value)))
+    }
+
+    fn read_trailer(&mut self, checksum: Sha256) -> io::Result<()> {
+        let mut entries = [0u8; 8];
+        self.read_exact(&mut entries)?;
+        let mut expected_checksum = [0u8; 32];
+        self.read_exact(&mut expected_checksum)?;
+
+        let entries = u64::from_le_bytes(entries);
+        if entries != self.entries {
+            return Err(invalid_data(format!(
+                "backup holds {} entries but its trailer records {}",
+                self.entries, entries
+            )));
+        }
+        if checksum.finalize().as_slice() != expected_checksum {
+            return Err(invalid_data("backup checksum does not match its entries".to_string()));
+        }
+        if !self.fill_buf()?.is_empty() {
+            return Err(invalid_data("unexpected data after the backup trailer".to_string()));
+        }
+        Ok(())
+    }
+
+    fn read_len(&mut self) -> io::Result<[u8; 4]> {
+        let mut len = [0u8; 4];
+        self.read_exact(&mut len)?;
+        Ok(len)
+    }
+
+    fn read_field(&mut self, len: [u8; 4]) -> io::Result<Vec<u8>> {
+        let mut field = vec![0u8; u32::from_le_bytes(len) as usize];
+        self.read_exact(&mut field)?;
+        Ok(field)
+    }
 }
 
 impl<R: Read> BufRead for BackupFileReader<R> {
@@ -82,3 +337,28 @@ impl<R: Read> Read for BackupFileReader<R> {
     }
 }
 
+fn field_len(field: &[u8]) -> io::Result<[u8; 4]> {
+    match u32::try_from(field.len()) {
+        Ok(len) if len != TRAILER_MARKER => Ok(len.to_le_bytes()),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "backup record field is too large")),
+    }
+}
+
+/// Feeds a record to the checksum exactly as it is framed in the backup.
+fn update_checksum(checksum: &mut Sha256, key_len: &[u8], key: &[u8], value_len: &[u8], value: &[u8]) {
+    checksum.update(key_len);
+    checksum.update(key);
+    checksum.update(value_len);
+    checksum.update(value);
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub(crate) fn check_work_factor(work_factor: u8) -> io::Result<()> {
+    if work_factor == 0 || work_factor >= 64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "scrypt work factor must be between 1 and 63"));
+    }
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+/// Path of the part number `index` of a split backup in `dir`.
+pub fn part_path(dir: &Path, index: usize) -> PathBuf {
+    dir.join(format!("backup.{:03}", index))
+}
+
+/// Creates or truncates a file that only its owner can read and write, for backups and DEK files.
+/// On Windows the file inherits the permissions of its directory.
+pub fn create_private_file<P: AsRef<Path>>(path: P) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let file = options.open(path)?;
+    // The mode only applies to new files, so an overwritten file is restricted here.
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+/// Writes a byte stream across `backup.000`, `backup.001`, ... files in a directory, starting a
+/// new part whenever the current one reaches `part_size` bytes.
+pub struct PartWriter {
+    dir: PathBuf,
+    part_size: u64,
+    parts: usize,
+    written: u64,
+    current: Option<BufWriter<File>>,
+}
+
+impl PartWriter {
+    pub fn new(dir: &Path, part_size: u64) -> Self {
+        PartWriter {
+            dir: dir.to_path_buf(),
+            part_size,
+            parts: 0,
+            written: 0,
+            current: None,
+        }
+    }
+
+    /// Flushes the last part and removes the parts a previous, longer backup left in the
+    /// directory, so they are not read as part of this one. Returns the number of parts.
+    pub fn finish(mut self) -> io::Result<usize> {
+        if let Some(current) = &mut self.current {
+            current.flush()?;
+        }
+        let mut index = self.parts;
+        while part_path(&self.dir, index).exists() {
+            fs::remove_file(part_path(&self.dir, index))?;
+            index += 1;
+        }
+        Ok(self.parts)
+    }
+}
+
+impl Write for PartWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.current.is_none() || self.written >= self.part_size {
+            if let Some(current) = &mut self.current {
+                current.flush()?;
+            }
+            let file = create_private_file(part_path(&self.dir, self.parts))?;
+            self.current = Some(BufWriter::new(file));
+            self.parts += 1;
+            self.written = 0;
+        }
+
+        let len = buf.len().min((self.part_size - self.written) as usize);
+        let current = self.current.as_mut().expect("a part was just opened");
+        let written = current.write(&buf[..len])?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some(current) => current.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads the parts written by `PartWriter` back as a single stream, in order, until the first
+/// missing part.
+pub struct PartReader {
+    dir: PathBuf,
+    next: usize,
+    current: BufReader<File>,
+}
+
+impl PartReader {
+    /// Fails with `NotFound` if `dir` holds no `backup.000`.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let current = BufReader::new(File::open(part_path(dir, 0))?);
+        Ok(PartReader {
+            dir: dir.to_path_buf(),
+            next: 1,
+            current,
+        })
+    }
+}
+
+impl Read for PartReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+
+            let next = part_path(&self.dir, self.next);
+            if !next.exists() {
+                return Ok(0);
+            }
+            self.current = BufReader::new(File::open(next)?);
+            self.next += 1;
+        }
+    }
+}
@@ -1,10 +1,23 @@
 use clap::{Parser, Subcommand};
 use redact::Secret;
+use serde::ser::{SerializeMap, Serializer as _};
+use serde::Deserialize;
+use serde_json::ser::Formatter;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
 use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
-use storage_backend::storage::Storage;
-use storage_backend::storage_config::{PasswordPolicyConfig, StorageConfig};
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use storage_backend::storage::{KeyValueStore, RestoreMode, Storage};
+use storage_backend::storage_config::{
+    CharacterSets, PasswordPolicyConfig, StorageConfig, DEFAULT_MAX_PASSWORD_LENGTH,
+};
+use zeroize::Zeroizing;
+
+const PASSWORD_ENV_VAR: &str = "STORAGE_PASSWORD";
+const NEW_PASSWORD_ENV_VAR: &str = "STORAGE_NEW_PASSWORD";
+const BACKUP_PASSWORD_ENV_VAR: &str = "STORAGE_BACKUP_PASSWORD";
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -20,8 +33,14 @@ struct StorageSettings {
     storage_path: PathBuf,
     #[clap(short, long)]
     password: Option<Secret<String>>,
+    /// Read the storage password from the first line of stdin.
+    #[clap(long, default_value = "false")]
+    password_stdin: bool,
     #[clap(short, long, value_parser = parse_password_policy_config)]
     password_policy_config: Option<PasswordPolicyConfig>,
+    /// TOML or JSON storage config file, used instead of `--storage-path`.
+    #[clap(long)]
+    config: Option<PathBuf>,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -30,8 +49,13 @@ struct BackupSettings {
     backup_path: PathBuf,
     #[clap(short, long, default_value = "dek")]
     dek_path: PathBuf,
-    #[clap(short, long, default_value = "password")]
-    password: Secret<String>,
+    /// Password of the backup DEK file.
+    #[clap(long)]
+    backup_password: Option<Secret<String>>,
+    /// Read the backup password from the next line of stdin, after the storage password if
+    /// `--password-stdin` is also given.
+    #[clap(long, default_value = "false")]
+    backup_password_stdin: bool,
     #[clap(flatten)]
     storage_settings: StorageSettings,
 }
@@ -60,23 +84,98 @@ enum Action {
     Write(StorageKeyValue),
     Read(StorageAndKey),
     Delete(StorageAndKey),
+    /// Sets fields of the JSON object stored under a key and prints the result.
+    Update {
+        #[clap(flatten)]
+        storage_and_key: StorageAndKey,
+        /// A `field=value` pair, where the value is JSON. Strings must be quoted, as in
+        /// `--set 'name="job"'`. Can be repeated.
+        #[clap(long = "set", required = true, value_parser = parse_field_update)]
+        updates: Vec<(String, Value)>,
+    },
     PartialCompare(StorageAndKey),
+    /// Prints the key-value pairs with keys from `--start`, included, to `--end`, excluded.
+    Range {
+        #[clap(flatten)]
+        storage_settings: StorageSettings,
+        #[clap(long)]
+        start: String,
+        #[clap(long)]
+        end: String,
+    },
+    /// Deletes every key starting with `--prefix`.
+    DeletePrefix {
+        #[clap(flatten)]
+        storage_settings: StorageSettings,
+        #[clap(long)]
+        prefix: String,
+    },
     Contains(StorageAndKey),
     ListKeys(StorageSettings),
-    Backup(BackupSettings),
-    RestoreBackup(BackupSettings),
+    /// Prints the number of keys, or of keys starting with `--prefix`. Without `--exact` the
+    /// number of all keys is RocksDB's estimate.
+    Count {
+        #[clap(flatten)]
+        storage_settings: StorageSettings,
+        #[clap(long)]
+        prefix: Option<String>,
+        #[clap(long)]
+        exact: bool,
+    },
+    /// Prints the number of keys, the size on disk and the number of SST files.
+    Stats(StorageSettings),
+    Backup {
+        #[clap(flatten)]
+        backup_settings: BackupSettings,
+        /// Split the backup into parts of at most this many bytes, written as `backup.000`,
+        /// `backup.001`, ... in the `--backup-path` directory.
+        #[clap(long)]
+        part_size: Option<u64>,
+    },
+    RestoreBackup {
+        #[clap(flatten)]
+        backup_settings: BackupSettings,
+        #[clap(long, default_value = "false")]
+        replace: bool,
+        #[clap(long)]
+        identity_file: Option<PathBuf>,
+    },
+    VerifyBackup {
+        #[clap(flatten)]
+        backup_settings: BackupSettings,
+        #[clap(long)]
+        identity_file: Option<PathBuf>,
+    },
+    /// Changes the storage password. The current one is given like for any other action.
     ChangePassword {
         #[clap(flatten)]
         storage_settings: StorageSettings,
         #[clap(short, long)]
-        new_password: Secret<String>,
+        new_password: Option<Secret<String>>,
+        /// Read the new password from the next line of stdin, after the current one if
+        /// `--password-stdin` is also given.
+        #[clap(long, default_value = "false")]
+        new_password_stdin: bool,
     },
+    /// Re-encrypts every entry with a freshly generated DEK.
+    RotateDek(StorageSettings),
+    /// Changes the password of a backup DEK file. The current one is given like for any other
+    /// backup action.
     ChangeBackupPassword {
         #[clap(flatten)]
         backup_settings: BackupSettings,
         #[clap(short, long)]
-        new_password: Secret<String>,
+        new_password: Option<Secret<String>>,
+        /// Read the new password from the next line of stdin, after the current ones.
+        #[clap(long, default_value = "false")]
+        new_password_stdin: bool,
     },
+    /// Writes every key and value as a JSON object sorted by key, decrypting the values when a
+    /// password is given. Values that are not JSON are written as strings.
+    ///
+    /// `Import` writes a dump back as it was stored only for values that are not JSON or are
+    /// compact JSON other than a string. A value that is a JSON string comes back without its
+    /// quotes and other JSON comes back compacted, unless the dump is written with `--raw`.
     Dump {
         #[clap(flatten)]
         storage_settings: StorageSettings,
@@ -84,71 +183,162 @@ enum Action {
         dump_file: PathBuf,
         #[clap(short, long, default_value = "false")]
         pretty: bool,
+        /// Write every value as a string, without parsing it as JSON.
+        #[clap(long, default_value = "false")]
+        raw: bool,
+    },
+    /// Writes the keys of a JSON object, as written by `Dump`, or of `{"key": .., "value": ..}`
+    /// lines in a single transaction. String values are written as they are and other values as
+    /// JSON. Nothing is written if any record is malformed.
+    Import {
+        #[clap(flatten)]
+        storage_settings: StorageSettings,
+        #[clap(short, long)]
+        input: PathBuf,
+        /// Read newline-delimited records instead of a single JSON object.
+        #[clap(long, default_value = "false")]
+        ndjson: bool,
+    },
+    /// Writes every key and value as a `key,value` CSV file.
+    ExportCsv {
+        #[clap(flatten)]
+        storage_settings: StorageSettings,
+        #[clap(short, long, default_value = "dump.csv")]
+        output: PathBuf,
+    },
+    /// Writes the rows of a `key,value` CSV file, as written by `ExportCsv`, in a single
+    /// transaction.
+    ImportCsv {
+        #[clap(flatten)]
+        storage_settings: StorageSettings,
+        #[clap(short, long)]
+        input: PathBuf,
     },
 }
 
+/// A line of an NDJSON import file.
+#[derive(Deserialize)]
+struct ImportRecord {
+    key: String,
+    value: Value,
+}
+
 impl Action {
-    fn get_storage_path(&self) -> &PathBuf {
+    fn get_storage_settings(&mut self) -> &mut StorageSettings {
         match self {
-            Action::New(args) => &args.storage_path,
-            Action::Write(args) => &args.storage_settings.storage_path,
-            Action::Read(args) => &args.storage_settings.storage_path,
-            Action::Delete(args) => &args.storage_settings.storage_path,
-            Action::PartialCompare(args) => &args.storage_settings.storage_path,
-            Action::Contains(args) => &args.storage_settings.storage_path,
-            Action::ListKeys(args) => &args.storage_path,
-            Action::Backup(args) => &args.storage_settings.storage_path,
-            Action::RestoreBackup(args) => &args.storage_settings.storage_path,
-            Action::ChangePassword {
+            Action::New(args) => args,
+            Action::Write(args) => &mut args.storage_settings,
+            Action::Read(args) => &mut args.storage_settings,
+            Action::Delete(args) => &mut args.storage_settings,
+            Action::Update {
+                storage_and_key, ..
+            } => &mut storage_and_key.storage_settings,
+            Action::PartialCompare(args) => &mut args.storage_settings,
+            Action::Range {
                 storage_settings, ..
-            } => &storage_settings.storage_path,
-            Action::ChangeBackupPassword {
-                backup_settings, ..
-            } => &backup_settings.storage_settings.storage_path,
-            Action::Dump {
+            } => storage_settings,
+            Action::DeletePrefix {
                 storage_settings, ..
-            } => &storage_settings.storage_path,
-        }
-    }
-
-    fn get_encryption_password(&self) -> Option<Secret<String>> {
-        match self {
-            Action::New(args) => args.password.clone(),
-            Action::Write(args) => args.storage_settings.password.clone(),
-            Action::Read(args) => args.storage_settings.password.clone(),
-            Action::Delete(args) => args.storage_settings.password.clone(),
-            Action::PartialCompare(args) => args.storage_settings.password.clone(),
-            Action::Contains(args) => args.storage_settings.password.clone(),
-            Action::ListKeys(args) => args.password.clone(),
-            Action::Backup(args) => args.storage_settings.password.clone(),
-            Action::RestoreBackup(args) => args.storage_settings.password.clone(),
+            } => storage_settings,
+            Action::Contains(args) => &mut args.storage_settings,
+            Action::ListKeys(args) => args,
+            Action::Count {
+                storage_settings, ..
+            } => storage_settings,
+            Action::Stats(args) => args,
+            Action::Backup {
+                backup_settings, ..
+            } => &mut backup_settings.storage_settings,
+            Action::RestoreBackup {
+                backup_settings, ..
+            } => &mut backup_settings.storage_settings,
+            Action::VerifyBackup {
+                backup_settings, ..
+            } => &mut backup_settings.storage_settings,
             Action::ChangePassword {
                 storage_settings, ..
-            } => storage_settings.password.clone(),
+            } => storage_settings,
+            Action::RotateDek(args) => args,
             Action::ChangeBackupPassword {
                 backup_settings, ..
-            } => backup_settings.storage_settings.password.clone(),
+            } => &mut backup_settings.storage_settings,
             Action::Dump {
                 storage_settings, ..
-            } => storage_settings.password.clone(),
+            } => storage_settings,
+            Action::Import {
+                storage_settings, ..
+            } => storage_settings,
+            Action::ExportCsv {
+                storage_settings, ..
+            } => storage_settings,
+            Action::ImportCsv {
+                storage_settings, ..
+            } => storage_settings,
         }
     }
+}
 
-    fn get_password_policy_config(&self) -> Option<PasswordPolicyConfig> {
-        match self {
-            Action::New(args) => args.password_policy_config.clone(),
-            Action::ChangePassword {
-                storage_settings, ..
-            } => storage_settings.password_policy_config.clone(),
-            _ => None,
+impl StorageSettings {
+    /// Fills in `password` from `--password-stdin` or the `STORAGE_PASSWORD` environment
+    /// variable. Only one of them and `--password` may be given.
+    fn resolve_password(&mut self) -> Result<(), String> {
+        self.password = resolve_secret(
+            self.password.take(),
+            self.password_stdin,
+            PASSWORD_ENV_VAR,
+            "password",
+            "Password: ",
+        )?;
+        Ok(())
+    }
+
+    /// Builds the storage config from `--config` if given, or from `--storage-path` otherwise.
+    /// `--password` and `--password-policy-config` override the values in the config file.
+    fn storage_config(&self) -> Result<StorageConfig, String> {
+        let mut config = match &self.config {
+            Some(config_path) => {
+                StorageConfig::from_file(config_path).map_err(|e| e.to_string())?
+            }
+            None => StorageConfig::new(self.storage_path.to_string_lossy().to_string(), None),
+        };
+        if self.password.is_some() {
+            config.password = self.password.clone();
         }
+        if self.password_policy_config.is_some() {
+            config.password_policy = self.password_policy_config.clone();
+        }
+
+        Ok(config)
+    }
+}
+
+impl BackupSettings {
+    /// Resolves the backup password from `--backup-password`, `--backup-password-stdin` or the
+    /// `STORAGE_BACKUP_PASSWORD` environment variable, exactly one of which must be given.
+    fn backup_password(&mut self) -> Result<Secret<String>, String> {
+        resolve_secret(
+            self.backup_password.take(),
+            self.backup_password_stdin,
+            BACKUP_PASSWORD_ENV_VAR,
+            "backup-password",
+            "Backup password: ",
+        )?
+        .ok_or_else(|| {
+            format!(
+                "A backup password must be given with --backup-password, \
+                 --backup-password-stdin or {}",
+                BACKUP_PASSWORD_ENV_VAR
+            )
+        })
     }
 }
 
 fn parse_password_policy_config(str: &str) -> Result<PasswordPolicyConfig, String> {
     let parts: Vec<&str> = str.split(',').collect();
-    if parts.len() != 4 {
-        return Err("To create Password Policy must have 4 comma-separated values".to_string());
+    if parts.len() != 4 && parts.len() != 6 {
+        return Err(
+            "To create Password Policy must have 4 or 6 comma-separated values".to_string(),
+        );
     }
 
     let min_length = parts[0]
@@ -163,48 +353,276 @@ fn parse_password_policy_config(str: &str) -> Result<PasswordPolicyConfig, Strin
     let min_number_of_digits = parts[3]
         .parse::<usize>()
         .map_err(|_| "Invalid min_number_of_digits".to_string())?;
+    let (min_number_of_lowercase, max_length) = if parts.len() == 6 {
+        let min_number_of_lowercase = parts[4]
+            .parse::<usize>()
+            .map_err(|_| "Invalid min_number_of_lowercase".to_string())?;
+        let max_length = parts[5]
+            .parse::<usize>()
+            .map_err(|_| "Invalid max_length".to_string())?;
+        (min_number_of_lowercase, max_length)
+    } else {
+        (0, DEFAULT_MAX_PASSWORD_LENGTH)
+    };
 
-    Ok(PasswordPolicyConfig {
+    let config = PasswordPolicyConfig {
         min_length,
         min_number_of_special_chars,
         min_number_of_uppercase,
         min_number_of_digits,
+        min_number_of_lowercase,
+        max_length,
+        character_sets: CharacterSets::default(),
+    };
+    config.validate().map_err(|error| error.to_string())?;
+    Ok(config)
+}
+
+fn parse_field_update(str: &str) -> Result<(String, Value), String> {
+    let (field, value) = str
+        .split_once('=')
+        .ok_or_else(|| "Expected a field=value pair".to_string())?;
+    let value = serde_json::from_str(value)
+        .map_err(|e| format!("Invalid JSON value for field {}: {}", field, e))?;
+    Ok((field.to_string(), value))
+}
+
+/// Resolves the new password of `ChangePassword` and `ChangeBackupPassword` from
+/// `--new-password`, `--new-password-stdin` or the `STORAGE_NEW_PASSWORD` environment variable,
+/// exactly one of which must be given.
+fn resolve_new_password(
+    new_password: Option<Secret<String>>,
+    new_password_stdin: bool,
+) -> Result<Secret<String>, String> {
+    resolve_secret(
+        new_password,
+        new_password_stdin,
+        NEW_PASSWORD_ENV_VAR,
+        "new-password",
+        "New password: ",
+    )?
+    .ok_or_else(|| {
+        format!(
+            "A new password must be given with --new-password, --new-password-stdin or {}",
+            NEW_PASSWORD_ENV_VAR
+        )
     })
 }
 
-pub fn run(args: Cli) -> Result<(), String> {
-    let storage = match args.action {
-        Action::New(storage_settings) => {
-            let path = storage_settings.storage_path.to_string_lossy().to_string();
-            let password = storage_settings.password;
-            let config = StorageConfig::new(path, password);
+/// Picks a secret from `--<flag>`, `--<flag>-stdin` or the `env_var` environment variable,
+/// failing if more than one of them is given. Returns `None` if none is.
+fn resolve_secret(
+    value: Option<Secret<String>>,
+    from_stdin: bool,
+    env_var: &str,
+    flag: &str,
+    prompt: &str,
+) -> Result<Option<Secret<String>>, String> {
+    let env_value = env::var(env_var).ok();
+    let sources = [value.is_some(), from_stdin, env_value.is_some()];
+    if sources.iter().filter(|given| **given).count() > 1 {
+        return Err(format!(
+            "Only one of --{}, --{}-stdin and {} can be used",
+            flag, flag, env_var
+        ));
+    }
 
-            if let Some(password_policy) = storage_settings.password_policy_config {
-                Storage::new_with_policy(&config, Some(password_policy))
-                    .map_err(|e| e.to_string())?;
-            } else {
-                Storage::new(&config).map_err(|e| e.to_string())?;
+    if from_stdin {
+        return read_password_from_stdin(prompt).map(Some);
+    }
+    Ok(value.or_else(|| env_value.map(Secret::from)))
+}
+
+/// Reads every record of an import file before anything is written, so a malformed record
+/// fails the import as a whole.
+fn read_import_records(input: &Path, ndjson: bool) -> Result<Vec<(String, Value)>, String> {
+    let file = File::open(input).map_err(|e| e.to_string())?;
+    if !ndjson {
+        let object: serde_json::Map<String, Value> =
+            serde_json::from_reader(BufReader::new(file)).map_err(|e| e.to_string())?;
+        return Ok(object.into_iter().collect());
+    }
+
+    let mut records = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: ImportRecord = serde_json::from_str(&line)
+            .map_err(|e| format!("Invalid record on line {}: {}", number + 1, e))?;
+        records.push((record.key, record.value));
+    }
+    Ok(records)
+}
+
+/// Writes `records` in a single transaction. Strings are written as they are, since `dump_json`
+/// writes the values that are not JSON as strings, and other values as JSON.
+fn import_records(storage: &Storage, records: &[(String, Value)]) -> Result<(), String> {
+    let transaction_id = storage.begin_transaction();
+    for (key, value) in records {
+        let value = match value {
+            Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+        if let Err(e) = storage.transactional_write(key, &value, transaction_id) {
+            storage
+                .rollback_transaction(transaction_id)
+                .map_err(|e| e.to_string())?;
+            return Err(e.to_string());
+        }
+    }
+    storage
+        .commit_transaction(transaction_id)
+        .map_err(|e| e.to_string())
+}
+
+/// Writes every entry as one JSON object, an entry at a time and in key order, so the dump does
+/// not have to fit in memory and dumps of the same data are identical. Values that are not valid
+/// JSON are written as strings, and so is every value when `raw` is set.
+fn dump_json<W: Write, F: Formatter>(
+    storage: &Storage,
+    mut serializer: serde_json::Serializer<W, F>,
+    raw: bool,
+) -> Result<usize, String> {
+    let mut map = (&mut serializer)
+        .serialize_map(None)
+        .map_err(|e| e.to_string())?;
+    let mut dumped = 0;
+    for key in storage.sorted_keys().map_err(|e| e.to_string())? {
+        let Some(value) = storage.read(&key).map_err(|e| e.to_string())? else {
+            continue;
+        };
+        let value = if raw {
+            Value::String(value)
+        } else {
+            serde_json::from_str(&value).unwrap_or(Value::String(value))
+        };
+        map.serialize_entry(&key, &value)
+            .map_err(|e| e.to_string())?;
+        dumped += 1;
+    }
+    map.end().map_err(|e| e.to_string())?;
+    serializer.into_inner().flush().map_err(|e| e.to_string())?;
+    Ok(dumped)
+}
+
+fn read_password_from_stdin(prompt: &str) -> Result<Secret<String>, String> {
+    let stdin = io::stdin();
+    let _echo_guard = if stdin.is_terminal() {
+        eprint!("{}", prompt);
+        EchoGuard::disable()
+    } else {
+        None
+    };
+
+    let mut line = Zeroizing::new(String::new());
+    stdin
+        .lock()
+        .read_line(&mut line)
+        .map_err(|e| e.to_string())?;
+    Ok(Secret::from(
+        line.trim_end_matches(['\n', '\r']).to_string(),
+    ))
+}
+
+/// Turns off terminal echo while it is alive.
+#[cfg(unix)]
+struct EchoGuard {
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl EchoGuard {
+    fn disable() -> Option<Self> {
+        let mut original = std::mem::MaybeUninit::<libc::termios>::uninit();
+        // SAFETY: tcgetattr fully initializes `original` when it succeeds.
+        let original = unsafe {
+            if libc::tcgetattr(libc::STDIN_FILENO, original.as_mut_ptr()) != 0 {
+                return None;
             }
+            original.assume_init()
+        };
 
-            println!("Created new storage at {:?}", storage_settings.storage_path);
+        let mut silent = original;
+        silent.c_lflag &= !libc::ECHO;
+        silent.c_lflag |= libc::ECHONL;
+        // SAFETY: `silent` is a valid termios copied from the current terminal settings.
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent) } != 0 {
+            return None;
+        }
+
+        Some(EchoGuard { original })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for EchoGuard {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings read in `disable`.
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct EchoGuard;
+
+#[cfg(not(unix))]
+impl EchoGuard {
+    fn disable() -> Option<Self> {
+        None
+    }
+}
+
+pub fn run(mut args: Cli) -> Result<(), String> {
+    args.action.get_storage_settings().resolve_password()?;
+
+    let storage = match args.action {
+        Action::New(storage_settings) => {
+            let config = storage_settings.storage_config()?;
+            Storage::new(&config).map_err(|e| e.to_string())?;
+
+            println!("Created new storage at {:?}", config.path);
             return Ok(());
         }
-        _ => {
-            let config = StorageConfig::new(
-                args.action.get_storage_path().to_string_lossy().to_string(),
-                args.action.get_encryption_password(),
+        Action::VerifyBackup {
+            mut backup_settings,
+            identity_file,
+        } => {
+            let stats = match identity_file {
+                Some(identity_file) => Storage::verify_backup_with_identity_file(
+                    &backup_settings.backup_path,
+                    &identity_file,
+                ),
+                None => {
+                    let password = backup_settings.backup_password()?;
+                    Storage::verify_backup(
+                        &backup_settings.backup_path,
+                        &backup_settings.dek_path,
+                        password,
+                    )
+                }
+            }
+            .map_err(|e| e.to_string())?;
+            println!(
+                "Backup {:?} is valid: {} entries, {} bytes",
+                backup_settings.backup_path, stats.entries, stats.bytes
             );
-            if let Some(password_policy) = args.action.get_password_policy_config() {
-                Storage::open_with_policy(&config, Some(password_policy))
-                    .map_err(|e| e.to_string())?
-            } else {
-                Storage::open(&config).map_err(|e| e.to_string())?
+            if let Some(created_at) = stats.created_at {
+                println!("Created at {} ms since the Unix epoch", created_at);
             }
+            return Ok(());
+        }
+        _ => {
+            let config = args.action.get_storage_settings().storage_config()?;
+            Storage::open(&config).map_err(|e| e.to_string())?
         }
     };
 
     match args.action {
-        Action::New(_) => {
+        Action::New(_) | Action::VerifyBackup { .. } => {
             eprintln!("Already handled above");
         }
         Action::Write(storage_key_value) => {
@@ -240,6 +658,22 @@ pub fn run(args: Cli) -> Result<(), String> {
                 storage_and_key.key, storage_and_key.storage_settings
             );
         }
+        Action::Update {
+            storage_and_key,
+            updates,
+        } => {
+            let updates: HashMap<&str, Value> = updates
+                .iter()
+                .map(|(field, value)| (field.as_str(), value.clone()))
+                .collect();
+            let document: Value = storage
+                .update(storage_and_key.key.as_str(), &updates, None)
+                .map_err(|e| e.to_string())?;
+            println!(
+                "Updated key {} in {:?}: {}",
+                storage_and_key.key, storage_and_key.storage_settings, document
+            );
+        }
         Action::PartialCompare(storage_and_key) => {
             let keys = storage
                 .partial_compare(&storage_and_key.key)
@@ -249,6 +683,30 @@ pub fn run(args: Cli) -> Result<(), String> {
                 storage_and_key.key, storage_and_key.storage_settings, keys
             );
         }
+        Action::Range {
+            storage_settings,
+            start,
+            end,
+        } => {
+            let pairs = storage.range(&start, &end).map_err(|e| e.to_string())?;
+            println!(
+                "Keys from {} to {} in {:?}:",
+                start, end, storage_settings.storage_path
+            );
+            for (key, value) in pairs {
+                println!("{}: {}", key, value);
+            }
+        }
+        Action::DeletePrefix {
+            storage_settings,
+            prefix,
+        } => {
+            let deleted = storage.delete_prefix(&prefix).map_err(|e| e.to_string())?;
+            println!(
+                "Deleted {} keys starting with {} from {:?}",
+                deleted, prefix, storage_settings.storage_path
+            );
+        }
         Action::Contains(storage_and_key) => {
             let contains = storage
                 .has_key(&storage_and_key.key)
@@ -267,29 +725,80 @@ pub fn run(args: Cli) -> Result<(), String> {
                 println!("{}", key);
             }
         }
-        Action::Backup(backup_settings) => {
-            storage
-                .backup(
+        Action::Count { prefix, exact, .. } => {
+            let count = match prefix {
+                Some(prefix) => storage.count_prefix(&prefix),
+                None => storage.count(exact),
+            }
+            .map_err(|e| e.to_string())?;
+            println!("{}", count);
+        }
+        Action::Stats(storage_settings) => {
+            let stats = storage.stats().map_err(|e| e.to_string())?;
+            println!("Stats for storage at {:?}", storage_settings.storage_path);
+            println!("Keys (estimated): {}", stats.estimate_num_keys);
+            println!("Disk size: {} bytes", stats.disk_size);
+            println!("SST files: {}", stats.num_live_sst_files);
+        }
+        Action::Backup {
+            mut backup_settings,
+            part_size,
+        } => {
+            let password = backup_settings.backup_password()?;
+            match part_size {
+                Some(part_size) => storage
+                    .backup_split(
+                        &backup_settings.backup_path,
+                        part_size,
+                        &backup_settings.dek_path,
+                        password,
+                    )
+                    .map(|_| ()),
+                None => storage.backup(
                     &backup_settings.backup_path,
                     &backup_settings.dek_path,
-                    backup_settings.password,
-                )
-                .map_err(|e| e.to_string())?;
+                    password,
+                ),
+            }
+            .map_err(|e| e.to_string())?;
             println!("Backup created at {:?}", backup_settings.backup_path);
         }
-        Action::RestoreBackup(backup_settings) => {
-            storage
-                .restore_backup(
+        Action::RestoreBackup {
+            mut backup_settings,
+            replace,
+            identity_file,
+        } => {
+            let mode = if replace {
+                RestoreMode::Replace
+            } else {
+                RestoreMode::Merge
+            };
+            let report = match identity_file {
+                Some(identity_file) => storage.restore_backup_with_identity_file(
                     &backup_settings.backup_path,
-                    &backup_settings.dek_path,
-                    backup_settings.password,
-                )
-                .map_err(|e| e.to_string())?;
-            println!("Backup restored from {:?}", backup_settings.backup_path);
+                    &identity_file,
+                    mode,
+                ),
+                None => {
+                    let password = backup_settings.backup_password()?;
+                    storage.restore_backup(
+                        &backup_settings.backup_path,
+                        &backup_settings.dek_path,
+                        password,
+                        mode,
+                    )
+                }
+            }
+            .map_err(|e| e.to_string())?;
+            println!(
+                "Restored {} entries from {:?}",
+                report.entries_restored, backup_settings.backup_path
+            );
         }
         Action::ChangePassword {
             storage_settings,
             new_password,
+            new_password_stdin,
         } => {
             let old_password = match storage_settings.password {
                 Some(pw) => pw,
@@ -297,6 +806,7 @@ pub fn run(args: Cli) -> Result<(), String> {
                     return Err("Current password must be provided to change password".to_string())
                 }
             };
+            let new_password = resolve_new_password(new_password, new_password_stdin)?;
 
             storage
                 .change_password(old_password, new_password)
@@ -306,11 +816,27 @@ pub fn run(args: Cli) -> Result<(), String> {
                 storage_settings.storage_path
             );
         }
+        Action::RotateDek(storage_settings) => {
+            let password = match storage_settings.password {
+                Some(pw) => pw,
+                None => {
+                    return Err("Current password must be provided to rotate the DEK".to_string())
+                }
+            };
+
+            storage.rotate_dek(password).map_err(|e| e.to_string())?;
+            println!(
+                "DEK rotated for storage at {:?}",
+                storage_settings.storage_path
+            );
+        }
         Action::ChangeBackupPassword {
-            backup_settings,
+            mut backup_settings,
             new_password,
+            new_password_stdin,
         } => {
-            let old_password = backup_settings.password;
+            let old_password = backup_settings.backup_password()?;
+            let new_password = resolve_new_password(new_password, new_password_stdin)?;
 
             storage
                 .change_backup_password(&backup_settings.dek_path, old_password, new_password)
@@ -320,36 +846,79 @@ pub fn run(args: Cli) -> Result<(), String> {
                 backup_settings.storage_settings.storage_path
             );
         }
+        Action::Import { input, ndjson, .. } => {
+            let records = read_import_records(&input, ndjson)?;
+            import_records(&storage, &records)?;
+            println!("Imported {} keys from {:?}", records.len(), input);
+        }
+        Action::ExportCsv { output, .. } => {
+            let file = File::create(&output).map_err(|e| e.to_string())?;
+            let exported = storage
+                .export_csv(BufWriter::new(file))
+                .map_err(|e| e.to_string())?;
+            println!("Exported {} keys to {:?}", exported, output);
+        }
+        Action::ImportCsv { input, .. } => {
+            let file = File::open(&input).map_err(|e| e.to_string())?;
+            let imported = storage.import_csv(file).map_err(|e| e.to_string())?;
+            println!("Imported {} keys from {:?}", imported, input);
+        }
         Action::Dump {
             storage_settings: _,
             dump_file,
             pretty,
+            raw,
         } => {
-            let keys = storage.keys().map_err(|e| e.to_string())?;
-            let mut json_map = serde_json::Map::new();
-            for key in keys {
-                if let Some(value) = storage.read(&key).map_err(|e| e.to_string())? {
-                    let json_value: serde_json::Value =
-                        serde_json::from_str(&value).map_err(|e| e.to_string())?;
-                    json_map.insert(key, json_value);
-                }
-            }
-            println!("Dumped storage content to {:?}", dump_file);
-            let json_data = serde_json::Value::Object(json_map);
-            let mut file = File::create(dump_file).map_err(|e| e.to_string())?;
-            if pretty {
-                file.write_all(
-                    serde_json::to_string_pretty(&json_data)
-                        .map_err(|e| e.to_string())?
-                        .as_bytes(),
-                )
-                .map_err(|e| e.to_string())?;
+            let file = BufWriter::new(File::create(&dump_file).map_err(|e| e.to_string())?);
+            let dumped = if pretty {
+                dump_json(&storage, serde_json::Serializer::pretty(file), raw)?
             } else {
-                file.write_all(json_data.to_string().as_bytes())
-                    .map_err(|e| e.to_string())?;
-            }
+                dump_json(&storage, serde_json::Serializer::new(file), raw)?
+            };
+            println!("Dumped {} keys to {:?}", dumped, dump_file);
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn dump_then_import(source: &Storage, target: &Storage, raw: bool) -> Result<(), String> {
+        let dump_path = env::temp_dir().join(format!("dump_{}.json", uuid::Uuid::new_v4()));
+        let file = BufWriter::new(File::create(&dump_path).map_err(|e| e.to_string())?);
+        dump_json(source, serde_json::Serializer::new(file), raw)?;
+        let records = read_import_records(&dump_path, false);
+        fs::remove_file(&dump_path).map_err(|e| e.to_string())?;
+        import_records(target, &records?)
+    }
+
+    #[test]
+    fn test_dump_then_import() -> Result<(), String> {
+        let values = [
+            ("string", "hello world"),
+            ("number", "42"),
+            ("object", r#"{"a":[1,"b"]}"#),
+        ];
+        // Values that are JSON strings or not compact JSON only round trip through a raw dump.
+        let raw_values = [("quoted", r#""hello""#), ("spaced", r#"{ "a": 1 }"#)];
+        for (values, raw) in [(&values[..], false), (&raw_values[..], true)] {
+            let (_source_guard, source) = Storage::open_temp().map_err(|e| e.to_string())?;
+            let (_target_guard, target) = Storage::open_temp().map_err(|e| e.to_string())?;
+            for (key, value) in values {
+                source.write(key, value).map_err(|e| e.to_string())?;
+            }
+
+            dump_then_import(&source, &target, raw)?;
+
+            for (key, value) in values {
+                let read = target.read(key).map_err(|e| e.to_string())?;
+                assert_eq!(read, Some(value.to_string()));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+use crate::error::StorageError;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Serialization format used by `KeyValueStore::get`/`set` to turn values into the bytes stored
+/// for a key. The raw string methods such as `Storage::read` and `Storage::write` do not use it.
+pub trait Codec {
+    /// Short name of the format, used in error messages.
+    const NAME: &'static str;
+
+    fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, StorageError>;
+
+    fn decode<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, StorageError>;
+}
+
+/// Stores values as JSON text. This is the default codec, and the only one `update` supports.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    const NAME: &'static str = "json";
+
+    fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, StorageError> {
+        serde_json::to_vec(value).map_err(|_| StorageError::ConversionError)
+    }
+
+    fn decode<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, StorageError> {
+        serde_json::from_slice(bytes).map_err(|_| StorageError::ConversionError)
+    }
+}
+
+/// Stores values as CBOR, which is more compact than JSON and keeps byte strings binary.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    const NAME: &'static str = "cbor";
+
+    fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, StorageError> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes).map_err(|_| StorageError::ConversionError)?;
+        Ok(bytes)
+    }
+
+    fn decode<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, StorageError> {
+        ciborium::from_reader(bytes).map_err(|_| StorageError::ConversionError)
+    }
+}
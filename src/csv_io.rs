@@ -0,0 +1,132 @@
+use crate::error::StorageError;
+use std::io::{BufRead, Write};
+
+/// Writes one CSV record terminated by CRLF, quoting the fields that contain a comma, a double
+/// quote or a line break as RFC 4180 requires.
+pub fn write_record<W: Write>(writer: &mut W, fields: &[&str]) -> Result<(), StorageError> {
+    for (index, field) in fields.iter().enumerate() {
+        if index > 0 {
+            writer.write_all(b",")?;
+        }
+        if field.contains([',', '"', '\r', '\n']) {
+            writer.write_all(b"\"")?;
+            writer.write_all(field.replace('"', "\"\"").as_bytes())?;
+            writer.write_all(b"\"")?;
+        } else {
+            writer.write_all(field.as_bytes())?;
+        }
+    }
+    writer.write_all(b"\r\n")?;
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    FieldStart,
+    Unquoted,
+    Quoted,
+    // A double quote was just read inside a quoted field: either the closing quote or the first
+    // half of an escaped one.
+    QuoteInQuoted,
+}
+
+/// Reads RFC 4180 records, accepting both CRLF and LF line endings. Blank lines are skipped.
+pub struct CsvReader<R: BufRead> {
+    inner: R,
+    line: usize,
+}
+
+impl<R: BufRead> CsvReader<R> {
+    pub fn new(inner: R) -> Self {
+        CsvReader { inner, line: 0 }
+    }
+
+    /// Line on which the last record read ended.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Returns the fields of the next record, or `None` at the end of the input.
+    pub fn read_record(&mut self) -> Result<Option<Vec<String>>, StorageError> {
+        let mut fields = Vec::new();
+        let mut field = Vec::new();
+        let mut state = State::FieldStart;
+        let mut raw_line = Vec::new();
+
+        loop {
+            raw_line.clear();
+            if self.inner.read_until(b'\n', &mut raw_line)? == 0 {
+                return match state {
+                    State::Quoted => Err(self.invalid("unterminated quoted field")),
+                    _ => Ok(None),
+                };
+            }
+            self.line += 1;
+
+            let (content, ending) = split_line_ending(&raw_line);
+            if state == State::FieldStart && fields.is_empty() && content.is_empty() {
+                continue;
+            }
+
+            for &byte in content {
+                state = match (state, byte) {
+                    (State::FieldStart, b'"') => State::Quoted,
+                    (State::FieldStart | State::Unquoted | State::QuoteInQuoted, b',') => {
+                        fields.push(self.finish_field(&mut field)?);
+                        State::FieldStart
+                    }
+                    (State::Unquoted, b'"') => {
+                        return Err(self.invalid("double quote in an unquoted field"));
+                    }
+                    (State::FieldStart | State::Unquoted, byte) => {
+                        field.push(byte);
+                        State::Unquoted
+                    }
+                    (State::Quoted, b'"') => State::QuoteInQuoted,
+                    (State::Quoted, byte) => {
+                        field.push(byte);
+                        State::Quoted
+                    }
+                    (State::QuoteInQuoted, b'"') => {
+                        field.push(b'"');
+                        State::Quoted
+                    }
+                    (State::QuoteInQuoted, _) => {
+                        return Err(self.invalid("unexpected character after a closing quote"));
+                    }
+                };
+            }
+
+            if state != State::Quoted {
+                fields.push(self.finish_field(&mut field)?);
+                return Ok(Some(fields));
+            }
+            if ending.is_empty() {
+                return Err(self.invalid("unterminated quoted field"));
+            }
+            field.extend_from_slice(ending);
+        }
+    }
+
+    fn finish_field(&self, field: &mut Vec<u8>) -> Result<String, StorageError> {
+        String::from_utf8(std::mem::take(field)).map_err(|_| self.invalid("field is not UTF-8"))
+    }
+
+    fn invalid(&self, reason: &'static str) -> StorageError {
+        StorageError::InvalidCsv {
+            line: self.line,
+            reason,
+        }
+    }
+}
+
+fn split_line_ending(line: &[u8]) -> (&[u8], &[u8]) {
+    let ending_len = if line.ends_with(b"\r\n") {
+        2
+    } else if line.ends_with(b"\n") {
+        1
+    } else {
+        0
+    };
+    line.split_at(line.len() - ending_len)
+}
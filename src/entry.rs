@@ -0,0 +1,139 @@
+use crate::{
+    codec::{Codec, JsonCodec},
+    error::StorageError,
+    storage::Storage,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+use uuid::Uuid;
+
+/// A key of a `Storage`, as returned by `Storage::entry`. Each method reads and writes the key in
+/// a single transaction, either the one the entry was created with or one of its own, so no
+/// other write can slip in between the read and the write.
+pub struct Entry<'a, C = JsonCodec> {
+    storage: &'a Storage<C>,
+    key: String,
+    transaction_id: Option<Uuid>,
+}
+
+impl<'a, C: Codec> Entry<'a, C> {
+    pub(crate) fn new(storage: &'a Storage<C>, key: &str, transaction_id: Option<Uuid>) -> Self {
+        Entry {
+            storage,
+            key: key.to_string(),
+            transaction_id,
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Stores `default` if the key has no value, and returns the value of the key.
+    pub fn or_insert(self, default: &str) -> Result<String, StorageError> {
+        self.or_insert_with(|| default.to_string())
+    }
+
+    /// Same as `or_insert`, computing the default only when the key has no value.
+    pub fn or_insert_with<F>(self, default: F) -> Result<String, StorageError>
+    where
+        F: FnOnce() -> String,
+    {
+        let value = self
+            .storage
+            .read_modify_write(&self.key, self.transaction_id, |current| {
+                Ok(match current {
+                    Some(_) => None,
+                    None => Some(default().into_bytes()),
+                })
+            })?;
+        String::from_utf8(value.unwrap_or_default()).map_err(|_| StorageError::ConversionError)
+    }
+
+    /// Updates the value in place if the key has one. Does nothing otherwise.
+    pub fn and_modify<F>(self, modify: F) -> Result<Self, StorageError>
+    where
+        F: FnOnce(&mut String),
+    {
+        self.storage.read_modify_write(
+            &self.key,
+            self.transaction_id,
+            |current| match current {
+                Some(current) => {
+                    let mut value = String::from_utf8(current.to_vec())
+                        .map_err(|_| StorageError::ConversionError)?;
+                    modify(&mut value);
+                    Ok(Some(value.into_bytes()))
+                }
+                None => Ok(None),
+            },
+        )?;
+        Ok(self)
+    }
+}
+
+/// Same as `Entry`, storing the value with the storage's codec. Returned by
+/// `Storage::typed_entry`.
+pub struct TypedEntry<'a, V, C = JsonCodec> {
+    entry: Entry<'a, C>,
+    _value: PhantomData<V>,
+}
+
+impl<'a, V, C> TypedEntry<'a, V, C>
+where
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    pub(crate) fn new(entry: Entry<'a, C>) -> Self {
+        TypedEntry {
+            entry,
+            _value: PhantomData,
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        self.entry.key()
+    }
+
+    /// Stores `default` if the key has no value, and returns the value of the key.
+    pub fn or_insert(self, default: V) -> Result<V, StorageError> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Same as `or_insert`, computing the default only when the key has no value.
+    pub fn or_insert_with<F>(self, default: F) -> Result<V, StorageError>
+    where
+        F: FnOnce() -> V,
+    {
+        let entry = self.entry;
+        let value =
+            entry
+                .storage
+                .read_modify_write(&entry.key, entry.transaction_id, |current| match current {
+                    Some(_) => Ok(None),
+                    None => Ok(Some(C::encode(&default())?)),
+                })?;
+        C::decode(&value.unwrap_or_default())
+    }
+
+    /// Updates the value in place if the key has one. Does nothing otherwise.
+    pub fn and_modify<F>(self, modify: F) -> Result<Self, StorageError>
+    where
+        F: FnOnce(&mut V),
+    {
+        let entry = &self.entry;
+        entry.storage.read_modify_write(
+            &entry.key,
+            entry.transaction_id,
+            |current| match current {
+                Some(current) => {
+                    let mut value = C::decode(current)?;
+                    modify(&mut value);
+                    Ok(Some(C::encode(&value)?))
+                }
+                None => Ok(None),
+            },
+        )?;
+        Ok(self)
+    }
+}
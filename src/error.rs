@@ -1,35 +1,106 @@
-use crate::password_policy::PasswordPolicy;
+use crate::password_policy::PolicyViolation;
 use std::io::Error as IoError;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum StorageError {
-    #[error("Value not found {0}")]
-    NotFound(String),
-    #[error("Error modifying storage")]
-    WriteError,
-    #[error("Error reading from storage")]
-    ReadError,
+    #[error("{kind} not found: {key}")]
+    NotFound { kind: &'static str, key: String },
+    #[error("Error modifying storage: {0}")]
+    WriteError(#[source] rocksdb::Error),
+    #[error("Error reading from storage: {0}")]
+    ReadError(#[source] rocksdb::Error),
     #[error("Error converting data")]
     ConversionError,
     #[error("Error serializing/deserializing data")]
     SerializationError,
     #[error("Error creating storage")]
     CreationError(#[from] rocksdb::Error),
-    #[error("Error while commiting changes")]
-    CommitError,
+    #[error("Storage is locked by another process: {0}")]
+    Locked(#[source] rocksdb::Error),
+    #[error("Error while commiting changes: {0}")]
+    CommitError(#[source] rocksdb::Error),
+    #[error("Transaction conflict: {0}")]
+    Conflict(#[source] rocksdb::Error),
     #[error("Failed I/O action: {0}")]
     IoError(#[from] IoError),
-    #[error("Failed to encrypt data")]
+    #[error("Failed to encrypt data: {}", cocoon_message(.error))]
     FailedToEncryptData { error: cocoon::Error },
-    #[error("Failed to decrypt data")]
+    #[error("Failed to decrypt data: {}", cocoon_message(.error))]
     FailedToDecryptData { error: cocoon::Error },
-    #[error("Password does not meet complexity requirements. Required policy: {0:?}")]
-    WeakPassword(PasswordPolicy),
+    #[error("Failed to encrypt backup: {0}")]
+    BackupEncryptFailed(#[source] age::EncryptError),
+    #[error("Failed to decrypt backup, check the DEK file, password or identity: {0}")]
+    BackupDecryptFailed(#[source] age::DecryptError),
+    #[error("Failed to wrap DEK: {0}")]
+    DekEncryptFailed(#[source] age::EncryptError),
+    #[error("Failed to unwrap DEK: {0}")]
+    DekDecryptFailed(#[source] age::DecryptError),
+    #[error("Value was encrypted for another key")]
+    ValueKeyMismatch,
+    #[error("Failed to decompress data")]
+    FailedToDecompressData,
+    #[error("Failed to decrypt key")]
+    FailedToDecryptKey,
+    #[error(
+        "Password does not meet complexity requirements: {}",
+        .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+    )]
+    WeakPassword(Vec<PolicyViolation>),
     #[error("Error generating random DEK: {0}")]
     RandomDekGenerationError(#[from] rand::rand_core::OsError),
     #[error("Wrong password provided")]
     WrongPassword,
     #[error("No password set for the storage")]
     NoPasswordSet,
+    #[error("Storage is encrypted, a password is required to open it")]
+    PasswordRequired,
+    #[error("Storage holds unencrypted data and cannot be opened with a password")]
+    NotEncrypted,
+    #[error("Backup is corrupt: {0}")]
+    CorruptBackup(String),
+    #[error("DEK file belongs to a different backup")]
+    BackupDekMismatch,
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("Invalid storage path {path}: {reason}")]
+    InvalidPath { path: String, reason: String },
+    #[error("Storage is open in read-only mode")]
+    ReadOnly,
+    #[error("{0} transactions are still open")]
+    TransactionsOpen(usize),
+    #[error("Storage is corrupt: {0}")]
+    Corruption(#[source] rocksdb::Error),
+    #[error("Blocking storage task failed: {0}")]
+    TaskFailed(String),
+    #[error("{operation} is not supported with the {codec} codec")]
+    UnsupportedByCodec {
+        operation: &'static str,
+        codec: &'static str,
+    },
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+    #[error("Key {0:?} is reserved for internal use")]
+    ReservedKey(String),
+    #[error("Value of {size} bytes exceeds the limit of {limit} bytes")]
+    ValueTooLarge { size: usize, limit: usize },
+    #[error("Invalid CSV on line {line}: {reason}")]
+    InvalidCsv { line: usize, reason: &'static str },
+    #[error("Invalid portable export at record {record}: {reason}")]
+    InvalidExport { record: u64, reason: &'static str },
+}
+
+// `cocoon::Error` only implements `Display` with a feature this crate does not enable.
+fn cocoon_message(error: &cocoon::Error) -> String {
+    match error {
+        cocoon::Error::Io(error) => format!("I/O error: {}", error),
+        cocoon::Error::UnrecognizedFormat => {
+            "unrecognized format, the data may be corrupt".to_string()
+        }
+        cocoon::Error::Cryptography => {
+            "integrity check failed, wrong key or tampered data".to_string()
+        }
+        cocoon::Error::TooLarge => "container is too large for this platform".to_string(),
+        cocoon::Error::TooShort => "data is truncated".to_string(),
+    }
 }
@@ -0,0 +1,70 @@
+use aes::cipher::{KeyIvInit, StreamCipher};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+type HmacSha256 = Hmac<Sha256>;
+
+const IV_LEN: usize = 16;
+
+/// Deterministic authenticated encryption for storage keys, built like SIV: the IV is an HMAC of
+/// the plaintext and the plaintext is encrypted with AES-256-CTR under that IV. Equal keys always
+/// map to equal ciphertexts, so they can still be looked up directly.
+pub(crate) struct KeyCipher {
+    enc_key: Zeroizing<[u8; 32]>,
+    mac_key: Zeroizing<[u8; 32]>,
+}
+
+impl KeyCipher {
+    /// Derives the key encryption keys from the storage DEK.
+    pub fn new(dek: &[u8]) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(None, dek);
+        let mut enc_key = Zeroizing::new([0u8; 32]);
+        let mut mac_key = Zeroizing::new([0u8; 32]);
+        hkdf.expand(b"storage key encryption", enc_key.as_mut())
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        hkdf.expand(b"storage key authentication", mac_key.as_mut())
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+
+        KeyCipher { enc_key, mac_key }
+    }
+
+    pub fn encrypt(&self, key: &[u8]) -> Vec<u8> {
+        let mut mac = self.mac();
+        mac.update(key);
+        let tag = mac.finalize().into_bytes();
+
+        let mut encrypted = Vec::with_capacity(IV_LEN + key.len());
+        encrypted.extend_from_slice(&tag[..IV_LEN]);
+        encrypted.extend_from_slice(key);
+        self.apply_keystream(&tag[..IV_LEN], &mut encrypted[IV_LEN..]);
+        encrypted
+    }
+
+    /// Returns `None` if `encrypted` was not produced by `encrypt` with the same DEK.
+    pub fn decrypt(&self, encrypted: &[u8]) -> Option<Vec<u8>> {
+        if encrypted.len() < IV_LEN {
+            return None;
+        }
+        let (iv, ciphertext) = encrypted.split_at(IV_LEN);
+
+        let mut key = ciphertext.to_vec();
+        self.apply_keystream(iv, &mut key);
+
+        let mut mac = self.mac();
+        mac.update(&key);
+        mac.verify_truncated_left(iv).ok()?;
+        Some(key)
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(self.mac_key.as_ref()).expect("HMAC accepts keys of any length")
+    }
+
+    fn apply_keystream(&self, iv: &[u8], data: &mut [u8]) {
+        let mut cipher = Aes256Ctr::new(self.enc_key.as_ref().into(), iv.into());
+        cipher.apply_keystream(data);
+    }
+}
@@ -1,5 +1,19 @@
+#[cfg(feature = "async")]
+pub mod async_storage;
+pub mod codec;
+pub mod entry;
 pub mod error;
+pub mod metrics;
 pub mod password_policy;
+pub mod scoped_store;
 pub mod storage;
+pub mod storage_builder;
 pub mod storage_config;
-pub(crate) mod backup_io;
\ No newline at end of file
+pub(crate) mod backup_io;
+pub(crate) mod backup_parts;
+pub(crate) mod csv_io;
+pub(crate) mod key_cipher;
+pub(crate) mod merge_operator;
+pub(crate) mod portable_io;
+pub(crate) mod read_cache;
+pub(crate) mod value_compression;
\ No newline at end of file
@@ -0,0 +1,64 @@
+use crate::{error::StorageError, storage_config::MergeOperator, value_compression};
+use rocksdb::MergeOperands;
+use serde_json::Value;
+
+/// Registers `operator` with RocksDB under its name. RocksDB records the name, so a storage
+/// holding merged keys must keep being opened with the same operator.
+pub fn register(options: &mut rocksdb::Options, operator: MergeOperator) {
+    match operator {
+        MergeOperator::JsonArrayAppend => {
+            options.set_merge_operator_associative("json_array_append", json_array_append)
+        }
+        MergeOperator::LastWriteWins => {
+            options.set_merge_operator_associative("last_write_wins", last_write_wins)
+        }
+    }
+}
+
+/// Encodes an operand for `operator`. Appended values are wrapped in an array of their own, so
+/// RocksDB can combine operands before it reaches the stored value.
+pub fn encode_operand(operator: MergeOperator, operand: &str) -> Result<Vec<u8>, StorageError> {
+    match operator {
+        MergeOperator::JsonArrayAppend => {
+            let value: Value =
+                serde_json::from_str(operand).map_err(|_| StorageError::SerializationError)?;
+            serde_json::to_vec(&Value::Array(vec![value]))
+                .map_err(|_| StorageError::SerializationError)
+        }
+        MergeOperator::LastWriteWins => Ok(operand.as_bytes().to_vec()),
+    }
+}
+
+// A stored value that is not an array becomes the first item. Returning `None` makes RocksDB
+// report the merge as failed when the key is read.
+fn json_array_append(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    let mut items = match existing {
+        Some(existing) => {
+            let existing = value_compression::decompress(existing.to_vec()).ok()?;
+            match serde_json::from_slice(&existing).ok()? {
+                Value::Array(items) => items,
+                value => vec![value],
+            }
+        }
+        None => Vec::new(),
+    };
+    for operand in operands {
+        match serde_json::from_slice(operand).ok()? {
+            Value::Array(mut appended) => items.append(&mut appended),
+            _ => return None,
+        }
+    }
+    serde_json::to_vec(&Value::Array(items)).ok()
+}
+
+fn last_write_wins(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    operands.iter().last().or(existing).map(<[u8]>::to_vec)
+}
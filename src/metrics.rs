@@ -0,0 +1,98 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Operation counters of a storage opened with `StorageConfig::metrics`, as returned by
+/// `Storage::metrics`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StorageMetrics {
+    /// Values read by key, including the reads served by the read cache.
+    pub reads: u64,
+    pub writes: u64,
+    pub deletes: u64,
+    pub cache_hits: u64,
+    pub commits: u64,
+    pub rollbacks: u64,
+}
+
+impl StorageMetrics {
+    /// Formats the counters in the Prometheus text exposition format, for a service to serve on
+    /// its metrics endpoint.
+    pub fn render_prometheus(&self) -> String {
+        let counters = [
+            ("storage_reads_total", "Values read by key.", self.reads),
+            ("storage_writes_total", "Values written.", self.writes),
+            ("storage_deletes_total", "Keys deleted.", self.deletes),
+            (
+                "storage_cache_hits_total",
+                "Reads served by the read cache.",
+                self.cache_hits,
+            ),
+            (
+                "storage_commits_total",
+                "Transactions committed.",
+                self.commits,
+            ),
+            (
+                "storage_rollbacks_total",
+                "Transactions rolled back.",
+                self.rollbacks,
+            ),
+        ];
+
+        let mut output = String::new();
+        for (name, help, value) in counters {
+            // Writing to a `String` cannot fail.
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} counter", name);
+            let _ = writeln!(output, "{} {}", name, value);
+        }
+        output
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Operation {
+    Read,
+    Write,
+    Delete,
+    CacheHit,
+    Commit,
+    Rollback,
+}
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    deletes: AtomicU64,
+    cache_hits: AtomicU64,
+    commits: AtomicU64,
+    rollbacks: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn record(&self, operation: Operation) {
+        let counter = match operation {
+            Operation::Read => &self.reads,
+            Operation::Write => &self.writes,
+            Operation::Delete => &self.deletes,
+            Operation::CacheHit => &self.cache_hits,
+            Operation::Commit => &self.commits,
+            Operation::Rollback => &self.rollbacks,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> StorageMetrics {
+        StorageMetrics {
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            commits: self.commits.load(Ordering::Relaxed),
+            rollbacks: self.rollbacks.load(Ordering::Relaxed),
+        }
+    }
+}
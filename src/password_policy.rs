@@ -1,21 +1,97 @@
-use crate::storage_config::PasswordPolicyConfig;
+use crate::{
+    error::StorageError,
+    storage_config::{CharacterSets, PasswordPolicyConfig, DEFAULT_MAX_PASSWORD_LENGTH},
+};
+use std::{fmt, sync::Arc};
 
 pub const UPPERCASE: &[char] = &[
     'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', 'S',
     'T', 'U', 'V', 'W', 'X', 'Y', 'Z',
 ];
+pub const LOWERCASE: &[char] = &[
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's',
+    't', 'u', 'v', 'w', 'x', 'y', 'z',
+];
 pub const DIGITS: &[char] = &['0', '1', '2', '3', '4', '5', '6', '7', '8', '9'];
 pub const SPECIAL: &[char] = &[
     '!', '#', '$', '%', '&', '(', ')', '*', '+', ',', '-', '.', '/', ':', ';', '<', '=', '>', '?',
     '@', '[', ']', '^', '_', '{', '|', '}', '~',
 ];
 
-#[derive(Debug, Clone)]
+/// A password policy rule that a password breaks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyViolation {
+    TooShort { needed: usize, got: usize },
+    TooLong { max: usize, got: usize },
+    NotEnoughSpecialChars { needed: usize, got: usize },
+    NotEnoughUppercase { needed: usize, got: usize },
+    NotEnoughDigits { needed: usize, got: usize },
+    NotEnoughLowercase { needed: usize, got: usize },
+    RejectedByValidator,
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyViolation::TooShort { needed, got } => {
+                write!(f, "needs at least {needed} characters, has {got}")
+            }
+            PolicyViolation::TooLong { max, got } => {
+                write!(f, "allows at most {max} characters, has {got}")
+            }
+            PolicyViolation::NotEnoughSpecialChars { needed, got } => {
+                write!(f, "needs at least {needed} special characters, has {got}")
+            }
+            PolicyViolation::NotEnoughUppercase { needed, got } => {
+                write!(f, "needs at least {needed} uppercase letters, has {got}")
+            }
+            PolicyViolation::NotEnoughDigits { needed, got } => {
+                write!(f, "needs at least {needed} digits, has {got}")
+            }
+            PolicyViolation::NotEnoughLowercase { needed, got } => {
+                write!(f, "needs at least {needed} lowercase letters, has {got}")
+            }
+            PolicyViolation::RejectedByValidator => write!(f, "rejected by the custom validator"),
+        }
+    }
+}
+
+type Validator = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+#[derive(Clone)]
 pub struct PasswordPolicy {
     min_length: usize,
     min_number_of_special_chars: usize,
     min_number_of_uppercase: usize,
     min_number_of_digits: usize,
+    min_number_of_lowercase: usize,
+    max_length: usize,
+    special_chars: Vec<char>,
+    uppercase_chars: Vec<char>,
+    digit_chars: Vec<char>,
+    lowercase_chars: Vec<char>,
+    validator: Option<Validator>,
+}
+
+impl fmt::Debug for PasswordPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PasswordPolicy")
+            .field("min_length", &self.min_length)
+            .field(
+                "min_number_of_special_chars",
+                &self.min_number_of_special_chars,
+            )
+            .field("min_number_of_uppercase", &self.min_number_of_uppercase)
+            .field("min_number_of_digits", &self.min_number_of_digits)
+            .field("min_number_of_lowercase", &self.min_number_of_lowercase)
+            .field("max_length", &self.max_length)
+            .field("special_chars", &self.special_chars)
+            .field("uppercase_chars", &self.uppercase_chars)
+            .field("digit_chars", &self.digit_chars)
+            .field("lowercase_chars", &self.lowercase_chars)
+            .field("validator", &self.validator.is_some())
+            .finish()
+    }
 }
 
 impl Default for PasswordPolicy {
@@ -25,32 +101,160 @@ impl Default for PasswordPolicy {
             min_number_of_special_chars: 3,
             min_number_of_uppercase: 3,
             min_number_of_digits: 3,
+            min_number_of_lowercase: 0,
+            max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+            special_chars: SPECIAL.to_vec(),
+            uppercase_chars: UPPERCASE.to_vec(),
+            digit_chars: DIGITS.to_vec(),
+            lowercase_chars: LOWERCASE.to_vec(),
+            validator: None,
         }
     }
 }
 
+/// Same as `PasswordPolicy::new`.
+impl From<PasswordPolicyConfig> for PasswordPolicy {
+    fn from(config: PasswordPolicyConfig) -> Self {
+        PasswordPolicy::new(config)
+    }
+}
+
 impl PasswordPolicy {
+    /// Skips `PasswordPolicyConfig::validate`, which runs when a storage is opened with the
+    /// policy. `try_new` runs it right away.
     pub fn new(config: PasswordPolicyConfig) -> Self {
         PasswordPolicy {
             min_length: config.min_length,
             min_number_of_special_chars: config.min_number_of_special_chars,
             min_number_of_uppercase: config.min_number_of_uppercase,
             min_number_of_digits: config.min_number_of_digits,
+            min_number_of_lowercase: config.min_number_of_lowercase,
+            max_length: config.max_length,
+            special_chars: char_set(config.character_sets.special, SPECIAL),
+            uppercase_chars: char_set(config.character_sets.uppercase, UPPERCASE),
+            digit_chars: char_set(config.character_sets.digits, DIGITS),
+            lowercase_chars: char_set(config.character_sets.lowercase, LOWERCASE),
+            validator: None,
+        }
+    }
+
+    /// Same as `new`, failing with `StorageError::InvalidConfig` if `config` does not pass
+    /// `validate`.
+    pub fn try_new(config: PasswordPolicyConfig) -> Result<Self, StorageError> {
+        config.validate()?;
+        Ok(PasswordPolicy::new(config))
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), StorageError> {
+        PasswordPolicyConfig {
+            min_length: self.min_length,
+            min_number_of_special_chars: self.min_number_of_special_chars,
+            min_number_of_uppercase: self.min_number_of_uppercase,
+            min_number_of_digits: self.min_number_of_digits,
+            min_number_of_lowercase: self.min_number_of_lowercase,
+            max_length: self.max_length,
+            character_sets: CharacterSets {
+                special: Some(self.special_chars.iter().collect()),
+                uppercase: Some(self.uppercase_chars.iter().collect()),
+                digits: Some(self.digit_chars.iter().collect()),
+                lowercase: Some(self.lowercase_chars.iter().collect()),
+            },
         }
+        .validate()
+    }
+
+    /// Adds a custom check, such as a banned password list or an entropy estimate, that runs
+    /// before the built-in rules. A password it rejects only reports
+    /// `PolicyViolation::RejectedByValidator`.
+    pub fn with_validator<F>(mut self, validator: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.validator = Some(Arc::new(validator));
+        self
     }
 
     pub fn is_valid(&self, password: &str) -> bool {
-        let has_enough_length = password.len() >= self.min_length;
-        let has_enough_special_chars = password.chars().filter(|c| SPECIAL.contains(c)).count()
-            >= self.min_number_of_special_chars;
-        let has_enough_uppercase_chars = password.chars().filter(|c| UPPERCASE.contains(c)).count()
-            >= self.min_number_of_uppercase;
-        let has_enough_digits =
-            password.chars().filter(|c| DIGITS.contains(c)).count() >= self.min_number_of_digits;
-
-        has_enough_length
-            && has_enough_special_chars
-            && has_enough_uppercase_chars
-            && has_enough_digits
+        self.check(password).is_ok()
+    }
+
+    /// Returns every rule that `password` breaks.
+    pub fn check(&self, password: &str) -> Result<(), Vec<PolicyViolation>> {
+        if let Some(validator) = &self.validator {
+            if !validator(password) {
+                return Err(vec![PolicyViolation::RejectedByValidator]);
+            }
+        }
+
+        let mut violations = Vec::new();
+
+        // Lengths count characters, not UTF-8 bytes.
+        let length = password.chars().count();
+        if length < self.min_length {
+            violations.push(PolicyViolation::TooShort {
+                needed: self.min_length,
+                got: length,
+            });
+        }
+        if length > self.max_length {
+            violations.push(PolicyViolation::TooLong {
+                max: self.max_length,
+                got: length,
+            });
+        }
+
+        let special_chars = password
+            .chars()
+            .filter(|c| self.special_chars.contains(c))
+            .count();
+        if special_chars < self.min_number_of_special_chars {
+            violations.push(PolicyViolation::NotEnoughSpecialChars {
+                needed: self.min_number_of_special_chars,
+                got: special_chars,
+            });
+        }
+        let uppercase_chars = password
+            .chars()
+            .filter(|c| self.uppercase_chars.contains(c))
+            .count();
+        if uppercase_chars < self.min_number_of_uppercase {
+            violations.push(PolicyViolation::NotEnoughUppercase {
+                needed: self.min_number_of_uppercase,
+                got: uppercase_chars,
+            });
+        }
+        let digits = password
+            .chars()
+            .filter(|c| self.digit_chars.contains(c))
+            .count();
+        if digits < self.min_number_of_digits {
+            violations.push(PolicyViolation::NotEnoughDigits {
+                needed: self.min_number_of_digits,
+                got: digits,
+            });
+        }
+        let lowercase_chars = password
+            .chars()
+            .filter(|c| self.lowercase_chars.contains(c))
+            .count();
+        if lowercase_chars < self.min_number_of_lowercase {
+            violations.push(PolicyViolation::NotEnoughLowercase {
+                needed: self.min_number_of_lowercase,
+                got: lowercase_chars,
+            });
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+}
+
+fn char_set(configured: Option<String>, default: &[char]) -> Vec<char> {
+    match configured {
+        Some(set) => set.chars().collect(),
+        None => default.to_vec(),
     }
 }
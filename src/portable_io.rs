@@ -0,0 +1,93 @@
+use crate::error::StorageError;
+use std::io::{Read, Write};
+
+/// First bytes of a portable export, so that any other file is rejected before it is imported.
+const MAGIC: &[u8; 8] = b"BVMXPRT1";
+// A record starts with the key length as a big-endian u32 and the value length as a big-endian
+// u64, followed by the key and value bytes.
+const RECORD_HEADER_LEN: usize = 12;
+
+type Record = (Vec<u8>, Vec<u8>);
+
+pub fn write_header<W: Write>(writer: &mut W) -> Result<(), StorageError> {
+    writer.write_all(MAGIC)?;
+    Ok(())
+}
+
+pub fn write_record<W: Write>(
+    writer: &mut W,
+    key: &[u8],
+    value: &[u8],
+) -> Result<(), StorageError> {
+    let key_len = u32::try_from(key.len())
+        .map_err(|_| StorageError::InvalidKey("key is longer than 4 GiB".to_string()))?;
+    writer.write_all(&key_len.to_be_bytes())?;
+    writer.write_all(&(value.len() as u64).to_be_bytes())?;
+    writer.write_all(key)?;
+    writer.write_all(value)?;
+    Ok(())
+}
+
+/// Reads the records written by `write_record` after checking the header.
+pub struct PortableReader<R: Read> {
+    inner: R,
+    // Number of the record being read, counting from 1, or 0 while reading the header.
+    record: u64,
+}
+
+impl<R: Read> PortableReader<R> {
+    pub fn new(inner: R) -> Result<Self, StorageError> {
+        let mut magic = [0u8; MAGIC.len()];
+        let mut reader = PortableReader { inner, record: 0 };
+        if !reader.read_full(&mut magic)? || &magic != MAGIC {
+            return Err(reader.invalid("not a portable export"));
+        }
+        Ok(reader)
+    }
+
+    /// Returns the key and value of the next record, or `None` at the end of the input.
+    pub fn read_record(&mut self) -> Result<Option<Record>, StorageError> {
+        self.record += 1;
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        if !self.read_full(&mut header)? {
+            return Ok(None);
+        }
+
+        let (key_len, value_len) = header.split_at(4);
+        let key_len = u32::from_be_bytes(key_len.try_into().expect("4 bytes")) as u64;
+        let value_len = u64::from_be_bytes(value_len.try_into().expect("8 bytes"));
+        // Lengths are not trusted for allocation: a corrupt length only reads up to the end.
+        let key = self.read_exactly(key_len)?;
+        let value = self.read_exactly(value_len)?;
+        Ok(Some((key, value)))
+    }
+
+    /// Fills `buf`, returning `false` if the input ended before its first byte.
+    fn read_full(&mut self, buf: &mut [u8]) -> Result<bool, StorageError> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.inner.read(&mut buf[filled..])? {
+                0 if filled == 0 => return Ok(false),
+                0 => return Err(self.invalid("truncated record")),
+                read => filled += read,
+            }
+        }
+        Ok(true)
+    }
+
+    fn read_exactly(&mut self, len: u64) -> Result<Vec<u8>, StorageError> {
+        let mut data = Vec::new();
+        (&mut self.inner).take(len).read_to_end(&mut data)?;
+        if (data.len() as u64) < len {
+            return Err(self.invalid("truncated record"));
+        }
+        Ok(data)
+    }
+
+    fn invalid(&self, reason: &'static str) -> StorageError {
+        StorageError::InvalidExport {
+            record: self.record,
+            reason,
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Least recently used cache of decrypted values, keyed by storage key. The values are scrubbed
+/// from memory when they are evicted, invalidated or dropped with the cache.
+///
+/// Keys written inside a transaction are only invalidated again when it commits, because a read
+/// made before the commit caches the previously committed value.
+pub(crate) struct ReadCache {
+    capacity: usize,
+    entries: HashMap<String, (Zeroizing<Vec<u8>>, u64)>,
+    // Last use of each entry, oldest first.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    pending: HashMap<Uuid, HashSet<String>>,
+}
+
+impl ReadCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        ReadCache {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: &str) -> Option<Vec<u8>> {
+        let tick = self.next_tick();
+        let (value, last_use) = self.entries.get_mut(key)?;
+        self.recency.remove(last_use);
+        *last_use = tick;
+        self.recency.insert(tick, key.to_string());
+        Some(value.to_vec())
+    }
+
+    pub(crate) fn insert(&mut self, key: &str, value: Vec<u8>) {
+        self.remove(key);
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        let tick = self.next_tick();
+        self.recency.insert(tick, key.to_string());
+        self.entries
+            .insert(key.to_string(), (Zeroizing::new(value), tick));
+    }
+
+    /// Drops the cached value of a key that was just written or deleted.
+    pub(crate) fn invalidate(&mut self, key: &str, transaction_id: Option<Uuid>) {
+        self.remove(key);
+        if let Some(transaction_id) = transaction_id {
+            self.pending
+                .entry(transaction_id)
+                .or_default()
+                .insert(key.to_string());
+        }
+    }
+
+    pub(crate) fn commit(&mut self, transaction_id: Uuid) {
+        for key in self.pending.remove(&transaction_id).unwrap_or_default() {
+            self.remove(&key);
+        }
+    }
+
+    pub(crate) fn rollback(&mut self, transaction_id: Uuid) {
+        self.pending.remove(&transaction_id);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((_, last_use)) = self.entries.remove(key) {
+            self.recency.remove(&last_use);
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
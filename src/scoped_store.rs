@@ -0,0 +1,150 @@
+use crate::{
+    codec::{Codec, JsonCodec},
+    error::StorageError,
+    storage::{KeyValueStore, Storage},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// View of a `Storage` restricted to the keys under a prefix, as returned by `Storage::scoped`.
+/// Keys passed in are relative to the prefix, and keys returned have the prefix removed.
+pub struct ScopedStore<'a, C = JsonCodec> {
+    storage: &'a Storage<C>,
+    prefix: String,
+}
+
+impl<'a, C: Codec> ScopedStore<'a, C> {
+    pub(crate) fn new(storage: &'a Storage<C>, prefix: &str) -> Self {
+        ScopedStore {
+            storage,
+            prefix: prefix.to_string(),
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn read(&self, key: &str) -> Result<Option<String>, StorageError> {
+        self.storage.read(&self.full_key(key))
+    }
+
+    pub fn write(&self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.storage.write(&self.full_key(key), value)
+    }
+
+    pub fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.storage.delete(&self.full_key(key))
+    }
+
+    pub fn has_key(&self, key: &str) -> Result<bool, StorageError> {
+        self.storage.has_key(&self.full_key(key))
+    }
+
+    pub fn keys(&self) -> Result<Vec<String>, StorageError> {
+        self.partial_compare_keys("")
+    }
+
+    pub fn partial_compare_keys(&self, key: &str) -> Result<Vec<String>, StorageError> {
+        let keys = self.storage.partial_compare_keys(&self.full_key(key))?;
+        Ok(keys.into_iter().map(|k| self.strip_prefix(k)).collect())
+    }
+
+    pub fn partial_compare(&self, key: &str) -> Result<Vec<(String, String)>, StorageError> {
+        let entries = self.storage.partial_compare(&self.full_key(key))?;
+        Ok(entries
+            .into_iter()
+            .map(|(k, v)| (self.strip_prefix(k), v))
+            .collect())
+    }
+
+    fn full_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn strip_prefix(&self, key: String) -> String {
+        key[self.prefix.len()..].to_string()
+    }
+}
+
+impl<C: Codec> KeyValueStore for ScopedStore<'_, C> {
+    fn get<K, V>(&self, key: K) -> Result<Option<V>, StorageError>
+    where
+        K: AsRef<str>,
+        V: DeserializeOwned,
+    {
+        self.storage.get(self.full_key(key.as_ref()))
+    }
+
+    fn get_tx<K, V>(&self, key: K, transaction_id: Uuid) -> Result<Option<V>, StorageError>
+    where
+        K: AsRef<str>,
+        V: DeserializeOwned,
+    {
+        self.storage
+            .get_tx(self.full_key(key.as_ref()), transaction_id)
+    }
+
+    fn set<K, V>(&self, key: K, value: V, transaction_id: Option<Uuid>) -> Result<(), StorageError>
+    where
+        K: AsRef<str>,
+        V: Serialize,
+    {
+        self.storage
+            .set(self.full_key(key.as_ref()), value, transaction_id)
+    }
+
+    fn set_batch_lenient<K, V>(
+        &self,
+        items: &[(K, V)],
+    ) -> Result<Vec<Result<(), StorageError>>, StorageError>
+    where
+        K: AsRef<str>,
+        V: Serialize,
+    {
+        let items: Vec<_> = items
+            .iter()
+            .map(|(key, value)| (self.full_key(key.as_ref()), value))
+            .collect();
+        self.storage.set_batch_lenient(&items)
+    }
+
+    fn delete<K: AsRef<str>>(
+        &self,
+        key: K,
+        transaction_id: Option<Uuid>,
+    ) -> Result<(), StorageError> {
+        KeyValueStore::delete(self.storage, self.full_key(key.as_ref()), transaction_id)
+    }
+
+    fn update<K, V>(
+        &self,
+        id: K,
+        updates: &HashMap<&str, Value>,
+        transaction_id: Option<Uuid>,
+    ) -> Result<V, StorageError>
+    where
+        K: AsRef<str> + std::marker::Copy,
+        V: Serialize + DeserializeOwned + Clone,
+    {
+        let key = self.full_key(id.as_ref());
+        self.storage.update(key.as_str(), updates, transaction_id)
+    }
+
+    fn update_merge<K, V>(
+        &self,
+        id: K,
+        patch: &Value,
+        transaction_id: Option<Uuid>,
+    ) -> Result<V, StorageError>
+    where
+        K: AsRef<str> + std::marker::Copy,
+        V: Serialize + DeserializeOwned + Clone,
+    {
+        let key = self.full_key(id.as_ref());
+        self.storage
+            .update_merge(key.as_str(), patch, transaction_id)
+    }
+}
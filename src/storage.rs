@@ -1,45 +1,295 @@
 use crate::{
-    backup_io::{BackupFileReader, BackupFileWriter},
+    backup_io::{
+        check_work_factor, read_backup_id, AfterBackupId, BackupFileReader, BackupFileWriter,
+        BackupFormat, BackupId, BACKUP_ID_MAGIC,
+    },
+    backup_parts::{create_private_file, PartReader, PartWriter},
+    codec::{Codec, JsonCodec},
+    csv_io::{self, CsvReader},
+    entry::{Entry, TypedEntry},
     error::StorageError,
+    key_cipher::KeyCipher,
+    merge_operator,
+    metrics::{Metrics, Operation, StorageMetrics},
     password_policy::PasswordPolicy,
-    storage_config::{PasswordPolicyConfig, StorageConfig},
+    portable_io::{self, PortableReader},
+    read_cache::ReadCache,
+    scoped_store::ScopedStore,
+    storage_builder::StorageBuilder,
+    storage_config::{
+        Compression, KdfParams, MergeOperator, PasswordPolicyConfig, StorageConfig,
+        ValueCompression,
+    },
+    value_compression,
 };
+use age::{scrypt, secrecy::SecretString, x25519};
 use cocoon::Cocoon;
+use hmac::{Hmac, Mac};
 use rand::{rngs::OsRng, TryRngCore};
 use redact::Secret;
-use rocksdb::TransactionDB;
+use rocksdb::{DBAccess, TransactionDB};
 use serde::{de::DeserializeOwned, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 use std::{
     cell::RefCell,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    env,
     fs::{self, File},
-    io::{BufRead, BufReader, Cursor, Read, Write},
+    io::{BufRead, BufReader, Cursor, ErrorKind, Read, Write},
+    marker::PhantomData,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use uuid::Uuid;
+use zeroize::{ZeroizeOnDrop, Zeroizing};
+
+// Internal keys start with a NUL byte. User keys starting with one are rejected, so they can
+// never collide.
+const RESERVED_PREFIX: char = '\0';
+const DEK_KEY: &str = "\0DEK";
+// Holds the wrapped replacement DEK while `rotate_dek` rewrites the entries.
+const DEK_ROTATION_KEY: &str = "\0DEK_ROTATION";
+// Keys of the DEKs before they moved under `RESERVED_PREFIX`, migrated when a storage is opened.
+const LEGACY_DEK_KEY: &str = "DEK";
+const LEGACY_DEK_ROTATION_KEY: &str = "DEK_ROTATION";
+// Every DEK wrapped with Cocoon starts with it. A value written through the public API never
+// does, since 0xc0 appears neither in UTF-8 nor after 0x7f in CBOR.
+const COCOON_MAGIC: [u8; 3] = [0x7f, 0xc0, b'\n'];
+// Every DEK wrapped with age scrypt, when `KdfParams::dek_work_factor` is set, starts with it.
+const AGE_MAGIC: &[u8] = b"age-encryption.org/";
+// Holds `<backup creation time>:<entries restored>` after each batch a chunked restore writes, so
+// an interrupted restore of the same backup can resume. Removed once the restore completes.
+const RESTORE_CHECKPOINT_KEY: &str = "\0restore_checkpoint";
+// Key of the restore checkpoint before it moved under `RESERVED_PREFIX`, migrated when a storage
+// is opened.
+const LEGACY_RESTORE_CHECKPOINT_KEY: &str = "__restore_checkpoint";
+const ROTATION_BATCH_SIZE: usize = 1000;
+const MIGRATION_BATCH_SIZE: usize = 1000;
+const RECLAIM_BATCH_SIZE: usize = 10_000;
+const PROGRESS_INTERVAL: u64 = 1000;
+const DEFAULT_BACKUP_BATCH_SIZE: u64 = 1000;
+const KEY_HASH_LEN: usize = 32;
+// Starts the plaintext of an encrypted value, followed by the SHA-256 of its key. 0xfc can neither
+// start a UTF-8 string nor a well-formed CBOR item, so values encrypted before they were bound to
+// their key, which start with the value itself, are told apart.
+const KEY_BOUND_MARKER: u8 = 0xfc;
+const MIB: usize = 1024 * 1024;
+// Values written with a TTL start with this byte followed by the expiry time. 0xff can neither
+// start a UTF-8 string nor a CBOR item, so it never collides with values stored otherwise.
+const EXPIRY_MARKER: u8 = 0xff;
+const EXPIRY_HEADER_LEN: usize = 9;
+
+/// A write for `Storage::apply`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteOp {
+    Put { key: String, value: String },
+    Delete { key: String },
+}
+
+/// How a restore treats keys that already exist in the storage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RestoreMode {
+    /// Backup entries are written over the existing keys, which are otherwise kept.
+    #[default]
+    Merge,
+    /// All existing keys are deleted first, so the storage ends up matching the backup exactly.
+    Replace,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BackupStats {
+    pub entries: u64,
+    pub bytes: u64,
+    /// Creation time of the backup in unix milliseconds, read from its manifest. `None` for
+    /// backups written before manifests were added.
+    pub created_at: Option<u64>,
+    /// RocksDB sequence number of the snapshot the backup holds, as `StorageSnapshot::sequence`
+    /// reports it. `None` for backups written before manifests recorded it.
+    pub sequence: Option<u64>,
+}
+
+/// Outcome of a restore. With a manifest, `entries_restored` equals the `entries` that
+/// `verify_backup` reports once the restore is complete.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RestoreReport {
+    /// Entries of the backup now in the storage, including those written by an interrupted
+    /// restore that this one resumed.
+    pub entries_restored: u64,
+    /// Bytes of keys and values read from the backup.
+    pub bytes_read: u64,
+}
+
+/// Result of `Storage::partial_compare_lenient`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SalvagedEntries {
+    pub entries: Vec<(String, String)>,
+    /// Keys whose values could not be read.
+    pub failed_keys: Vec<String>,
+}
+
+/// Outcome of `Storage::reclaim_space`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReclaimReport {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    /// Set when the cancellation flag stopped the compaction before it covered every key.
+    pub cancelled: bool,
+}
+
+impl ReclaimReport {
+    pub fn bytes_freed(&self) -> u64 {
+        self.bytes_before.saturating_sub(self.bytes_after)
+    }
+}
+
+/// Error of `Storage::flush`, `compact_range` and `reclaim_space`, which close the storage files
+/// and open them again. `storage` gives the storage back, unless the files could not be opened
+/// again, in which case `error` is why.
+pub struct ReopenError<C = JsonCodec> {
+    pub storage: Option<Box<Storage<C>>>,
+    pub error: StorageError,
+}
 
-const DEK_KEY: &str = "DEK";
+impl<C> ReopenError<C> {
+    fn new(storage: Storage<C>, error: StorageError) -> Self {
+        Self {
+            storage: Some(Box::new(storage)),
+            error,
+        }
+    }
+}
+
+impl<C> std::fmt::Debug for ReopenError<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReopenError")
+            .field("storage_open", &self.storage.is_some())
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl<C> std::fmt::Display for ReopenError<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl<C> std::error::Error for ReopenError<C> {}
+
+impl<C> From<ReopenError<C>> for StorageError {
+    fn from(error: ReopenError<C>) -> Self {
+        error.error
+    }
+}
+
+/// Size of a storage, as returned by `Storage::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    /// RocksDB's estimate of the number of keys, which can be off after overwrites and deletes.
+    /// `Storage::count` gives the exact number.
+    pub estimate_num_keys: u64,
+    pub num_live_sst_files: u64,
+    /// Total size in bytes of the files in the storage directory.
+    pub disk_size: u64,
+}
 
 /// Storage is limited to single threaded access due to the use of RefCell for transaction management.
-pub struct Storage {
+/// `C` is the format `KeyValueStore::get`/`set` store values in.
+pub struct Storage<C = JsonCodec> {
     db: rocksdb::TransactionDB,
-    transactions: RefCell<HashMap<Uuid, Box<rocksdb::Transaction<'static, TransactionDB>>>>,
-    password: Option<Vec<u8>>,
+    transactions: RefCell<HashMap<Uuid, OpenTransaction>>,
+    password: Option<RefCell<Zeroizing<Vec<u8>>>>,
+    // HMAC of the current password keyed with the DEK, to recognize it without unwrapping the DEK.
+    password_tag: RefCell<Option<[u8; 32]>>,
     password_policy: PasswordPolicy,
+    kdf_params: KdfParams,
+    key_cipher: Option<RefCell<KeyCipher>>,
+    prefix_length: Option<usize>,
+    read_only: bool,
+    cache: Option<RefCell<ReadCache>>,
+    backup_batch_size: u64,
+    metrics: Option<Metrics>,
+    max_key_length: Option<usize>,
+    max_value_bytes: Option<usize>,
+    compression: ValueCompression,
+    write_options: rocksdb::WriteOptions,
+    // Options the storage was opened with, to open it again after `with_plain_db`.
+    options: rocksdb::Options,
+    merge_operator: Option<MergeOperator>,
+    lossy_utf8: bool,
+    codec: PhantomData<C>,
+}
+
+/// Deletes the directory of a storage created by `Storage::open_temp` when dropped. It comes first
+/// in the returned pair so that, bound with `let`, it is dropped after the storage: a storage still
+/// open when its directory is removed may fail to write.
+#[derive(Debug)]
+pub struct TempGuard {
+    path: PathBuf,
+}
+
+impl TempGuard {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempGuard {
+    fn drop(&mut self) {
+        // Nothing can be reported from a drop, and a leftover temp directory is harmless.
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+struct OpenTransaction {
+    transaction: Box<rocksdb::Transaction<'static, TransactionDB>>,
+    started_at: Instant,
 }
 
+// The DEK and the values of the read cache are held in `Zeroizing`, so they are scrubbed from
+// memory when the storage is dropped.
+impl<C> ZeroizeOnDrop for Storage<C> {}
+
 pub trait KeyValueStore {
     fn get<K, V>(&self, key: K) -> Result<Option<V>, StorageError>
     where
         K: AsRef<str>,
         V: DeserializeOwned;
 
+    /// Same as `get`, reading through the transaction so its own uncommitted writes are seen.
+    fn get_tx<K, V>(&self, key: K, transaction_id: Uuid) -> Result<Option<V>, StorageError>
+    where
+        K: AsRef<str>,
+        V: DeserializeOwned;
+
     fn set<K, V>(&self, key: K, value: V, transaction_id: Option<Uuid>) -> Result<(), StorageError>
     where
         K: AsRef<str>,
         V: Serialize;
 
+    /// Sets every item on its own, each committed separately, and returns the outcome of each item
+    /// in order, so an import can report the records that failed instead of losing them all. This
+    /// is not atomic: the items that succeed stay written whatever happens to the others, and a
+    /// crash part way leaves only the first items written. Use `set` in a transaction to write all
+    /// items or none. Only a failure that stops every item, such as a read-only storage, is
+    /// returned as an error of its own.
+    fn set_batch_lenient<K, V>(
+        &self,
+        items: &[(K, V)],
+    ) -> Result<Vec<Result<(), StorageError>>, StorageError>
+    where
+        K: AsRef<str>,
+        V: Serialize;
+
+    fn delete<K: AsRef<str>>(
+        &self,
+        key: K,
+        transaction_id: Option<Uuid>,
+    ) -> Result<(), StorageError>;
+
     fn update<K, V>(
         &self,
         id: K,
@@ -49,6 +299,18 @@ pub trait KeyValueStore {
     where
         K: AsRef<str> + std::marker::Copy,
         V: Serialize + DeserializeOwned + Clone;
+
+    /// Applies `patch` as an RFC 7386 JSON Merge Patch. Unlike `update`, which replaces top-level
+    /// fields, nested objects are merged recursively and a `null` in the patch removes the field.
+    fn update_merge<K, V>(
+        &self,
+        id: K,
+        patch: &Value,
+        transaction_id: Option<Uuid>,
+    ) -> Result<V, StorageError>
+    where
+        K: AsRef<str> + std::marker::Copy,
+        V: Serialize + DeserializeOwned + Clone;
 }
 
 impl Storage {
@@ -56,864 +318,6485 @@ impl Storage {
         config: &StorageConfig,
         password_policy_config: Option<PasswordPolicyConfig>,
     ) -> Result<Storage, StorageError> {
-        let mut options = create_options();
-        options.create_if_missing(true);
-        Self::open_db(config, password_policy_config, &options)
+        Self::new_with_password_policy(config, password_policy(config, password_policy_config)?)
     }
 
     pub fn open_with_policy(
         config: &StorageConfig,
         password_policy_config: Option<PasswordPolicyConfig>,
     ) -> Result<Storage, StorageError> {
-        let options = create_options();
-        Self::open_db(config, password_policy_config, &options)
+        Self::open_with_password_policy(config, password_policy(config, password_policy_config)?)
+    }
+
+    /// Same as `new_with_policy`, taking an already built policy, e.g. one with a custom
+    /// validator.
+    pub fn new_with_password_policy(
+        config: &StorageConfig,
+        password_policy: PasswordPolicy,
+    ) -> Result<Storage, StorageError> {
+        Self::open_db(config, password_policy, true, false)
+    }
+
+    /// Same as `open_with_policy`, taking an already built policy, e.g. one with a custom
+    /// validator.
+    pub fn open_with_password_policy(
+        config: &StorageConfig,
+        password_policy: PasswordPolicy,
+    ) -> Result<Storage, StorageError> {
+        Self::open_db(config, password_policy, false, false)
     }
 
     pub fn new(config: &StorageConfig) -> Result<Storage, StorageError> {
-        let mut options = create_options();
-        options.create_if_missing(true);
-        Self::open_db(config, None, &options)
+        Self::open_db(config, password_policy(config, None)?, true, false)
     }
 
     pub fn open(config: &StorageConfig) -> Result<Storage, StorageError> {
-        let options = create_options();
-        Self::open_db(config, None, &options)
+        Self::open_db(config, password_policy(config, None)?, false, false)
+    }
+
+    /// Same as `open`, opening RocksDB with `options` instead of the ones built from `config`,
+    /// for tuning the config does not cover, such as a shared `Env` or a rate limiter. The
+    /// `rocksdb` and `prefix_length` settings of `config` are ignored, except for the merge
+    /// operator, which is registered on `options` since merged keys cannot be read without it.
+    pub fn open_with_options(
+        config: &StorageConfig,
+        options: rocksdb::Options,
+    ) -> Result<Storage, StorageError> {
+        Self::open_with_options_and_policy(config, options, None)
     }
 
-    fn open_db(
+    /// Same as `open_with_options`, checking the password against `password_policy_config` like
+    /// `open_with_policy`.
+    pub fn open_with_options_and_policy(
         config: &StorageConfig,
+        mut options: rocksdb::Options,
         password_policy_config: Option<PasswordPolicyConfig>,
-        options: &rocksdb::Options,
     ) -> Result<Storage, StorageError> {
-        let db = rocksdb::TransactionDB::open(
+        if let Some(merge_operator) = config.merge_operator {
+            merge_operator::register(&mut options, merge_operator);
+        }
+        Self::open_db_with_options(
+            config,
             options,
+            password_policy(config, password_policy_config)?,
+            false,
+            false,
+        )
+    }
+
+    /// Same as `open`, but while another process holds the storage lock it waits and tries
+    /// again, up to `attempts` tries in all. The wait starts at `backoff` and doubles after each
+    /// try. Fails with `StorageError::Locked` if the lock is still held after the last one.
+    pub fn open_with_retry(
+        config: &StorageConfig,
+        attempts: usize,
+        backoff: Duration,
+    ) -> Result<Storage, StorageError> {
+        let mut attempt = 1;
+        let mut delay = backoff;
+        loop {
+            match Self::open(config) {
+                Err(StorageError::Locked(_)) if attempt < attempts => {
+                    thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Runs RocksDB's repair on the storage files before opening them, salvaging as much data as
+    /// possible after an unclean shutdown or a corruption reported by `check_integrity`. Entries
+    /// in corrupt blocks are lost.
+    pub fn open_and_repair(config: &StorageConfig) -> Result<Storage, StorageError> {
+        <rocksdb::TransactionDB>::repair(&create_options(config), config.path.as_str())?;
+        Self::open(config)
+    }
+
+    /// Tells whether the storage at `path` was created with a password by looking for its DEK, so
+    /// a tool can ask for the password only when one is needed. The files are opened read-only,
+    /// which works while another process has the storage open.
+    pub fn store_is_encrypted(path: &str) -> Result<bool, StorageError> {
+        let db = rocksdb::DB::open_for_read_only(&rocksdb::Options::default(), path, false)
+            .map_err(open_error)?;
+        if db.get(DEK_KEY).map_err(StorageError::ReadError)?.is_some() {
+            return Ok(true);
+        }
+        let legacy_dek = db.get(LEGACY_DEK_KEY).map_err(StorageError::ReadError)?;
+        Ok(legacy_dek.is_some_and(|dek| dek.starts_with(&COCOON_MAGIC)))
+    }
+
+    pub fn builder() -> StorageBuilder {
+        StorageBuilder::default()
+    }
+
+    /// Creates an empty storage without a password in a fresh directory under the system temp
+    /// directory, for tests. The returned guard deletes the directory when dropped.
+    ///
+    /// ```ignore
+    /// let (_guard, storage) = Storage::open_temp()?;
+    /// ```
+    pub fn open_temp() -> Result<(TempGuard, Storage), StorageError> {
+        let path = env::temp_dir().join(format!("storage_{}", Uuid::new_v4()));
+        let guard = TempGuard { path };
+        let config = StorageConfig::new(guard.path.to_string_lossy().to_string(), None);
+        let storage = Storage::new(&config)?;
+        Ok((guard, storage))
+    }
+
+    /// Decrypts and parses a whole backup without restoring it, so a backup can be checked before
+    /// it is trusted. Fails with `BackupDekMismatch` if the DEK file belongs to another backup,
+    /// with `WrongPassword` if it cannot be decrypted and with `CorruptBackup` if a record is
+    /// malformed.
+    pub fn verify_backup<P: AsRef<Path>>(
+        backup_path: &P,
+        dek_path: &P,
+        password: Secret<String>,
+    ) -> Result<BackupStats, StorageError> {
+        let (backup_file, dek) = open_backup_with_dek(backup_path, dek_path, &password, None)?;
+        let backup_reader = BackupFileReader::new(backup_file, &dek, None)?;
+
+        verify_backup_entries(backup_reader)
+    }
+
+    /// Same as `verify_backup`, for backups created with `backup_to_recipients` and decrypted with
+    /// an age identity file.
+    pub fn verify_backup_with_identity_file<P: AsRef<Path>>(
+        backup_path: &P,
+        identity_file: &P,
+    ) -> Result<BackupStats, StorageError> {
+        let (_, backup_file) = open_backup(backup_path)?;
+        let backup_reader = BackupFileReader::new_with_identity_file(backup_file, identity_file)?;
+
+        verify_backup_entries(backup_reader)
+    }
+}
+
+impl<C: Codec> Storage<C> {
+    pub(crate) fn open_db(
+        config: &StorageConfig,
+        password_policy: PasswordPolicy,
+        create_if_missing: bool,
+        read_only: bool,
+    ) -> Result<Storage<C>, StorageError> {
+        Self::open_db_with_options(
+            config,
+            create_options(config),
+            password_policy,
+            create_if_missing,
+            read_only,
+        )
+    }
+
+    fn open_db_with_options(
+        config: &StorageConfig,
+        mut options: rocksdb::Options,
+        password_policy: PasswordPolicy,
+        create_if_missing: bool,
+        read_only: bool,
+    ) -> Result<Storage<C>, StorageError> {
+        if config.encrypt_keys && config.password.is_none() {
+            return Err(StorageError::NoPasswordSet);
+        }
+        if config.sync_writes && config.disable_wal {
+            return Err(StorageError::InvalidConfig(
+                "sync_writes requires the write-ahead log, which disable_wal turns off".to_string(),
+            ));
+        }
+        // RocksDB merges the stored bytes, which are ciphertext when a password is set.
+        if config.merge_operator.is_some() && config.password.is_some() {
+            return Err(StorageError::InvalidConfig(
+                "merge_operator cannot be used with a password".to_string(),
+            ));
+        }
+        password_policy.validate()?;
+
+        if create_if_missing {
+            create_storage_dir(&config.path)?;
+        }
+
+        options.create_if_missing(create_if_missing);
+        let db = rocksdb::TransactionDB::open(
+            &options,
             &rocksdb::TransactionDBOptions::default(),
             config.path.as_str(),
-        )?;
-
-        let password_policy = if let Some(ref policy) = password_policy_config {
-            PasswordPolicy::new(policy.clone())
-        } else {
-            PasswordPolicy::default()
-        };
+        )
+        .map_err(open_error)?;
+        if !read_only {
+            migrate_legacy_restore_checkpoint(&db)?;
+        }
 
         let dek = if let Some(ref password) = config.password {
-            if !password_policy.is_valid(password.expose_secret()) {
-                return Err(StorageError::WeakPassword(password_policy));
-            }
-            let dek = match db.get(DEK_KEY).map_err(|_| StorageError::ReadError)? {
+            password_policy
+                .check(password.expose_secret())
+                .map_err(StorageError::WeakPassword)?;
+            migrate_legacy_dek(&db, read_only)?;
+            let dek = match db.get(DEK_KEY).map_err(StorageError::ReadError)? {
                 Some(encrypted_dek) => {
-                    let mut entry_cursor = Cursor::new(encrypted_dek);
-
-                    let cocoon = Cocoon::new(password.expose_secret().as_bytes());
-                    let dek = cocoon
-                        .parse(&mut entry_cursor)
-                        .map_err(|_| StorageError::WrongPassword)?;
-
-                    dek
+                    unwrap_dek(encrypted_dek, password, config.kdf_params.max_work_factor)?
                 }
+                // Values already written in plaintext would fail to decrypt.
+                None if db.iterator(rocksdb::IteratorMode::Start).next().is_some() => {
+                    return Err(StorageError::NotEncrypted)
+                }
+                // A read-only storage cannot store a new DEK.
+                None if read_only => return Err(StorageError::ReadOnly),
                 None => {
-                    let mut bytes = [0u8; 32];
-                    OsRng.try_fill_bytes(&mut bytes)?;
-
-                    let mut entry_cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
-                    let mut cocoon = Cocoon::new(password.expose_secret().as_bytes());
-                    cocoon
-                        .dump(bytes.to_vec(), &mut entry_cursor)
-                        .map_err(|error| StorageError::FailedToEncryptData { error })?;
-                    let encrypted_dek = entry_cursor.into_inner();
+                    let mut bytes = Zeroizing::new([0u8; 32]);
+                    OsRng.try_fill_bytes(bytes.as_mut())?;
+
+                    let encrypted_dek = wrap_dek(bytes.as_ref(), password, &config.kdf_params)?;
                     db.put(DEK_KEY.as_bytes(), encrypted_dek)
-                        .map_err(|_| StorageError::WriteError)?;
-                    bytes.to_vec()
+                        .map_err(StorageError::WriteError)?;
+                    Zeroizing::new(bytes.to_vec())
                 }
             };
 
             Some(dek)
         } else {
+            // Without the DEK every value would read back as ciphertext.
+            if db.get(DEK_KEY).map_err(StorageError::ReadError)?.is_some()
+                || legacy_dek(&db)?.is_some()
+            {
+                return Err(StorageError::PasswordRequired);
+            }
             None
         };
 
+        let key_cipher = match &dek {
+            Some(dek) if config.encrypt_keys => Some(RefCell::new(KeyCipher::new(dek))),
+            _ => None,
+        };
+        let password_tag = dek
+            .as_ref()
+            .zip(config.password.as_ref())
+            .map(|(dek, password)| password_tag(dek, password));
+
         Ok(Storage {
             db,
             transactions: RefCell::new(HashMap::new()),
-            password: dek,
+            password: dek.map(RefCell::new),
+            password_tag: RefCell::new(password_tag),
             password_policy,
+            kdf_params: config.kdf_params.clone(),
+            key_cipher,
+            prefix_length: config.prefix_length,
+            read_only,
+            cache: config
+                .cache_capacity
+                .filter(|capacity| *capacity > 0)
+                .map(|capacity| RefCell::new(ReadCache::new(capacity))),
+            backup_batch_size: config
+                .backup_batch_size
+                .filter(|batch_size| *batch_size > 0)
+                .map_or(DEFAULT_BACKUP_BATCH_SIZE, |batch_size| batch_size as u64),
+            metrics: config.metrics.then(Metrics::default),
+            max_key_length: config.max_key_length,
+            max_value_bytes: config.max_value_bytes,
+            compression: config.compression,
+            write_options: write_options(config),
+            options,
+            merge_operator: config.merge_operator,
+            lossy_utf8: config.lossy_utf8,
+            codec: PhantomData,
         })
     }
 
+    /// Starts a transaction that commits with the durability set in the `StorageConfig`.
+    fn transaction(&self) -> rocksdb::Transaction<'_, TransactionDB> {
+        self.db
+            .transaction_opt(&self.write_options, &rocksdb::TransactionOptions::default())
+    }
+
+    /// Rejects keys that are empty or longer than `StorageConfig::max_key_length`.
+    fn check_key(&self, key: &str) -> Result<(), StorageError> {
+        if key.is_empty() {
+            return Err(StorageError::InvalidKey("key is empty".to_string()));
+        }
+        check_reserved(key)?;
+        match self.max_key_length {
+            Some(max_key_length) if key.len() > max_key_length => {
+                Err(StorageError::InvalidKey(format!(
+                    "key is {} bytes long, the maximum is {}",
+                    key.len(),
+                    max_key_length
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Rejects values longer than `StorageConfig::max_value_bytes`, not counting the expiry
+    /// header of a value written with a TTL.
+    fn check_value_size(&self, data: &[u8]) -> Result<(), StorageError> {
+        let size = if has_expiry(data) {
+            data.len() - EXPIRY_HEADER_LEN
+        } else {
+            data.len()
+        };
+        match self.max_value_bytes {
+            Some(limit) if size > limit => Err(StorageError::ValueTooLarge { size, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_writable(&self) -> Result<(), StorageError> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
+        }
+        Ok(())
+    }
+
     pub fn change_password(
         &self,
         old_password: Secret<String>,
         new_password: Secret<String>,
     ) -> Result<(), StorageError> {
+        self.check_writable()?;
+        let old_password = Zeroizing::new(old_password);
+        let new_password = Zeroizing::new(new_password);
+
         match &self.password {
             Some(_) => {
-                if !self.password_policy.is_valid(&new_password.expose_secret()) {
-                    return Err(StorageError::WeakPassword(self.password_policy.clone()));
-                }
+                self.password_policy
+                    .check(new_password.expose_secret())
+                    .map_err(StorageError::WeakPassword)?;
             }
             None => return Err(StorageError::NoPasswordSet),
         }
 
-        let dek = match self.db.get(DEK_KEY).map_err(|_| StorageError::ReadError)? {
-            Some(encrypted_dek) => {
-                let mut entry_cursor = Cursor::new(encrypted_dek);
-
-                let cocoon = Cocoon::new(old_password.expose_secret().as_bytes());
-                let dek = cocoon
-                    .parse(&mut entry_cursor)
-                    .map_err(|_| StorageError::WrongPassword)?;
-
-                dek
-            }
-            None => return Err(StorageError::NotFound("DEK".to_string())),
+        let dek = match self.cached_dek(&old_password) {
+            Some(dek) => dek,
+            None => match self.db.get(DEK_KEY).map_err(StorageError::ReadError)? {
+                Some(encrypted_dek) => unwrap_dek(
+                    encrypted_dek,
+                    &old_password,
+                    self.kdf_params.max_work_factor,
+                )?,
+                None => {
+                    return Err(StorageError::NotFound {
+                        kind: "DEK",
+                        key: DEK_KEY.to_string(),
+                    })
+                }
+            },
         };
 
-        let mut entry_cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
-        let mut cocoon = Cocoon::new(new_password.expose_secret().as_bytes());
-        cocoon
-            .dump(dek, &mut entry_cursor)
-            .map_err(|error| StorageError::FailedToEncryptData { error })?;
-        let encrypted_dek = entry_cursor.into_inner();
+        let encrypted_dek = wrap_dek(&dek, &new_password, &self.kdf_params)?;
         self.db
             .put(DEK_KEY.as_bytes(), encrypted_dek)
-            .map_err(|_| StorageError::WriteError)?;
+            .map_err(StorageError::WriteError)?;
+        *self.password_tag.borrow_mut() = Some(password_tag(&dek, &new_password));
 
         Ok(())
     }
 
-    pub fn change_backup_password<P: AsRef<Path>>(
-        &self,
-        dek_path: &P,
-        old_password: Secret<String>,
-        new_password: Secret<String>,
-    ) -> Result<(), StorageError> {
-        if !self.password_policy.is_valid(&new_password.expose_secret()) {
-            return Err(StorageError::WeakPassword(self.password_policy.clone()));
-        }
-
-        let mut dek_file = File::open(dek_path)?;
-        let mut buf = Vec::new();
-        dek_file.read_to_end(&mut buf)?;
-
-        let mut entry_cursor = Cursor::new(buf);
-
-        let cocoon = Cocoon::new(old_password.expose_secret().as_bytes());
-        let dek = cocoon
-            .parse(&mut entry_cursor)
-            .map_err(|_| StorageError::WrongPassword)?;
+    /// Returns the cached DEK if `password` is the current storage password, sparing the key
+    /// derivation of unwrapping the stored DEK. `None` means the password could not be recognized,
+    /// not that it is wrong.
+    fn cached_dek(&self, password: &Secret<String>) -> Option<Zeroizing<Vec<u8>>> {
+        let dek = self.password.as_ref()?.borrow();
+        let tag = (*self.password_tag.borrow())?;
+        password_mac(&dek, password).verify_slice(&tag).ok()?;
+        Some(dek.clone())
+    }
 
-        let mut new_entry_cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
-        let mut new_cocoon = Cocoon::new(new_password.expose_secret().as_bytes());
-        new_cocoon
-            .dump(dek, &mut new_entry_cursor)
-            .map_err(|error| StorageError::FailedToEncryptData { error })?;
-        let encrypted_dek = new_entry_cursor.into_inner();
+    /// Checks `password` against the stored DEK without changing the store.
+    pub fn verify_password(&self, password: Secret<String>) -> Result<bool, StorageError> {
+        let password = Zeroizing::new(password);
 
-        let mut dek_file = File::create(dek_path)?;
-        dek_file.write_all(&encrypted_dek)?;
+        if self.password.is_none() {
+            return Err(StorageError::NoPasswordSet);
+        }
+        if self.cached_dek(&password).is_some() {
+            return Ok(true);
+        }
 
-        Ok(())
+        match self.db.get(DEK_KEY).map_err(StorageError::ReadError)? {
+            Some(encrypted_dek) => {
+                match unwrap_dek(encrypted_dek, &password, self.kdf_params.max_work_factor) {
+                    Ok(_) => Ok(true),
+                    Err(StorageError::WrongPassword) => Ok(false),
+                    Err(error) => Err(error),
+                }
+            }
+            None => Err(StorageError::NotFound {
+                kind: "DEK",
+                key: DEK_KEY.to_string(),
+            }),
+        }
     }
 
-    pub fn restore_backup<P: AsRef<Path>>(
-        &self,
-        backup_path: &P,
-        dek_path: &P,
-        password: Secret<String>,
-    ) -> Result<(), StorageError> {
-        let backup_file = File::open(backup_path)?;
-        let backup_file = BufReader::new(backup_file);
-        let mut dek_file = File::open(dek_path)?;
-        let mut buf = Vec::new();
-        let transaction_id = self.begin_transaction();
-        let result: Result<(), StorageError> = {
-            let mut encrypted_dek = Vec::new();
-            dek_file.read_to_end(&mut encrypted_dek)?;
-            let mut entry_cursor = Cursor::new(encrypted_dek);
-
-            let cocoon = Cocoon::new(password.expose_secret().as_bytes());
-            let dek = cocoon
-                .parse(&mut entry_cursor)
-                .map_err(|_| StorageError::WrongPassword)?;
+    /// Replaces the DEK with a freshly generated one and re-encrypts every value, and every key if
+    /// keys are encrypted, with it. `password` must be the current storage password.
+    ///
+    /// Entries are rewritten in batches, with the new DEK kept under its own key until the last
+    /// batch swaps it in. If a rotation is interrupted, the storage cannot be read until
+    /// `rotate_dek` is called again with the same password to finish it.
+    pub fn rotate_dek(&self, password: Secret<String>) -> Result<(), StorageError> {
+        self.check_writable()?;
+        let password = Zeroizing::new(password);
+
+        if self.password.is_none() {
+            return Err(StorageError::NoPasswordSet);
+        }
 
-            let mut backup_reader = BackupFileReader::new(backup_file, dek)?;
+        let old_dek = match self.db.get(DEK_KEY).map_err(StorageError::ReadError)? {
+            Some(encrypted_dek) => {
+                unwrap_dek(encrypted_dek, &password, self.kdf_params.max_work_factor)?
+            }
+            None => {
+                return Err(StorageError::NotFound {
+                    kind: "DEK",
+                    key: DEK_KEY.to_string(),
+                })
+            }
+        };
 
-            while backup_reader.read_until(b';', &mut buf)? != 0 {
-                buf.pop();
-                let mut parts = buf.splitn(2, |&b| b == b',');
-                if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
-                    let key = String::from_utf8(key.to_vec())
-                        .map_err(|_| StorageError::ConversionError)?;
-                    let value = String::from_utf8(value.to_vec())
-                        .map_err(|_| StorageError::ConversionError)?;
-                    let key = hex::decode(key).map_err(|_| StorageError::ConversionError)?;
-                    let value = hex::decode(value).map_err(|_| StorageError::ConversionError)?;
-
-                    let mut map = self.transactions.borrow_mut();
-                    let tx = map
-                        .get_mut(&transaction_id)
-                        .ok_or(StorageError::NotFound("Transaction".to_string()))?;
-                    tx.put(&key, &value).map_err(|_| StorageError::WriteError)?;
-                }
-                buf.clear();
+        // Resume an interrupted rotation with the DEK it was rotating to.
+        let new_dek = match self
+            .db
+            .get(DEK_ROTATION_KEY)
+            .map_err(StorageError::ReadError)?
+        {
+            Some(encrypted_dek) => {
+                unwrap_dek(encrypted_dek, &password, self.kdf_params.max_work_factor)?
+            }
+            None => {
+                let mut bytes = Zeroizing::new([0u8; 32]);
+                OsRng.try_fill_bytes(bytes.as_mut())?;
+                let encrypted_dek = wrap_dek(bytes.as_ref(), &password, &self.kdf_params)?;
+                self.db
+                    .put(DEK_ROTATION_KEY.as_bytes(), encrypted_dek)
+                    .map_err(StorageError::WriteError)?;
+                Zeroizing::new(bytes.to_vec())
             }
-            Ok(())
         };
 
+        let mut transaction_id = self.begin_transaction();
+        let result = self.rotate_entries(&old_dek, &new_dek, &mut transaction_id);
+
+        let result = result.and_then(|_| {
+            let encrypted_dek = wrap_dek(&new_dek, &password, &self.kdf_params)?;
+            let mut map = self.transactions.borrow_mut();
+            let tx = map
+                .get_mut(&transaction_id)
+                .map(|open| &open.transaction)
+                .ok_or_else(|| StorageError::NotFound {
+                    kind: "Transaction",
+                    key: transaction_id.to_string(),
+                })?;
+            tx.put(DEK_KEY.as_bytes(), encrypted_dek)
+                .map_err(StorageError::WriteError)?;
+            tx.delete(DEK_ROTATION_KEY.as_bytes())
+                .map_err(StorageError::WriteError)
+        });
+
         if result.is_err() {
-            self.rollback_transaction(transaction_id)?;
-        } else {
-            self.commit_transaction(transaction_id)?;
+            // The transaction is already gone if an intermediate commit failed.
+            let _ = self.rollback_transaction(transaction_id);
+            return result;
         }
+        self.commit_transaction(transaction_id)?;
 
-        result
+        if let Some(key_cipher) = &self.key_cipher {
+            *key_cipher.borrow_mut() = KeyCipher::new(&new_dek);
+        }
+        *self.password_tag.borrow_mut() = Some(password_tag(&new_dek, &password));
+        if let Some(dek) = &self.password {
+            *dek.borrow_mut() = new_dek;
+        }
+
+        Ok(())
     }
 
-    pub fn backup<P: AsRef<Path>>(
+    fn rotate_entries(
         &self,
-        backup_path: P,
-        dek_path: P,
-        password: Secret<String>,
+        old_dek: &[u8],
+        new_dek: &[u8],
+        transaction_id: &mut Uuid,
     ) -> Result<(), StorageError> {
-        if !self.password_policy.is_valid(&password.expose_secret()) {
-            return Err(StorageError::WeakPassword(self.password_policy.clone()));
-        }
+        let key_ciphers = self
+            .key_cipher
+            .as_ref()
+            .map(|_| (KeyCipher::new(old_dek), KeyCipher::new(new_dek)));
 
         let snapshot = self.db.snapshot();
-        let mut iter = snapshot.iterator(rocksdb::IteratorMode::Start);
-        let backup_file = File::create(backup_path)?;
-        let mut dek_file = File::create(dek_path)?;
-        let mut data_vec = Vec::new();
-        let mut item_counter = 0;
-
-        let mut dek = [0u8; 32];
-        OsRng.try_fill_bytes(&mut dek)?;
-
-        let mut entry_cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
-        let mut cocoon = Cocoon::new(password.expose_secret().as_bytes());
-        cocoon
-            .dump(dek.to_vec(), &mut entry_cursor)
-            .map_err(|error| StorageError::FailedToEncryptData { error })?;
-        let encrypted_dek = entry_cursor.into_inner();
-        dek_file.write_all(&encrypted_dek)?;
-
-        let mut backup_writer = BackupFileWriter::new(backup_file, dek.to_vec())?;
-
-        while let Some(Ok((k, v))) = iter.next() {
-            data_vec.push((k.to_vec(), v.to_vec()));
-
-            if item_counter == 1000 {
-                let mut serialized_data = String::new();
-                for (key, value) in &data_vec {
-                    let key = hex::encode(key);
-                    let value = hex::encode(value);
-                    serialized_data.push_str(&format!("{},{};", key, value));
+        let mut pending = 0;
+
+        // A read error must end the rotation as failed, or the DEK would be swapped with entries
+        // left under the old one.
+        for entry in user_entries(snapshot.iterator(rocksdb::IteratorMode::Start)) {
+            let (stored_key, stored_value) = entry?;
+            // Entries that no longer decrypt with the old DEK were already rotated by an
+            // interrupted call.
+            let key = match &key_ciphers {
+                Some((old_key_cipher, _)) => match old_key_cipher.decrypt(&stored_key) {
+                    Some(key) => key,
+                    None => continue,
+                },
+                None => stored_key.to_vec(),
+            };
+            let value = match decrypt_value(old_dek, &key, stored_value.to_vec()) {
+                Ok(value) => value,
+                Err(_) if decrypt_value(new_dek, &key, stored_value.to_vec()).is_ok() => continue,
+                Err(error) => return Err(error),
+            };
+            let value = encrypt_value(new_dek, &key, value)?;
+
+            {
+                let mut map = self.transactions.borrow_mut();
+                let tx = map
+                    .get_mut(transaction_id)
+                    .map(|open| &open.transaction)
+                    .ok_or_else(|| StorageError::NotFound {
+                        kind: "Transaction",
+                        key: transaction_id.to_string(),
+                    })?;
+                match &key_ciphers {
+                    Some((_, new_key_cipher)) => {
+                        tx.delete(&stored_key).map_err(StorageError::WriteError)?;
+                        tx.put(new_key_cipher.encrypt(&key), value)
+                            .map_err(StorageError::WriteError)?;
+                    }
+                    None => tx
+                        .put(&stored_key, value)
+                        .map_err(StorageError::WriteError)?,
                 }
-                backup_writer.write_all(serialized_data.as_bytes())?;
-                item_counter = 0;
-                data_vec.clear();
-            } else {
-                item_counter += 1;
             }
-        }
 
-        if !data_vec.is_empty() {
-            let mut serialized_data = String::new();
-            for (key, value) in &data_vec {
-                let key = hex::encode(key);
-                let value = hex::encode(value);
-                serialized_data.push_str(&format!("{},{};", key, value));
+            pending += 1;
+            if pending >= ROTATION_BATCH_SIZE {
+                self.commit_transaction(*transaction_id)?;
+                *transaction_id = self.begin_transaction();
+                pending = 0;
             }
-            backup_writer.write_all(serialized_data.as_bytes())?;
         }
 
-        backup_writer.finish()?;
-
         Ok(())
     }
 
-    pub fn delete_db_files(storage: Storage) -> Result<(), StorageError> {
-        let path = PathBuf::from(storage.db.path());
-        drop(storage);
-        fs::remove_dir_all(path)?;
-        Ok(())
-    }
-
-    pub fn delete(&self, key: &str) -> Result<(), StorageError> {
-        let tx = self.db.transaction();
-        tx.delete(key.as_bytes())
-            .map_err(|_| StorageError::WriteError)?;
-        tx.commit().map_err(|_| StorageError::CommitError)?;
+    pub fn change_backup_password<P: AsRef<Path>>(
+        &self,
+        dek_path: &P,
+        old_password: Secret<String>,
+        new_password: Secret<String>,
+    ) -> Result<(), StorageError> {
+        let old_password = Zeroizing::new(old_password);
+        let new_password = Zeroizing::new(new_password);
+
+        self.password_policy
+            .check(new_password.expose_secret())
+            .map_err(StorageError::WeakPassword)?;
+
+        let (backup_id, encrypted_dek) = read_dek_file(dek_path)?;
+        let dek = unwrap_dek(
+            encrypted_dek,
+            &old_password,
+            self.kdf_params.max_work_factor,
+        )?;
+        let encrypted_dek = wrap_dek(&dek, &new_password, &self.kdf_params)?;
 
-        Ok(())
+        write_dek_file(dek_path, backup_id.as_ref(), &encrypted_dek)
     }
 
-    pub fn transactional_delete(
+    pub fn restore_backup<P: AsRef<Path>>(
         &self,
-        key: &str,
-        transaction_id: Uuid,
-    ) -> Result<(), StorageError> {
-        let mut map = self.transactions.borrow_mut();
-        let tx = map
-            .get_mut(&transaction_id)
-            .ok_or(StorageError::NotFound("Transaction".to_string()))?;
-        tx.delete(key.as_bytes())
-            .map_err(|_| StorageError::WriteError)?;
+        backup_path: &P,
+        dek_path: &P,
+        password: Secret<String>,
+        mode: RestoreMode,
+    ) -> Result<RestoreReport, StorageError> {
+        self.restore_backup_with_progress(backup_path, dek_path, password, mode, None)
+    }
 
-        Ok(())
+    /// Same as `restore_backup`, calling `progress` with the running number of restored entries
+    /// every `PROGRESS_INTERVAL` entries and once more at the end.
+    pub fn restore_backup_with_progress<P: AsRef<Path>>(
+        &self,
+        backup_path: &P,
+        dek_path: &P,
+        password: Secret<String>,
+        mode: RestoreMode,
+        progress: Option<&dyn Fn(u64)>,
+    ) -> Result<RestoreReport, StorageError> {
+        let max_work_factor = self.kdf_params.max_work_factor;
+        let (backup_file, dek) =
+            open_backup_with_dek(backup_path, dek_path, &password, max_work_factor)?;
+        let backup_reader = BackupFileReader::new(backup_file, &dek, max_work_factor)?;
+
+        self.restore(backup_reader, mode, None, progress)
     }
 
-    pub fn write(&self, key: &str, value: &str) -> Result<(), StorageError> {
-        let tx = self.db.transaction();
-        let mut data = value.as_bytes().to_vec();
+    /// Restores a backup created with `backup_to_recipients`, using the matching age X25519
+    /// private key.
+    pub fn restore_backup_with_identity<P: AsRef<Path>>(
+        &self,
+        backup_path: &P,
+        identity: &x25519::Identity,
+        mode: RestoreMode,
+    ) -> Result<RestoreReport, StorageError> {
+        let (_, backup_file) = open_backup(backup_path)?;
+        let backup_reader = BackupFileReader::new_with_identity(backup_file, identity)?;
 
-        if self.password.is_some() {
-            data = self.encrypt_data(data)?
+        self.restore(backup_reader, mode, None, None)
+    }
+
+    /// Restores a backup created with `backup_to_recipients`, reading the private key from an age
+    /// identity file so it never has to be passed on the command line.
+    pub fn restore_backup_with_identity_file<P: AsRef<Path>>(
+        &self,
+        backup_path: &P,
+        identity_file: &P,
+        mode: RestoreMode,
+    ) -> Result<RestoreReport, StorageError> {
+        let (_, backup_file) = open_backup(backup_path)?;
+        let backup_reader = BackupFileReader::new_with_identity_file(backup_file, identity_file)?;
+
+        self.restore(backup_reader, mode, None, None)
+    }
+
+    /// Restores a backup from any reader, using the already decrypted backup DEK.
+    ///
+    /// Entries are collected in a RocksDB write batch. With `commit_every` set to `None` the whole
+    /// backup is written as a single batch, so the restore is all-or-nothing but the batch grows
+    /// with the backup size. With `Some(n)` a batch is written every `n` entries, which bounds
+    /// memory usage on large backups at the cost of atomicity: an error or crash mid-restore leaves
+    /// the entries written so far in the store. With `RestoreMode::Replace` the existing keys are
+    /// deleted in the first batch.
+    ///
+    /// Each batch also records how far the restore got. Restoring the same backup again, with any
+    /// of the restore methods, skips the entries already written and carries on from there.
+    /// Backups written before manifests were added cannot be told apart, so they always restore
+    /// from the start.
+    pub fn restore_from<R: Read>(
+        &self,
+        reader: R,
+        dek: Vec<u8>,
+        mode: RestoreMode,
+        commit_every: Option<usize>,
+    ) -> Result<RestoreReport, StorageError> {
+        let dek = Zeroizing::new(dek);
+        let (_, reader) = read_backup_id(reader)?;
+        let backup_reader = BackupFileReader::new(reader, &dek, self.kdf_params.max_work_factor)?;
+        self.restore(backup_reader, mode, commit_every, None)
+    }
+
+    fn restore<R: Read>(
+        &self,
+        backup_reader: BackupFileReader<R>,
+        mode: RestoreMode,
+        commit_every: Option<usize>,
+        progress: Option<&dyn Fn(u64)>,
+    ) -> Result<RestoreReport, StorageError> {
+        self.check_writable()?;
+        // A write batch skips the per-key locking and conflict tracking of a transaction, which
+        // only add overhead to a bulk load.
+        let mut batch = rocksdb::WriteBatchWithTransaction::<true>::default();
+        let result = self
+            .restore_entries(backup_reader, mode, commit_every, progress, &mut batch)
+            .and_then(|report| {
+                batch.delete(RESTORE_CHECKPOINT_KEY);
+                self.db
+                    .write_opt(batch, &self.write_options)
+                    .map_err(StorageError::WriteError)?;
+                Ok(report)
+            });
+        self.clear_cache();
+
+        result
+    }
+
+    fn restore_entries<R: Read>(
+        &self,
+        mut backup_reader: BackupFileReader<R>,
+        mode: RestoreMode,
+        commit_every: Option<usize>,
+        progress: Option<&dyn Fn(u64)>,
+        batch: &mut rocksdb::WriteBatchWithTransaction<true>,
+    ) -> Result<RestoreReport, StorageError> {
+        let format = backup_reader.read_format()?;
+        let backup_id = backup_reader.created_at();
+        let resume_after = match (backup_id, self.restore_checkpoint()?) {
+            (Some(backup_id), Some((checkpoint_id, restored))) if backup_id == checkpoint_id => {
+                restored
+            }
+            _ => 0,
+        };
+        // A resumed restore already deleted the existing keys in its first batch.
+        if mode == RestoreMode::Replace && resume_after == 0 {
+            self.delete_all_keys(batch)?;
+        }
+
+        let mut pending = 0;
+        let mut restored = 0;
+        let mut bytes_read = 0;
+        for_each_backup_entry(&mut backup_reader, format, |key, value| {
+            restored += 1;
+            bytes_read += (key.len() + value.len()) as u64;
+            if restored > resume_after {
+                self.restore_entry(key, value, format, batch)?;
+                pending += 1;
+            }
+            if commit_every.is_some_and(|batch_size| pending >= batch_size) {
+                if let Some(backup_id) = backup_id {
+                    batch.put(
+                        RESTORE_CHECKPOINT_KEY,
+                        format!("{}:{}", backup_id, restored),
+                    );
+                }
+                self.db
+                    .write_opt(std::mem::take(batch), &self.write_options)
+                    .map_err(StorageError::WriteError)?;
+                pending = 0;
+            }
+            if restored % PROGRESS_INTERVAL == 0 {
+                report_progress(progress, restored);
+            }
+            Ok(())
+        })?;
+
+        if restored % PROGRESS_INTERVAL != 0 {
+            report_progress(progress, restored);
+        }
+
+        Ok(RestoreReport {
+            entries_restored: restored,
+            bytes_read,
+        })
+    }
+
+    fn delete_all_keys(
+        &self,
+        batch: &mut rocksdb::WriteBatchWithTransaction<true>,
+    ) -> Result<(), StorageError> {
+        for entry in user_entries(self.db.iterator(rocksdb::IteratorMode::Start)) {
+            let (k, _) = entry?;
+            batch.delete(&k);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the backup id and the number of entries recorded by the last batch of an
+    /// interrupted restore.
+    fn restore_checkpoint(&self) -> Result<Option<(u64, u64)>, StorageError> {
+        let Some(checkpoint) = self
+            .db
+            .get(RESTORE_CHECKPOINT_KEY)
+            .map_err(StorageError::ReadError)?
+        else {
+            return Ok(None);
+        };
+        Ok(parse_restore_checkpoint(&checkpoint))
+    }
+
+    fn restore_entry(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        format: BackupFormat,
+        batch: &mut rocksdb::WriteBatchWithTransaction<true>,
+    ) -> Result<(), StorageError> {
+        // Older backups may contain the source storage DEK, which must not replace ours.
+        if is_internal_key(key) {
+            return Ok(());
         }
 
-        tx.put(key.as_bytes(), data)
-            .map_err(|_| StorageError::WriteError)?;
-        tx.commit().map_err(|_| StorageError::CommitError)?;
+        // Legacy hex backups hold the values as they were stored, already encrypted with the DEK
+        // of the storage backed up, so they are written back as they are.
+        let mut value = value.to_vec();
+        if self.password.is_some() && format != BackupFormat::LegacyHex {
+            value = self.encrypt_data(key, value)?;
+        }
 
+        batch.put(self.encode_key(key), value);
         Ok(())
     }
 
-    pub fn transactional_write(
+    pub fn backup<P: AsRef<Path>>(
         &self,
-        key: &str,
-        value: &str,
-        transaction_id: Uuid,
+        backup_path: P,
+        dek_path: P,
+        password: Secret<String>,
     ) -> Result<(), StorageError> {
-        let mut map = self.transactions.borrow_mut();
-        let tx = map
-            .get_mut(&transaction_id)
-            .ok_or(StorageError::NotFound("Transaction".to_string()))?;
-        let mut data = value.as_bytes().to_vec();
+        self.backup_with_progress(backup_path, dek_path, password, None)
+    }
 
-        if self.password.is_some() {
-            data = self.encrypt_data(data)?
+    /// Same as `backup`, calling `progress` with the running number of backed up entries every
+    /// time a batch of `StorageConfig::backup_batch_size` entries is flushed, and once more at the
+    /// end.
+    pub fn backup_with_progress<P: AsRef<Path>>(
+        &self,
+        backup_path: P,
+        dek_path: P,
+        password: Secret<String>,
+        progress: Option<&dyn Fn(u64)>,
+    ) -> Result<(), StorageError> {
+        self.consistent_backup(backup_path, dek_path, password, progress)?;
+        Ok(())
+    }
+
+    /// Same as `backup_with_progress`, returning the figures `verify_backup` reports for the
+    /// backup. The snapshot is pinned, along with the DEK, before the backup DEK is generated, and
+    /// its sequence number is recorded in the manifest. Writes made while the backup runs,
+    /// including from `progress`, are left out of it, and a `rotate_dek` meanwhile does not stop
+    /// the snapshot values from being decrypted.
+    pub fn consistent_backup<P: AsRef<Path>>(
+        &self,
+        backup_path: P,
+        dek_path: P,
+        password: Secret<String>,
+        progress: Option<&dyn Fn(u64)>,
+    ) -> Result<BackupStats, StorageError> {
+        let snapshot = self.snapshot();
+        let (dek, backup_id) = self.create_backup_dek(dek_path, &password)?;
+        let backup_file = create_private_file(backup_path)?;
+
+        let mut backup_writer = BackupFileWriter::new(
+            backup_file,
+            dek.as_ref(),
+            self.kdf_params.backup_work_factor,
+            snapshot.sequence(),
+            &backup_id,
+        )?;
+        let stats = self.write_backup_entries(&snapshot, &mut backup_writer, progress)?;
+        backup_writer.finish()?;
+
+        Ok(stats)
+    }
+
+    /// Same as `backup`, splitting the backup into `backup.000`, `backup.001`, ... files in `dir`,
+    /// each at most `part_size` bytes long. The backup is encrypted as a single stream that is cut
+    /// into parts, so every part is needed to restore it and none can be decrypted on its own.
+    /// Restoring or verifying from `dir` reads the parts back in order. Returns the number of
+    /// parts written.
+    pub fn backup_split<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        part_size: u64,
+        dek_path: P,
+        password: Secret<String>,
+    ) -> Result<usize, StorageError> {
+        if part_size == 0 {
+            return Err(StorageError::InvalidConfig(
+                "part size must be greater than zero".to_string(),
+            ));
         }
+        let snapshot = self.snapshot();
+        let (dek, backup_id) = self.create_backup_dek(dek_path, &password)?;
+        fs::create_dir_all(&dir)?;
+
+        let mut backup_writer = BackupFileWriter::new(
+            PartWriter::new(dir.as_ref(), part_size),
+            dek.as_ref(),
+            self.kdf_params.backup_work_factor,
+            snapshot.sequence(),
+            &backup_id,
+        )?;
+        self.write_backup_entries(&snapshot, &mut backup_writer, None)?;
+        Ok(backup_writer.finish()?.finish()?)
+    }
+
+    /// Generates the DEK and the id of a new backup and writes them to `dek_path`, the DEK
+    /// encrypted with `password`.
+    fn create_backup_dek<P: AsRef<Path>>(
+        &self,
+        dek_path: P,
+        password: &Secret<String>,
+    ) -> Result<(Zeroizing<[u8; 32]>, BackupId), StorageError> {
+        self.password_policy
+            .check(password.expose_secret())
+            .map_err(StorageError::WeakPassword)?;
 
-        tx.put(key.as_bytes(), data)
-            .map_err(|_| StorageError::WriteError)?;
+        let mut dek = Zeroizing::new([0u8; 32]);
+        OsRng.try_fill_bytes(dek.as_mut())?;
+        let backup_id = new_backup_id()?;
+
+        let encrypted_dek = wrap_dek(dek.as_ref(), password, &self.kdf_params)?;
+        write_dek_file(dek_path, Some(&backup_id), &encrypted_dek)?;
+
+        Ok((dek, backup_id))
+    }
+
+    /// Creates a backup encrypted to the given age X25519 public keys instead of a password, so
+    /// it can only be restored by the holder of one of the matching private keys. No DEK file is
+    /// written.
+    pub fn backup_to_recipients<P: AsRef<Path>>(
+        &self,
+        backup_path: P,
+        recipients: Vec<x25519::Recipient>,
+    ) -> Result<(), StorageError> {
+        let snapshot = self.snapshot();
+        let backup_file = create_private_file(backup_path)?;
+        let mut backup_writer = BackupFileWriter::new_with_recipients(
+            backup_file,
+            recipients,
+            snapshot.sequence(),
+            &new_backup_id()?,
+        )?;
+        self.write_backup_entries(&snapshot, &mut backup_writer, None)?;
+        backup_writer.finish()?;
 
         Ok(())
     }
 
-    pub fn read(&self, key: &str) -> Result<Option<String>, StorageError> {
-        match self.db.get(key.as_bytes()) {
-            Ok(Some(mut data)) => {
-                if self.password.is_some() {
-                    data = self.decrypt_data(data)?;
-                }
+    fn write_backup_entries<W: Write>(
+        &self,
+        snapshot: &StorageSnapshot<'_, C>,
+        backup_writer: &mut BackupFileWriter<W>,
+        progress: Option<&dyn Fn(u64)>,
+    ) -> Result<BackupStats, StorageError> {
+        // The snapshot values are encrypted with the DEK of the time it was taken, which a
+        // `rotate_dek` called from `progress` would replace.
+        let dek = self.password.as_ref().map(|dek| dek.borrow().clone());
+        let key_cipher = dek
+            .as_ref()
+            .filter(|_| self.key_cipher.is_some())
+            .map(|dek| KeyCipher::new(dek));
+        let mut backed_up = 0;
+        let mut bytes = 0;
+
+        // Records go straight to the writer, which buffers them into age chunks itself.
+        for entry in user_entries(snapshot.snapshot.iterator(rocksdb::IteratorMode::Start)) {
+            let (k, v) = entry?;
+
+            // Values are stored in plaintext inside the encrypted backup, so it can be restored
+            // into a storage with a different DEK.
+            let k = match &key_cipher {
+                Some(key_cipher) => key_cipher
+                    .decrypt(&k)
+                    .ok_or(StorageError::FailedToDecryptKey)?,
+                None => k.into_vec(),
+            };
+            let v = match &dek {
+                Some(dek) => Zeroizing::new(decrypt_value(dek, &k, v.into_vec())?),
+                None => Zeroizing::new(v.into_vec()),
+            };
+            backup_writer.write_record(&k, &v)?;
 
-                let data_ret =
-                    String::from_utf8(data).map_err(|_| StorageError::ConversionError)?;
-                Ok(Some(data_ret))
+            backed_up += 1;
+            bytes += (k.len() + v.len()) as u64;
+            if backed_up % self.backup_batch_size == 0 {
+                backup_writer.flush()?;
+                report_progress(progress, backed_up);
             }
-            Ok(None) => Ok(None),
-            Err(_) => Err(StorageError::ReadError),
         }
+
+        if backed_up % self.backup_batch_size != 0 {
+            report_progress(progress, backed_up);
+        }
+
+        Ok(BackupStats {
+            entries: backed_up,
+            bytes,
+            created_at: Some(backup_writer.created_at()),
+            sequence: Some(snapshot.sequence()),
+        })
     }
 
-    pub fn is_empty(&self) -> bool {
-        let iter = self.db.iterator(rocksdb::IteratorMode::Start);
-        let is_empty = iter.peekable().peek().is_none();
-        is_empty
+    /// Sums the sizes of the files in the storage directory, including the write-ahead log and
+    /// files RocksDB has not cleaned up yet.
+    pub fn disk_size(&self) -> Result<u64, StorageError> {
+        dir_size(self.db.path())
     }
 
-    pub fn keys(&self) -> Result<Vec<String>, StorageError> {
-        let mut result = Vec::new();
-        let mut iter = self.db.iterator(rocksdb::IteratorMode::Start);
-        while let Some(Ok((k, _))) = iter.next() {
-            let k = String::from_utf8(k.to_vec()).map_err(|_| StorageError::ConversionError)?;
-            result.push(k);
+    /// Collects the key count and on-disk figures from the `rocksdb.estimate-num-keys` and
+    /// `rocksdb.num-live-sst-files` properties, without scanning the storage.
+    pub fn stats(&self) -> Result<StorageStats, StorageError> {
+        Ok(StorageStats {
+            estimate_num_keys: self.property_int("rocksdb.estimate-num-keys")?.unwrap_or(0),
+            num_live_sst_files: self
+                .property_int("rocksdb.num-live-sst-files")?
+                .unwrap_or(0),
+            disk_size: self.disk_size()?,
+        })
+    }
+
+    /// Returns the RocksDB property `name`, such as `rocksdb.stats` or
+    /// `rocksdb.cur-size-all-mem-tables`, or `None` if RocksDB does not know it.
+    pub fn property(&self, name: &str) -> Result<Option<String>, StorageError> {
+        self.db
+            .property_value(name)
+            .map_err(StorageError::ReadError)
+    }
+
+    /// Same as `property`, for the properties with an integer value, such as
+    /// `rocksdb.num-running-compactions`.
+    pub fn property_int(&self, name: &str) -> Result<Option<u64>, StorageError> {
+        self.db
+            .property_int_value(name)
+            .map_err(StorageError::ReadError)
+    }
+
+    /// Reads every entry with checksum verification, returning `StorageError::Corruption` with the
+    /// RocksDB error for the first corrupt block found.
+    pub fn check_integrity(&self) -> Result<(), StorageError> {
+        let mut read_options = rocksdb::ReadOptions::default();
+        read_options.set_verify_checksums(true);
+        read_options.fill_cache(false);
+        read_options.set_total_order_seek(true);
+
+        for entry in self
+            .db
+            .iterator_opt(rocksdb::IteratorMode::Start, read_options)
+        {
+            entry.map_err(|error| match error.kind() {
+                rocksdb::ErrorKind::Corruption => StorageError::Corruption(error),
+                _ => StorageError::ReadError(error),
+            })?;
         }
-        Ok(result)
+        Ok(())
     }
 
-    pub fn partial_compare_keys(&self, key: &str) -> Result<Vec<String>, StorageError> {
-        let mut result = Vec::new();
-        let mut iter = self.db.iterator(rocksdb::IteratorMode::From(
-            key.as_bytes(),
-            rocksdb::Direction::Forward,
-        ));
-        while let Some(Ok((k, _))) = iter.next() {
-            let k = String::from_utf8(k.to_vec()).map_err(|_| StorageError::ConversionError)?;
-            if k.starts_with(key) {
-                result.push(k);
-            } else {
+    /// Writes the memtables to SST files, blocking until they are persisted. Committed writes are
+    /// already in the write-ahead log unless `disable_wal` is set, so this is for a process about
+    /// to copy the files for a cold backup or to shut down without a long log replay on the next
+    /// open. With `disable_wal`, it is what makes the writes so far durable.
+    ///
+    /// The `TransactionDB` binding cannot flush, so the storage is closed, flushed as a plain
+    /// RocksDB database and opened again, which is why it is taken and given back. Fails with
+    /// `TransactionsOpen` while a transaction is open. The `ReopenError` holds the storage back
+    /// unless the files could not be opened again.
+    pub fn flush(self) -> Result<Self, ReopenError<C>> {
+        let (storage, ()) =
+            self.with_plain_db(|db| db.flush().map_err(StorageError::WriteError))?;
+        Ok(storage)
+    }
+
+    /// Compacts the keys from `start`, included, to `end`, excluded, a bound left as `None` being
+    /// open, so deleted entries stop taking disk space. Encrypted keys are not stored in plaintext
+    /// order, so a storage with `encrypt_keys` can only be compacted whole.
+    ///
+    /// Like `flush`, the storage is closed, compacted as a plain RocksDB database and opened
+    /// again, fails with `TransactionsOpen` while a transaction is open and is given back in the
+    /// `ReopenError` when it can be.
+    pub fn compact_range(
+        self,
+        start: Option<&str>,
+        end: Option<&str>,
+    ) -> Result<Self, ReopenError<C>> {
+        if self.key_cipher.is_some() && (start.is_some() || end.is_some()) {
+            let error = StorageError::InvalidKey(
+                "encrypted keys are not stored in plaintext order, so only the whole storage can \
+                 be compacted"
+                    .to_string(),
+            );
+            return Err(ReopenError::new(self, error));
+        }
+        let (storage, ()) = self.with_plain_db(|db| {
+            db.compact_range(start, end);
+            Ok(())
+        })?;
+        Ok(storage)
+    }
+
+    /// Compacts the storage to free the space still held by deleted entries, for example after
+    /// `delete_prefix` or a `Replace` restore. The keys are compacted in ranges of 10000 entries:
+    /// `progress` receives the number of ranges done after each one, and setting `cancel` from
+    /// another thread stops before the next range. A storage cancelled before the first range is
+    /// left open. Like `flush`, the storage is taken and given back, fails with
+    /// `TransactionsOpen` while a transaction is open and is given back in the `ReopenError` when
+    /// it can be.
+    pub fn reclaim_space(
+        self,
+        cancel: &AtomicBool,
+        progress: Option<&dyn Fn(u64)>,
+    ) -> Result<(Self, ReclaimReport), ReopenError<C>> {
+        let path = self.db.path().to_path_buf();
+        let bytes_before = match dir_size(&path) {
+            Ok(bytes) => bytes,
+            Err(error) => return Err(ReopenError::new(self, error)),
+        };
+        let cancelled_report = ReclaimReport {
+            bytes_before,
+            bytes_after: bytes_before,
+            cancelled: true,
+        };
+
+        // The bounds are read before closing the files, so a cancellation during the scan does
+        // not have to open them again.
+        let mut bounds = Vec::new();
+        let mut scan = Ok(());
+        for (index, entry) in self.db.iterator(rocksdb::IteratorMode::Start).enumerate() {
+            if cancel.load(Ordering::Relaxed) {
                 break;
             }
+            match entry {
+                Ok((key, _)) if index > 0 && index % RECLAIM_BATCH_SIZE == 0 => bounds.push(key),
+                Ok(_) => {}
+                Err(error) => {
+                    scan = Err(StorageError::ReadError(error));
+                    break;
+                }
+            }
+        }
+        if let Err(error) = scan {
+            return Err(ReopenError::new(self, error));
         }
+        if cancel.load(Ordering::Relaxed) {
+            return Ok((self, cancelled_report));
+        }
+
+        let (storage, cancelled) = self.with_plain_db(|db| {
+            let mut start = None;
+            for (done, end) in bounds.into_iter().map(Some).chain([None]).enumerate() {
+                if cancel.load(Ordering::Relaxed) {
+                    return Ok(true);
+                }
+                db.compact_range(start.as_deref(), end.as_deref());
+                report_progress(progress, done as u64 + 1);
+                start = end;
+            }
+            Ok(false)
+        })?;
 
-        Ok(result)
+        let bytes_after = match dir_size(&path) {
+            Ok(bytes) => bytes,
+            Err(error) => return Err(ReopenError::new(storage, error)),
+        };
+        let report = ReclaimReport {
+            bytes_before,
+            bytes_after,
+            cancelled,
+        };
+        Ok((storage, report))
     }
 
-    pub fn partial_compare(&self, key: &str) -> Result<Vec<(String, String)>, StorageError> {
-        let mut result = Vec::new();
-        let mut iter = self.db.iterator(rocksdb::IteratorMode::From(
-            key.as_bytes(),
-            rocksdb::Direction::Forward,
-        ));
-        while let Some(Ok((k, v))) = iter.next() {
-            let k = String::from_utf8(k.to_vec()).map_err(|_| StorageError::ConversionError)?;
-            let v = if self.password.is_some() {
-                self.decrypt_data(v.to_vec())?
-            } else {
-                v.to_vec()
-            };
-            let v = String::from_utf8(v).map_err(|_| StorageError::ConversionError)?;
-            if k.starts_with(key) {
-                result.push((k, v));
-            } else {
-                break;
+    /// Closes the storage files, runs `f` on them opened as a plain RocksDB `DB`, which can flush
+    /// and compact unlike the `TransactionDB` binding, and opens them again. The open transactions
+    /// borrow the `TransactionDB`, so none may be open. The storage is given back whenever the
+    /// files open again, whether `f` failed or not.
+    fn with_plain_db<T>(
+        mut self,
+        f: impl FnOnce(&rocksdb::DB) -> Result<T, StorageError>,
+    ) -> Result<(Self, T), ReopenError<C>> {
+        if let Err(error) = self.check_writable() {
+            return Err(ReopenError::new(self, error));
+        }
+        let open_transactions = self.active_transaction_count();
+        if open_transactions > 0 {
+            return Err(ReopenError::new(
+                self,
+                StorageError::TransactionsOpen(open_transactions),
+            ));
+        }
+
+        let path = self.db.path().to_path_buf();
+        drop(self.db);
+        // The plain DB is dropped, releasing the lock on the files, before they are opened again.
+        let result = rocksdb::DB::open(&self.options, &path)
+            .map_err(open_error)
+            .and_then(|db| f(&db));
+        match rocksdb::TransactionDB::open(
+            &self.options,
+            &rocksdb::TransactionDBOptions::default(),
+            &path,
+        ) {
+            Ok(db) => {
+                self.db = db;
+                match result {
+                    Ok(result) => Ok((self, result)),
+                    Err(error) => Err(ReopenError::new(self, error)),
+                }
             }
+            Err(error) => Err(ReopenError {
+                storage: None,
+                error: open_error(error),
+            }),
         }
+    }
+
+    pub fn delete_db_files(storage: Storage<C>) -> Result<(), StorageError> {
+        let path = PathBuf::from(storage.db.path());
+        drop(storage);
+        fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.check_writable()?;
+        check_reserved(key)?;
+        let tx = self.transaction();
+        tx.delete(self.encode_key(key.as_bytes()))
+            .map_err(|error| conflict_or(error, StorageError::WriteError))?;
+        tx.commit()
+            .map_err(|error| conflict_or(error, StorageError::CommitError))?;
+        self.invalidate_cached(key, None);
+        self.record(Operation::Delete);
 
-        Ok(result)
+        Ok(())
     }
 
-    pub fn has_key(&self, key: &str) -> Result<bool, StorageError> {
-        let result = self
-            .db
-            .get(key.as_bytes())
-            .map_err(|_| StorageError::ReadError)?;
-        Ok(result.is_some())
+    /// Reads `key` as seen by the transaction, bypassing the read cache. An expired value is
+    /// reported as missing but left for `purge_expired`, since deleting it would add it to the
+    /// transaction.
+    fn transactional_read_bytes(
+        &self,
+        key: &str,
+        transaction_id: Uuid,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        check_reserved(key)?;
+        let map = self.transactions.borrow();
+        let tx = map
+            .get(&transaction_id)
+            .map(|open| &open.transaction)
+            .ok_or_else(|| StorageError::NotFound {
+                kind: "Transaction",
+                key: transaction_id.to_string(),
+            })?;
+        self.record(Operation::Read);
+        let Some(data) = tx
+            .get(self.encode_key(key.as_bytes()))
+            .map_err(StorageError::ReadError)?
+        else {
+            return Ok(None);
+        };
+        let data = self.unseal_value(key.as_bytes(), data)?;
+        Ok(strip_expiry(data, unix_millis(SystemTime::now())))
     }
 
-    /// # Safety
-    /// This method uses `std::mem::transmute` to extend the transaction's lifetime to `'static`,
-    /// which is safe in this context because all transactions are stored in a `RefCell` within the `Storage` struct,
-    /// and are only accessed from the same thread.
-    /// Ensure that all transactions are properly committed or rolled back to avoid resource leaks.
-    pub fn begin_transaction(&self) -> Uuid {
-        let transaction = self.db.transaction();
+    pub fn transactional_delete(
+        &self,
+        key: &str,
+        transaction_id: Uuid,
+    ) -> Result<(), StorageError> {
+        self.check_writable()?;
+        check_reserved(key)?;
         let mut map = self.transactions.borrow_mut();
-        let id = Uuid::new_v4();
-        map.insert(
-            id,
-            Box::new(unsafe {
-                std::mem::transmute::<
-                    rocksdb::Transaction<'_, TransactionDB>,
-                    rocksdb::Transaction<'static, TransactionDB>,
-                >(transaction)
-            }),
-        );
-        id
+        let tx = map
+            .get_mut(&transaction_id)
+            .map(|open| &open.transaction)
+            .ok_or_else(|| StorageError::NotFound {
+                kind: "Transaction",
+                key: transaction_id.to_string(),
+            })?;
+        tx.delete(self.encode_key(key.as_bytes()))
+            .map_err(|error| conflict_or(error, StorageError::WriteError))?;
+        self.invalidate_cached(key, Some(transaction_id));
+        self.record(Operation::Delete);
+
+        Ok(())
+    }
+
+    pub fn write(&self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.write_bytes(key, value.as_bytes().to_vec(), None)
+    }
+
+    /// Same as `write`, but leaves the storage untouched when `key` already holds `value`.
+    /// Returns whether a write happened. Encryption is not deterministic, so the stored value is
+    /// decrypted and compared as plaintext. A value with a TTL is always rewritten, since `write`
+    /// would drop its expiry.
+    pub fn write_if_changed(&self, key: &str, value: &str) -> Result<bool, StorageError> {
+        check_reserved(key)?;
+        let stored = self
+            .db
+            .get(self.encode_key(key.as_bytes()))
+            .map_err(StorageError::ReadError)?;
+        if let Some(stored) = stored {
+            if self.unseal_value(key.as_bytes(), stored)? == value.as_bytes() {
+                return Ok(false);
+            }
+        }
+
+        self.write(key, value)?;
+        Ok(true)
+    }
+
+    pub fn transactional_write(
+        &self,
+        key: &str,
+        value: &str,
+        transaction_id: Uuid,
+    ) -> Result<(), StorageError> {
+        self.write_bytes(key, value.as_bytes().to_vec(), Some(transaction_id))
+    }
+
+    /// Writes `value` so that it expires after `ttl`. Expiry is checked whenever a value is read:
+    /// `read`, `get` and the iterators returning values skip expired keys, and `read` also deletes
+    /// them. Methods that only look at keys, such as `has_key`, `keys` and `count`, still see an
+    /// expired key until it is read or removed by `purge_expired`.
+    pub fn write_with_ttl(
+        &self,
+        key: &str,
+        value: &str,
+        ttl: Duration,
+        transaction_id: Option<Uuid>,
+    ) -> Result<(), StorageError> {
+        let expires_at = unix_millis(SystemTime::now() + ttl);
+        let mut data = Vec::with_capacity(EXPIRY_HEADER_LEN + value.len());
+        data.push(EXPIRY_MARKER);
+        data.extend_from_slice(&expires_at.to_be_bytes());
+        data.extend_from_slice(value.as_bytes());
+        self.write_bytes(key, data, transaction_id)
+    }
+
+    /// Hands `operand` to the merge operator set in the `StorageConfig`, which RocksDB applies to
+    /// the value of `key` when it is read or compacted, without reading it here. Fails with
+    /// `StorageError::InvalidConfig` when no merge operator is set, and with `SerializationError`
+    /// when the operator expects JSON and `operand` is not. Values written with a TTL cannot be
+    /// merged into.
+    pub fn merge(
+        &self,
+        key: &str,
+        operand: &str,
+        transaction_id: Option<Uuid>,
+    ) -> Result<(), StorageError> {
+        self.check_writable()?;
+        self.check_key(key)?;
+        let Some(merge_operator) = self.merge_operator else {
+            return Err(StorageError::InvalidConfig(
+                "no merge_operator is set".to_string(),
+            ));
+        };
+        let operand = merge_operator::encode_operand(merge_operator, operand)?;
+
+        self.with_transaction(transaction_id, |tx| {
+            tx.merge(self.encode_key(key.as_bytes()), operand)
+                .map_err(|error| conflict_or(error, StorageError::WriteError))
+        })?;
+        self.invalidate_cached(key, transaction_id);
+        self.record(Operation::Write);
+        Ok(())
+    }
+
+    /// Deletes every key written with `write_with_ttl` whose TTL has passed, in a single
+    /// transaction. Each value has to be decrypted to find its expiry. Returns how many keys were
+    /// deleted.
+    pub fn purge_expired(&self) -> Result<usize, StorageError> {
+        self.check_writable()?;
+        let now = unix_millis(SystemTime::now());
+        let tx = self.transaction();
+        let mut purged = 0;
+
+        for entry in user_entries(self.db.iterator(rocksdb::IteratorMode::Start)) {
+            let (k, v) = entry?;
+            let key = self.decode_key(&k)?;
+            let data = self.unseal_value(&key, v.into_vec())?;
+            if strip_expiry(data, now).is_none() {
+                tx.delete(&k).map_err(StorageError::WriteError)?;
+                purged += 1;
+            }
+        }
+
+        tx.commit().map_err(StorageError::CommitError)?;
+        self.clear_cache();
+        Ok(purged)
+    }
+
+    fn write_bytes(
+        &self,
+        key: &str,
+        mut data: Vec<u8>,
+        transaction_id: Option<Uuid>,
+    ) -> Result<(), StorageError> {
+        self.check_writable()?;
+        self.check_key(key)?;
+        data = self.seal_value(key.as_bytes(), data)?;
+
+        self.with_transaction(transaction_id, |tx| {
+            tx.put(self.encode_key(key.as_bytes()), data)
+                .map_err(|error| conflict_or(error, StorageError::WriteError))
+        })?;
+        self.invalidate_cached(key, transaction_id);
+        self.record(Operation::Write);
+        Ok(())
+    }
+
+    /// Moves the value stored under `from` to `to`, replacing any value already under `to`.
+    /// Without a transaction id the move is committed on its own. Values are bound to their key,
+    /// so with a password set the value is decrypted and encrypted again for `to`.
+    pub fn rename(
+        &self,
+        from: &str,
+        to: &str,
+        transaction_id: Option<Uuid>,
+    ) -> Result<(), StorageError> {
+        self.copy_entry(from, to, true, transaction_id)
+    }
+
+    /// Applies every write in `ops`, in order, in a single transaction. If any of them fails the
+    /// transaction is rolled back, so either all of them take effect or none does.
+    pub fn apply(&self, ops: Vec<WriteOp>) -> Result<(), StorageError> {
+        self.check_writable()?;
+        let transaction_id = self.begin_transaction();
+        let result = ops.into_iter().try_for_each(|op| match op {
+            WriteOp::Put { key, value } => self.transactional_write(&key, &value, transaction_id),
+            WriteOp::Delete { key } => self.transactional_delete(&key, transaction_id),
+        });
+        match result {
+            Ok(()) => self.commit_transaction(transaction_id),
+            Err(error) => {
+                // The failed write is the error worth reporting, not a failed rollback.
+                let _ = self.rollback_transaction(transaction_id);
+                Err(error)
+            }
+        }
     }
 
-    pub fn commit_transaction(&self, transaction_id: Uuid) -> Result<(), StorageError> {
-        let mut map = self.transactions.borrow_mut();
-        let tx = map
-            .remove(&transaction_id)
-            .ok_or(StorageError::NotFound("Transaction".to_string()))?;
-        tx.commit().map_err(|_| StorageError::CommitError)?;
+    /// Renames every key for which `f` returns a new name, to move data to a new key naming
+    /// scheme. Keys for which `f` returns `None` are left as they are. The keys are moved with
+    /// `rename` in transactions of 1000 keys, so an interrupted migration keeps the batches it
+    /// committed and can be run again if `f` returns `None` for the new names. A new name must
+    /// not be taken by another key. Returns the number of keys moved.
+    pub fn migrate<F>(&self, f: F) -> Result<usize, StorageError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        self.check_writable()?;
+        let mut transaction_id = self.begin_transaction();
+        match self.migrate_keys(&f, &mut transaction_id) {
+            Ok(moved) => {
+                self.commit_transaction(transaction_id)?;
+                Ok(moved)
+            }
+            Err(error) => {
+                // The transaction is already gone if an intermediate commit failed.
+                let _ = self.rollback_transaction(transaction_id);
+                Err(error)
+            }
+        }
+    }
+
+    fn migrate_keys<F>(&self, f: &F, transaction_id: &mut Uuid) -> Result<usize, StorageError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        // The snapshot keeps the keys already moved from being visited again under their new name.
+        let snapshot = self.db.snapshot();
+        let mut moved = 0;
+        for entry in user_entries(snapshot.iterator(rocksdb::IteratorMode::Start)) {
+            let (stored_key, _) = entry?;
+            let key = self.decode_key_string(&stored_key)?;
+            let Some(new_key) = f(&key).filter(|new_key| *new_key != key) else {
+                continue;
+            };
+
+            let taken = self.with_transaction(Some(*transaction_id), |tx| {
+                tx.get(self.encode_key(new_key.as_bytes()))
+                    .map(|value| value.is_some())
+                    .map_err(StorageError::ReadError)
+            })?;
+            if taken {
+                return Err(StorageError::InvalidKey(format!(
+                    "cannot migrate {} to {}, which already exists",
+                    key, new_key
+                )));
+            }
+            self.rename(&key, &new_key, Some(*transaction_id))?;
+
+            moved += 1;
+            if moved % MIGRATION_BATCH_SIZE == 0 {
+                self.commit_transaction(*transaction_id)?;
+                *transaction_id = self.begin_transaction();
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Same as `rename`, keeping the value under `from` as well.
+    pub fn copy(
+        &self,
+        from: &str,
+        to: &str,
+        transaction_id: Option<Uuid>,
+    ) -> Result<(), StorageError> {
+        self.copy_entry(from, to, false, transaction_id)
+    }
+
+    fn copy_entry(
+        &self,
+        from: &str,
+        to: &str,
+        delete_from: bool,
+        transaction_id: Option<Uuid>,
+    ) -> Result<(), StorageError> {
+        self.check_writable()?;
+        check_reserved(from)?;
+        self.check_key(to)?;
+        self.with_transaction(transaction_id, |tx| {
+            let stored_from = self.encode_key(from.as_bytes());
+            let value = tx
+                .get(&stored_from)
+                .map_err(StorageError::ReadError)?
+                .ok_or_else(|| StorageError::NotFound {
+                    kind: "Key",
+                    key: from.to_string(),
+                })?;
+            let value = if self.password.is_some() {
+                let data = Zeroizing::new(self.decrypt_data(from.as_bytes(), value)?);
+                self.encrypt_data(to.as_bytes(), data.to_vec())?
+            } else {
+                value
+            };
+
+            if delete_from {
+                tx.delete(&stored_from)
+                    .map_err(|error| conflict_or(error, StorageError::WriteError))?;
+            }
+            tx.put(self.encode_key(to.as_bytes()), value)
+                .map_err(|error| conflict_or(error, StorageError::WriteError))
+        })?;
+
+        if delete_from {
+            self.invalidate_cached(from, transaction_id);
+        }
+        self.invalidate_cached(to, transaction_id);
+        Ok(())
+    }
+
+    /// Reads `key` with a lock held until the transaction ends, and stores the value returned by
+    /// `modify` if there is one. Returns the value the key ends up with.
+    pub(crate) fn read_modify_write<F>(
+        &self,
+        key: &str,
+        transaction_id: Option<Uuid>,
+        modify: F,
+    ) -> Result<Option<Vec<u8>>, StorageError>
+    where
+        F: FnOnce(Option<&[u8]>) -> Result<Option<Vec<u8>>, StorageError>,
+    {
+        self.check_writable()?;
+        self.check_key(key)?;
+        self.with_transaction(transaction_id, |tx| {
+            let stored_key = self.encode_key(key.as_bytes());
+            let current = match tx
+                .get_for_update(&stored_key, true)
+                .map_err(|error| conflict_or(error, StorageError::ReadError))?
+            {
+                Some(data) => self.open_value(key.as_bytes(), data)?,
+                None => None,
+            };
+
+            match modify(current.as_deref())? {
+                Some(value) => {
+                    let data = self.seal_value(key.as_bytes(), value.clone())?;
+                    tx.put(&stored_key, data)
+                        .map_err(|error| conflict_or(error, StorageError::WriteError))?;
+                    self.invalidate_cached(key, transaction_id);
+                    Ok(Some(value))
+                }
+                None => Ok(current),
+            }
+        })
+    }
+
+    /// Runs `f` inside the transaction `transaction_id`, or inside a transaction of its own that
+    /// is committed if `f` succeeds.
+    fn with_transaction<T, F>(&self, transaction_id: Option<Uuid>, f: F) -> Result<T, StorageError>
+    where
+        F: FnOnce(&rocksdb::Transaction<'_, TransactionDB>) -> Result<T, StorageError>,
+    {
+        match transaction_id {
+            Some(transaction_id) => {
+                let map = self.transactions.borrow();
+                let tx = map
+                    .get(&transaction_id)
+                    .map(|open| &open.transaction)
+                    .ok_or_else(|| StorageError::NotFound {
+                        kind: "Transaction",
+                        key: transaction_id.to_string(),
+                    })?;
+                f(tx)
+            }
+            None => {
+                let tx = self.transaction();
+                let result = f(&tx)?;
+                tx.commit()
+                    .map_err(|error| conflict_or(error, StorageError::CommitError))?;
+                Ok(result)
+            }
+        }
+    }
+
+    /// Returns the entry for `key`, to read and update it in one step like
+    /// `HashMap::entry`.
+    pub fn entry(&self, key: &str, transaction_id: Option<Uuid>) -> Entry<'_, C> {
+        Entry::new(self, key, transaction_id)
+    }
+
+    /// Same as `entry`, storing the value with the codec `C` like `KeyValueStore::set`.
+    pub fn typed_entry<V>(&self, key: &str, transaction_id: Option<Uuid>) -> TypedEntry<'_, V, C>
+    where
+        V: Serialize + DeserializeOwned,
+    {
+        TypedEntry::new(self.entry(key, transaction_id))
+    }
+
+    pub fn read(&self, key: &str) -> Result<Option<String>, StorageError> {
+        self.read_bytes(key)?
+            .map(|data| String::from_utf8(data).map_err(|_| StorageError::ConversionError))
+            .transpose()
+    }
+
+    /// Returns a reader over the value of `key`, for large values the caller processes
+    /// incrementally instead of as a `String`. Values are encrypted as a single block, so the value
+    /// is still decrypted in full before the reader is returned.
+    pub fn read_stream(&self, key: &str) -> Result<Option<impl Read>, StorageError> {
+        Ok(self.read_bytes(key)?.map(Cursor::new))
+    }
+
+    fn read_bytes(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        check_reserved(key)?;
+        self.record(Operation::Read);
+        if let Some(cache) = &self.cache {
+            if let Some(value) = cache.borrow_mut().get(key) {
+                self.record(Operation::CacheHit);
+                return Ok(Some(value));
+            }
+        }
+
+        let Some(data) = self
+            .db
+            .get(self.encode_key(key.as_bytes()))
+            .map_err(StorageError::ReadError)?
+        else {
+            return Ok(None);
+        };
+        let data = self.unseal_value(key.as_bytes(), data)?;
+
+        // Values with a TTL are not cached, so the cache cannot keep them past their expiry.
+        let cacheable = !has_expiry(&data);
+        let value = strip_expiry(data, unix_millis(SystemTime::now()));
+        match (&value, &self.cache) {
+            (None, _) if !self.read_only => {
+                // Best effort: the key may be locked by an open transaction, in which case it is
+                // left for `purge_expired`.
+                let _ = self.delete(key);
+            }
+            (Some(value), Some(cache)) if cacheable => {
+                cache.borrow_mut().insert(key, value.clone());
+            }
+            _ => {}
+        }
+        Ok(value)
+    }
+
+    /// Decrypts a stored value and removes its expiry header. Returns `None` if it has expired.
+    fn open_value(&self, key: &[u8], data: Vec<u8>) -> Result<Option<Vec<u8>>, StorageError> {
+        let data = self.unseal_value(key, data)?;
+        Ok(strip_expiry(data, unix_millis(SystemTime::now())))
+    }
+
+    /// Compresses a value with the configured compression, then encrypts it if a password is set.
+    fn seal_value(&self, key: &[u8], data: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+        self.check_value_size(&data)?;
+        let data = value_compression::compress(self.compression, data)?;
+        if self.password.is_some() {
+            return self.encrypt_data(key, data);
+        }
+        Ok(data)
+    }
+
+    /// Reverses `seal_value`, leaving the expiry header in place.
+    fn unseal_value(&self, key: &[u8], mut data: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+        if self.password.is_some() {
+            data = self.decrypt_data(key, data)?;
+        }
+        value_compression::decompress(data)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let iter = user_entries(self.db.iterator(rocksdb::IteratorMode::Start));
+        let is_empty = iter.peekable().peek().is_none();
+        is_empty
+    }
+
+    pub fn keys(&self) -> Result<Vec<String>, StorageError> {
+        self.keys_in_range(None, None, false)
+    }
+
+    /// Returns the keys from `start`, included, to `end`, excluded, in ascending order, or in
+    /// descending order when `reverse` is set. A bound left as `None` is open. Encrypted keys are
+    /// not stored in plaintext order, so they take a scan of the whole storage and are sorted
+    /// afterwards.
+    pub fn keys_in_range(
+        &self,
+        start: Option<&str>,
+        end: Option<&str>,
+        reverse: bool,
+    ) -> Result<Vec<String>, StorageError> {
+        let ordered = self.key_cipher.is_none();
+        let mode = match (start, end) {
+            (Some(start), _) if ordered && !reverse => {
+                rocksdb::IteratorMode::From(start.as_bytes(), rocksdb::Direction::Forward)
+            }
+            (_, Some(end)) if ordered && reverse => {
+                rocksdb::IteratorMode::From(end.as_bytes(), rocksdb::Direction::Reverse)
+            }
+            _ if ordered && reverse => rocksdb::IteratorMode::End,
+            _ => rocksdb::IteratorMode::Start,
+        };
+        let mut read_options = rocksdb::ReadOptions::default();
+        if self.prefix_length.is_some() {
+            read_options.set_total_order_seek(true);
+        }
+
+        let mut keys = Vec::new();
+        for entry in user_entries(self.db.iterator_opt(mode, read_options)) {
+            let (k, _) = entry?;
+            let key = self.decode_listed_key(&k)?;
+            let before_start = start.is_some_and(|start| key.as_str() < start);
+            let past_end = end.is_some_and(|end| key.as_str() >= end);
+            // Plaintext keys are sorted, so the first key out of the range ends the scan.
+            if ordered && (if reverse { before_start } else { past_end }) {
+                break;
+            }
+            if !before_start && !past_end {
+                keys.push(key);
+            }
+        }
+
+        if !ordered {
+            keys.sort_unstable();
+            if reverse {
+                keys.reverse();
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Returns every key in plaintext order. Encrypted keys are not stored in plaintext order, so
+    /// they are sorted after the scan.
+    pub fn sorted_keys(&self) -> Result<Vec<String>, StorageError> {
+        let mut keys = user_entries(self.db.iterator(rocksdb::IteratorMode::Start))
+            .map(|entry| {
+                let (k, _) = entry?;
+                self.decode_key_string(&k)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if self.key_cipher.is_some() {
+            keys.sort_unstable();
+        }
+        Ok(keys)
+    }
+
+    /// Yields every key lazily, in the order RocksDB stores them.
+    pub fn iter_keys(&self) -> impl Iterator<Item = Result<String, StorageError>> + '_ {
+        user_entries(self.db.iterator(rocksdb::IteratorMode::Start)).map(move |entry| {
+            let (k, _) = entry?;
+            self.decode_key_string(&k)
+        })
+    }
+
+    /// Returns the smallest key. Encrypted keys are not stored in plaintext order, so finding it
+    /// takes a scan of the whole storage.
+    pub fn first_key(&self) -> Result<Option<String>, StorageError> {
+        self.edge_key(false)
+    }
+
+    /// Same as `first_key`, returning the largest key.
+    pub fn last_key(&self) -> Result<Option<String>, StorageError> {
+        self.edge_key(true)
+    }
+
+    fn edge_key(&self, last: bool) -> Result<Option<String>, StorageError> {
+        let mode = if last {
+            rocksdb::IteratorMode::End
+        } else {
+            rocksdb::IteratorMode::Start
+        };
+        let mut keys = user_entries(self.db.iterator(mode)).map(|entry| {
+            let (k, _) = entry?;
+            self.decode_key_string(&k)
+        });
+
+        if self.key_cipher.is_none() {
+            return keys.next().transpose();
+        }
+
+        let mut edge: Option<String> = None;
+        for key in keys {
+            let key = key?;
+            let replace = match &edge {
+                Some(edge) => (key > *edge) == last,
+                None => true,
+            };
+            if replace {
+                edge = Some(key);
+            }
+        }
+        Ok(edge)
+    }
+
+    pub fn partial_compare_keys(&self, key: &str) -> Result<Vec<String>, StorageError> {
+        let (mode, read_options) = self.prefix_scan(key);
+        self.filter_prefix_with(self.db.iterator_opt(mode, read_options), key, |k| {
+            self.decode_listed_key(k)
+        })
+        .map(|entry| entry.map(|(k, _)| k))
+        .collect()
+    }
+
+    pub fn partial_compare(&self, key: &str) -> Result<Vec<(String, String)>, StorageError> {
+        self.iter_prefix(key).collect()
+    }
+
+    /// Same as `partial_compare`, collected into a map ordered by key, also when keys are
+    /// encrypted.
+    pub fn partial_compare_map(
+        &self,
+        prefix: &str,
+    ) -> Result<BTreeMap<String, String>, StorageError> {
+        self.iter_prefix(prefix).collect()
+    }
+
+    /// Same as `partial_compare`, but an entry whose value cannot be decrypted, decompressed or
+    /// read as UTF-8 is skipped instead of failing the whole scan, to salvage the readable values
+    /// of a damaged storage. The keys that were skipped are returned along with the entries.
+    pub fn partial_compare_lenient(&self, prefix: &str) -> Result<SalvagedEntries, StorageError> {
+        let mut salvaged = SalvagedEntries::default();
+        for entry in self.prefix_entries(prefix) {
+            let (key, value) = entry?;
+            match self.decode_entry(key.clone(), value) {
+                Ok(Some(entry)) => salvaged.entries.push(entry),
+                Ok(None) => {}
+                Err(
+                    StorageError::FailedToDecryptData { .. }
+                    | StorageError::FailedToDecompressData
+                    | StorageError::ConversionError,
+                ) => salvaged.failed_keys.push(key),
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(salvaged)
+    }
+
+    /// Returns at most `limit` of the key-value pairs whose keys start with `prefix`, sorted by
+    /// key and, when `start_after` is given, only those after it. Passing the last key of a page
+    /// as `start_after` returns the next page. Encrypted keys are not stored in plaintext order,
+    /// so every page takes a scan of the whole storage.
+    pub fn partial_compare_limited(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>, StorageError> {
+        let (mode, read_options) = self.prefix_scan(prefix);
+        // Seeking to the cursor skips the earlier keys of the prefix. It shares the prefix, so it
+        // also shares the fixed-length prefix a prefix extractor seeks within.
+        let mode = match (mode, start_after) {
+            (rocksdb::IteratorMode::From(..), Some(cursor)) if cursor > prefix => {
+                rocksdb::IteratorMode::From(cursor.as_bytes(), rocksdb::Direction::Forward)
+            }
+            (mode, _) => mode,
+        };
+
+        let entries = self
+            .filter_prefix(self.db.iterator_opt(mode, read_options), prefix)
+            .filter(move |entry| match entry {
+                Ok((k, _)) => start_after.is_none_or(|cursor| k.as_str() > cursor),
+                Err(_) => true,
+            });
+        let decode = |entry: Result<(String, Vec<u8>), StorageError>| {
+            entry.and_then(|(k, v)| self.decode_entry(k, v)).transpose()
+        };
+
+        if self.key_cipher.is_none() {
+            return entries.filter_map(decode).take(limit).collect();
+        }
+        let mut entries = entries.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+            .into_iter()
+            .map(Ok)
+            .filter_map(decode)
+            .take(limit)
+            .collect()
+    }
+
+    /// Returns the key-value pairs whose keys are in `start..end`, sorted by key. Encrypted keys
+    /// are not stored in plaintext order, so finding them takes a scan of the whole storage.
+    pub fn range(&self, start: &str, end: &str) -> Result<Vec<(String, String)>, StorageError> {
+        let ordered = self.key_cipher.is_none();
+        let mode = if ordered {
+            rocksdb::IteratorMode::From(start.as_bytes(), rocksdb::Direction::Forward)
+        } else {
+            rocksdb::IteratorMode::Start
+        };
+        let mut read_options = rocksdb::ReadOptions::default();
+        if self.prefix_length.is_some() {
+            read_options.set_total_order_seek(true);
+        }
+
+        let mut pairs = Vec::new();
+        for entry in user_entries(self.db.iterator_opt(mode, read_options)) {
+            let (k, v) = entry?;
+            let key = self.decode_key_string(&k)?;
+            if key.as_str() >= end {
+                // Plaintext keys are sorted, so the first key past the range ends the scan.
+                if ordered {
+                    break;
+                }
+                continue;
+            }
+            if key.as_str() < start {
+                continue;
+            }
+            if let Some(pair) = self.decode_entry(key, v.into_vec())? {
+                pairs.push(pair);
+            }
+        }
+
+        if !ordered {
+            pairs.sort();
+        }
+        Ok(pairs)
+    }
+
+    /// Deletes every key starting with `prefix` in a single transaction, leaving the internal
+    /// entries alone. Returns how many keys were deleted.
+    pub fn delete_prefix(&self, prefix: &str) -> Result<usize, StorageError> {
+        self.check_writable()?;
+        self.with_transaction(None, |tx| {
+            let mut deleted = 0;
+            for entry in self.prefix_entries(prefix) {
+                let (key, _) = entry?;
+                tx.delete(self.encode_key(key.as_bytes()))
+                    .map_err(|error| conflict_or(error, StorageError::WriteError))?;
+                deleted += 1;
+            }
+            Ok(deleted)
+        })
+        .inspect(|_| self.clear_cache())
+    }
+
+    /// Yields the key-value pairs whose keys start with `prefix` lazily, decrypting each value as
+    /// it is reached.
+    pub fn iter_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = Result<(String, String), StorageError>> + 'a {
+        self.prefix_entries(prefix)
+            .filter_map(move |entry| entry.and_then(|(k, v)| self.decode_entry(k, v)).transpose())
+    }
+
+    /// Yields every key-value pair lazily, decrypting each value as it is reached.
+    pub fn entries(&self) -> impl Iterator<Item = Result<(String, String), StorageError>> + '_ {
+        user_entries(self.db.iterator(rocksdb::IteratorMode::Start)).filter_map(move |entry| {
+            let decode = || {
+                let (k, v) = entry?;
+                self.decode_entry(self.decode_key_string(&k)?, v.into_vec())
+            };
+            decode().transpose()
+        })
+    }
+
+    /// Yields every key and value exactly as RocksDB stores them, encrypted or compressed, along
+    /// with the internal DEK entries. Meant for inspecting an entry that fails to decrypt.
+    pub fn raw_entries(
+        &self,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), StorageError>> + '_ {
+        self.db.iterator(rocksdb::IteratorMode::Start).map(|entry| {
+            let (k, v) = entry.map_err(StorageError::ReadError)?;
+            Ok((k.into_vec(), v.into_vec()))
+        })
+    }
+
+    /// Writes every entry as a length-prefixed binary record holding its exact key and value
+    /// bytes, so values that are not UTF-8 survive, unlike with `export_csv` or the JSON dump.
+    /// Values are decrypted and decompressed, so the export can be imported into a storage with
+    /// another password or DEK; it must be protected like any plaintext copy. Values written with
+    /// a TTL keep their expiry. Returns the number of records written.
+    pub fn export_portable<W: Write>(&self, mut writer: W) -> Result<u64, StorageError> {
+        portable_io::write_header(&mut writer)?;
+        let mut exported = 0;
+        for entry in user_entries(self.db.iterator(rocksdb::IteratorMode::Start)) {
+            let (k, v) = entry?;
+            let key = self.decode_key(&k)?;
+            let value = self.unseal_value(&key, v.into_vec())?;
+            portable_io::write_record(&mut writer, &key, &value)?;
+            exported += 1;
+        }
+        writer.flush()?;
+        Ok(exported)
+    }
+
+    /// Writes the records of an `export_portable` output in a single write batch, compressed and
+    /// encrypted as this storage is configured. Existing keys are overwritten, and nothing is
+    /// written if the input is malformed. Returns the number of records imported.
+    pub fn import_portable<R: Read>(&self, reader: R) -> Result<u64, StorageError> {
+        self.check_writable()?;
+        let mut reader = PortableReader::new(BufReader::new(reader))?;
+        let mut batch = rocksdb::WriteBatchWithTransaction::<true>::default();
+        let mut imported = 0;
+        while let Some((key, value)) = reader.read_record()? {
+            // The source storage DEK must not replace ours.
+            if is_internal_key(&key) {
+                continue;
+            }
+            let value = self.seal_value(&key, value)?;
+            batch.put(self.encode_key(&key), value);
+            imported += 1;
+        }
+
+        self.db
+            .write_opt(batch, &self.write_options)
+            .map_err(StorageError::WriteError)?;
+        self.clear_cache();
+        Ok(imported)
+    }
+
+    /// Writes a `key,value` header followed by one row per entry, quoted as RFC 4180 requires, so
+    /// values containing commas or line breaks survive. Values are decrypted when a password is
+    /// set. Returns the number of rows written.
+    pub fn export_csv<W: Write>(&self, mut writer: W) -> Result<u64, StorageError> {
+        csv_io::write_record(&mut writer, &["key", "value"])?;
+        let mut exported = 0;
+        for entry in self.entries() {
+            let (key, value) = entry?;
+            csv_io::write_record(&mut writer, &[&key, &value])?;
+            exported += 1;
+        }
+        writer.flush()?;
+        Ok(exported)
+    }
+
+    /// Writes the rows of a CSV file laid out like the output of `export_csv` in a single
+    /// transaction. Nothing is written if any row is malformed. Returns the number of rows
+    /// imported.
+    pub fn import_csv<R: Read>(&self, reader: R) -> Result<u64, StorageError> {
+        self.check_writable()?;
+        let mut reader = CsvReader::new(BufReader::new(reader));
+        match reader.read_record()? {
+            Some(header) if header == ["key", "value"] => {}
+            Some(_) => {
+                return Err(StorageError::InvalidCsv {
+                    line: 1,
+                    reason: "expected a key,value header",
+                })
+            }
+            None => return Ok(0),
+        }
+
+        let transaction_id = self.begin_transaction();
+        let mut import = || {
+            let mut imported = 0;
+            while let Some(record) = reader.read_record()? {
+                let [key, value] = record.as_slice() else {
+                    return Err(StorageError::InvalidCsv {
+                        line: reader.line(),
+                        reason: "expected a key and a value",
+                    });
+                };
+                self.transactional_write(key, value, transaction_id)?;
+                imported += 1;
+            }
+            Ok(imported)
+        };
+
+        match import() {
+            Ok(imported) => {
+                self.commit_transaction(transaction_id)?;
+                Ok(imported)
+            }
+            Err(error) => {
+                self.rollback_transaction(transaction_id)?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Counts the keys. With `exact`, the whole store is iterated over, without decrypting
+    /// anything, and the internal DEK entries of an encrypted storage are not counted. Otherwise
+    /// the count is RocksDB's `rocksdb.estimate-num-keys`, read in constant time but only
+    /// approximate: overwritten and deleted keys can still be counted until they are compacted,
+    /// and so are the internal entries.
+    pub fn count(&self, exact: bool) -> Result<u64, StorageError> {
+        if !exact {
+            return Ok(self.property_int("rocksdb.estimate-num-keys")?.unwrap_or(0));
+        }
+        let mut count = 0;
+        for entry in user_entries(self.db.iterator(rocksdb::IteratorMode::Start)) {
+            entry?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Counts the keys starting with `prefix` without decrypting their values.
+    pub fn count_prefix(&self, prefix: &str) -> Result<u64, StorageError> {
+        let mut count = 0;
+        for entry in self.prefix_entries(prefix) {
+            entry?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Same as `entries`, yielding only the values.
+    pub fn values(&self) -> impl Iterator<Item = Result<String, StorageError>> + '_ {
+        self.entries().map(|entry| entry.map(|(_, v)| v))
+    }
+
+    /// Returns `None` for an expired entry.
+    fn decode_entry(
+        &self,
+        key: String,
+        value: Vec<u8>,
+    ) -> Result<Option<(String, String)>, StorageError> {
+        match self.open_value(key.as_bytes(), value)? {
+            Some(value) => {
+                let value = String::from_utf8(value).map_err(|_| StorageError::ConversionError)?;
+                Ok(Some((key, value)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn decode_key_string(&self, stored_key: &[u8]) -> Result<String, StorageError> {
+        String::from_utf8(self.decode_key(stored_key)?).map_err(|_| StorageError::ConversionError)
+    }
+
+    /// Same as `decode_key_string`, replacing invalid UTF-8 when `lossy_utf8` is set, for the
+    /// methods that only list keys.
+    fn decode_listed_key(&self, stored_key: &[u8]) -> Result<String, StorageError> {
+        if !self.lossy_utf8 {
+            return self.decode_key_string(stored_key);
+        }
+        Ok(String::from_utf8_lossy(&self.decode_key(stored_key)?).into_owned())
+    }
+
+    /// Yields the keys starting with `prefix` along with their stored values. Encrypted keys are
+    /// not in plaintext order, so finding them takes a scan of the whole storage.
+    fn prefix_entries<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = Result<(String, Vec<u8>), StorageError>> + 'a {
+        let (mode, read_options) = self.prefix_scan(prefix);
+        self.filter_prefix(self.db.iterator_opt(mode, read_options), prefix)
+    }
+
+    /// Picks where a scan for `prefix` starts and how it seeks.
+    fn prefix_scan<'a>(
+        &self,
+        prefix: &'a str,
+    ) -> (rocksdb::IteratorMode<'a>, rocksdb::ReadOptions) {
+        let ordered = self.key_cipher.is_none();
+        let mode = if ordered {
+            rocksdb::IteratorMode::From(prefix.as_bytes(), rocksdb::Direction::Forward)
+        } else {
+            rocksdb::IteratorMode::Start
+        };
+
+        // With a prefix extractor, seeks are only ordered within a single prefix, so shorter
+        // prefixes and encrypted keys have to fall back to a total order scan.
+        let mut read_options = rocksdb::ReadOptions::default();
+        match self.prefix_length {
+            Some(len) if ordered && prefix.len() >= len => {
+                read_options.set_prefix_same_as_start(true)
+            }
+            Some(_) => read_options.set_total_order_seek(true),
+            None => {}
+        }
+
+        (mode, read_options)
+    }
+
+    /// Decodes the keys yielded by `iter`, keeping the entries whose keys start with `prefix`.
+    fn filter_prefix<'a>(
+        &'a self,
+        iter: rocksdb::DBIteratorWithThreadMode<'a, TransactionDB>,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = Result<(String, Vec<u8>), StorageError>> + 'a {
+        self.filter_prefix_with(iter, prefix, |k| self.decode_key_string(k))
+    }
+
+    /// Same as `filter_prefix`, decoding keys with `decode_key`.
+    fn filter_prefix_with<'a>(
+        &'a self,
+        iter: rocksdb::DBIteratorWithThreadMode<'a, TransactionDB>,
+        prefix: &'a str,
+        decode_key: impl Fn(&[u8]) -> Result<String, StorageError> + 'a,
+    ) -> impl Iterator<Item = Result<(String, Vec<u8>), StorageError>> + 'a {
+        let ordered = self.key_cipher.is_none();
+        user_entries(iter)
+            .map(move |entry| {
+                let (k, v) = entry?;
+                Ok((decode_key(&k)?, v.into_vec()))
+            })
+            // Plaintext keys are sorted, so the first key past the prefix ends the scan.
+            .take_while(move |entry| match entry {
+                Ok((k, _)) => !ordered || k.starts_with(prefix),
+                Err(_) => true,
+            })
+            .filter(move |entry| match entry {
+                Ok((k, _)) => k.starts_with(prefix),
+                Err(_) => true,
+            })
+    }
+
+    /// Returns a view of the storage limited to the keys starting with `prefix`. The prefix is
+    /// used as is, so it usually ends with a separator such as `jobs/`.
+    pub fn scoped(&self, prefix: &str) -> ScopedStore<'_, C> {
+        ScopedStore::new(self, prefix)
+    }
+
+    /// Returns a frozen view of the storage. Reads through it ignore every write made after the
+    /// snapshot was taken.
+    pub fn snapshot(&self) -> StorageSnapshot<'_, C> {
+        // No write can run between the two calls, since the storage is used from one thread.
+        let sequence = self.current_sequence();
+        StorageSnapshot {
+            storage: self,
+            snapshot: self.db.snapshot(),
+            sequence,
+        }
+    }
+
+    /// Returns the sequence number of the last write committed to RocksDB.
+    pub fn current_sequence(&self) -> u64 {
+        // `TransactionDB` does not expose `latest_sequence_number`, but a snapshot records the
+        // latest sequence number when it is created.
+        unsafe {
+            let snapshot = self.db.create_snapshot();
+            let sequence = librocksdb_sys::rocksdb_snapshot_get_sequence_number(snapshot);
+            self.db.release_snapshot(snapshot);
+            sequence
+        }
+    }
+
+    pub fn has_key(&self, key: &str) -> Result<bool, StorageError> {
+        check_reserved(key)?;
+        let result = self
+            .db
+            .get(self.encode_key(key.as_bytes()))
+            .map_err(StorageError::ReadError)?;
+        Ok(result.is_some())
+    }
+
+    /// Same as `has_key` for many keys at once, fetched in a single RocksDB call. Values are not
+    /// decrypted.
+    pub fn multi_contains(&self, keys: &[&str]) -> Result<Vec<bool>, StorageError> {
+        for key in keys {
+            check_reserved(key)?;
+        }
+        let stored_keys = keys.iter().map(|key| self.encode_key(key.as_bytes()));
+        self.db
+            .multi_get_opt(stored_keys, &rocksdb::ReadOptions::default())
+            .into_iter()
+            .map(|result| Ok(result.map_err(StorageError::ReadError)?.is_some()))
+            .collect()
+    }
+
+    /// Reads many keys in a single RocksDB call and returns the values of those that exist,
+    /// decrypted. Missing and expired keys are left out.
+    pub fn get_existing(&self, keys: &[&str]) -> Result<HashMap<String, String>, StorageError> {
+        for key in keys {
+            check_reserved(key)?;
+        }
+        let stored_keys = keys.iter().map(|key| self.encode_key(key.as_bytes()));
+        let values = self
+            .db
+            .multi_get_opt(stored_keys, &rocksdb::ReadOptions::default());
+
+        let mut existing = HashMap::new();
+        for (key, value) in keys.iter().zip(values) {
+            self.record(Operation::Read);
+            let Some(value) = value.map_err(StorageError::ReadError)? else {
+                continue;
+            };
+            if let Some((key, value)) = self.decode_entry(key.to_string(), value)? {
+                existing.insert(key, value);
+            }
+        }
+        Ok(existing)
+    }
+
+    /// # Safety
+    /// This method uses `std::mem::transmute` to extend the transaction's lifetime to `'static`,
+    /// which is safe in this context because all transactions are stored in a `RefCell` within the `Storage` struct,
+    /// and are only accessed from the same thread.
+    /// Ensure that all transactions are properly committed or rolled back to avoid resource leaks.
+    pub fn begin_transaction(&self) -> Uuid {
+        let transaction = self.transaction();
+        let mut map = self.transactions.borrow_mut();
+        let id = Uuid::new_v4();
+        map.insert(
+            id,
+            OpenTransaction {
+                transaction: Box::new(unsafe {
+                    std::mem::transmute::<
+                        rocksdb::Transaction<'_, TransactionDB>,
+                        rocksdb::Transaction<'static, TransactionDB>,
+                    >(transaction)
+                }),
+                started_at: Instant::now(),
+            },
+        );
+        id
+    }
+
+    /// Ids of the transactions begun and not yet committed or rolled back, in no particular
+    /// order. A list that keeps growing points to transactions that are never ended.
+    pub fn active_transaction_ids(&self) -> Vec<Uuid> {
+        self.transactions.borrow().keys().copied().collect()
+    }
+
+    pub fn active_transaction_count(&self) -> usize {
+        self.transactions.borrow().len()
+    }
+
+    pub fn commit_transaction(&self, transaction_id: Uuid) -> Result<(), StorageError> {
+        let mut map = self.transactions.borrow_mut();
+        let tx = map
+            .remove(&transaction_id)
+            .ok_or_else(|| StorageError::NotFound {
+                kind: "Transaction",
+                key: transaction_id.to_string(),
+            })?;
+        let result = tx
+            .transaction
+            .commit()
+            .map_err(|error| conflict_or(error, StorageError::CommitError));
+        if result.is_ok() {
+            self.record(Operation::Commit);
+        }
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.borrow_mut();
+            match result {
+                Ok(()) => cache.commit(transaction_id),
+                Err(_) => cache.rollback(transaction_id),
+            }
+        }
+
+        result
+    }
+
+    pub fn rollback_transaction(&self, transaction_id: Uuid) -> Result<(), StorageError> {
+        let mut map = self.transactions.borrow_mut();
+        map.remove(&transaction_id)
+            .ok_or_else(|| StorageError::NotFound {
+                kind: "Transaction",
+                key: transaction_id.to_string(),
+            })?;
+        if let Some(cache) = &self.cache {
+            cache.borrow_mut().rollback(transaction_id);
+        }
+        self.record(Operation::Rollback);
+        Ok(())
+    }
+
+    /// Rolls back every transaction begun more than `older_than` ago, releasing its locks, and
+    /// returns how many were rolled back. Committing or rolling back a reaped transaction later
+    /// fails with `StorageError::NotFound`. Storage methods never run concurrently, so a
+    /// transaction is either reaped before a commit starts or already gone from the list.
+    pub fn rollback_stale_transactions(&self, older_than: Duration) -> usize {
+        let stale: Vec<Uuid> = self
+            .transactions
+            .borrow()
+            .iter()
+            .filter(|(_, open)| open.started_at.elapsed() > older_than)
+            .map(|(transaction_id, _)| *transaction_id)
+            .collect();
+
+        stale
+            .into_iter()
+            .filter(|transaction_id| self.rollback_transaction(*transaction_id).is_ok())
+            .count()
+    }
+
+    /// Whether values are encrypted, which is the case when the storage was opened with a
+    /// password.
+    pub fn is_encrypted(&self) -> bool {
+        self.password.is_some()
+    }
+
+    /// Returns the operation counters, all zero unless the storage was opened with
+    /// `StorageConfig::metrics` set. `StorageMetrics::render_prometheus` formats them for a
+    /// metrics endpoint.
+    pub fn metrics(&self) -> StorageMetrics {
+        self.metrics
+            .as_ref()
+            .map(Metrics::snapshot)
+            .unwrap_or_default()
+    }
+
+    fn record(&self, operation: Operation) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record(operation);
+        }
+    }
+
+    /// Runs `f` in a new transaction and commits it. When `f` or the commit fails with
+    /// `StorageError::Conflict`, the transaction is discarded and `f` runs again in a new one, up
+    /// to `attempts` runs in all. Any other error is returned right away, after rolling the
+    /// transaction back.
+    pub fn with_retry<F, T>(&self, attempts: usize, mut f: F) -> Result<T, StorageError>
+    where
+        F: FnMut(Uuid) -> Result<T, StorageError>,
+    {
+        let mut attempt = 1;
+        loop {
+            let transaction_id = self.begin_transaction();
+            let result = match f(transaction_id) {
+                Ok(value) => self.commit_transaction(transaction_id).map(|_| value),
+                Err(error) => {
+                    self.rollback_transaction(transaction_id)?;
+                    Err(error)
+                }
+            };
+
+            match result {
+                Err(StorageError::Conflict(_)) if attempt < attempts => attempt += 1,
+                result => return result,
+            }
+        }
+    }
+
+    /// Drops the cached value of `key` after it was written or deleted. Inside a transaction it
+    /// is dropped again on commit.
+    fn invalidate_cached(&self, key: &str, transaction_id: Option<Uuid>) {
+        if let Some(cache) = &self.cache {
+            cache.borrow_mut().invalidate(key, transaction_id);
+        }
+    }
+
+    fn clear_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache.borrow_mut().clear();
+        }
+    }
+
+    fn encode_key(&self, key: &[u8]) -> Vec<u8> {
+        match &self.key_cipher {
+            Some(key_cipher) => key_cipher.borrow().encrypt(key),
+            None => key.to_vec(),
+        }
+    }
+
+    fn decode_key(&self, stored_key: &[u8]) -> Result<Vec<u8>, StorageError> {
+        match &self.key_cipher {
+            // The wrapped DEKs are always stored under their plaintext keys.
+            Some(key_cipher) if !is_internal_key(stored_key) => key_cipher
+                .borrow()
+                .decrypt(stored_key)
+                .ok_or(StorageError::FailedToDecryptKey),
+            _ => Ok(stored_key.to_vec()),
+        }
+    }
+
+    fn encrypt_data(&self, key: &[u8], data: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+        encrypt_value(&self.password.as_ref().unwrap().borrow(), key, data)
+    }
+
+    fn decrypt_data(&self, key: &[u8], data: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+        decrypt_value(&self.password.as_ref().unwrap().borrow(), key, data)
+    }
+}
+
+impl<C: Codec> KeyValueStore for Storage<C> {
+    fn get<K, V>(&self, key: K) -> Result<Option<V>, StorageError>
+    where
+        K: AsRef<str>,
+        V: DeserializeOwned,
+    {
+        match self.read_bytes(key.as_ref())? {
+            Some(value) => Ok(Some(C::decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_tx<K, V>(&self, key: K, transaction_id: Uuid) -> Result<Option<V>, StorageError>
+    where
+        K: AsRef<str>,
+        V: DeserializeOwned,
+    {
+        match self.transactional_read_bytes(key.as_ref(), transaction_id)? {
+            Some(value) => Ok(Some(C::decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set<K, V>(&self, key: K, value: V, transaction_id: Option<Uuid>) -> Result<(), StorageError>
+    where
+        K: AsRef<str>,
+        V: Serialize,
+    {
+        self.write_bytes(key.as_ref(), C::encode(&value)?, transaction_id)
+    }
+
+    fn set_batch_lenient<K, V>(
+        &self,
+        items: &[(K, V)],
+    ) -> Result<Vec<Result<(), StorageError>>, StorageError>
+    where
+        K: AsRef<str>,
+        V: Serialize,
+    {
+        self.check_writable()?;
+        Ok(items
+            .iter()
+            .map(|(key, value)| self.set(key, value, None))
+            .collect())
+    }
+
+    fn delete<K: AsRef<str>>(
+        &self,
+        key: K,
+        transaction_id: Option<Uuid>,
+    ) -> Result<(), StorageError> {
+        match transaction_id {
+            Some(transaction_id) => self.transactional_delete(key.as_ref(), transaction_id),
+            None => self.delete(key.as_ref()),
+        }
+    }
+
+    fn update<K, V>(
+        &self,
+        id: K,
+        updates: &HashMap<&str, Value>,
+        transaction_id: Option<Uuid>,
+    ) -> Result<V, StorageError>
+    where
+        K: AsRef<str> + std::marker::Copy,
+        V: Serialize + DeserializeOwned + Clone,
+    {
+        check_json_codec::<C>("update")?;
+
+        // 1. Fetch the existing value from the database
+        let value: Option<V> = self.get(id)?;
+
+        if let Some(value) = value {
+            // 2. Convert the existing value into a JSON object
+            let mut json_value =
+                serde_json::to_value(&value).map_err(|_| StorageError::SerializationError)?;
+
+            // 3. Apply the updates
+            if let Some(json_object) = json_value.as_object_mut() {
+                for (key, update) in updates {
+                    json_object.insert(key.to_string(), update.clone());
+                }
+            } else {
+                return Err(StorageError::SerializationError);
+            }
+
+            // 4. Convert the updated JSON object back to V
+            let updated_value: V =
+                serde_json::from_value(json_value).map_err(|_| StorageError::SerializationError)?;
+
+            // 5. Save the updated value back to the database
+            self.set(id, updated_value.clone(), transaction_id)?;
+
+            Ok(updated_value)
+        } else {
+            Err(StorageError::NotFound {
+                kind: "Value",
+                key: id.as_ref().to_string(),
+            })
+        }
+    }
+
+    fn update_merge<K, V>(
+        &self,
+        id: K,
+        patch: &Value,
+        transaction_id: Option<Uuid>,
+    ) -> Result<V, StorageError>
+    where
+        K: AsRef<str> + std::marker::Copy,
+        V: Serialize + DeserializeOwned + Clone,
+    {
+        check_json_codec::<C>("update_merge")?;
+
+        let mut document: Value = self.get(id)?.ok_or_else(|| StorageError::NotFound {
+            kind: "Value",
+            key: id.as_ref().to_string(),
+        })?;
+        merge_patch(&mut document, patch);
+
+        let updated_value: V =
+            serde_json::from_value(document).map_err(|_| StorageError::SerializationError)?;
+        self.set(id, updated_value.clone(), transaction_id)?;
+
+        Ok(updated_value)
+    }
+}
+
+fn check_json_codec<C: Codec>(operation: &'static str) -> Result<(), StorageError> {
+    if C::NAME != JsonCodec::NAME {
+        return Err(StorageError::UnsupportedByCodec {
+            operation,
+            codec: C::NAME,
+        });
+    }
+    Ok(())
+}
+
+/// RFC 7386: objects are merged key by key, a `null` removes the key and anything else replaces
+/// the target.
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let Some(patch) = patch.as_object() else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(serde_json::Map::new());
+    }
+    if let Some(target) = target.as_object_mut() {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge_patch(target.entry(key.as_str()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+/// Encrypts `data` together with a hash of `key`, so the value can only be decrypted under the key
+/// it was written to.
+fn encrypt_value(dek: &[u8], key: &[u8], data: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+    let mut plaintext = Vec::with_capacity(1 + KEY_HASH_LEN + data.len());
+    plaintext.push(KEY_BOUND_MARKER);
+    plaintext.extend_from_slice(&Sha256::digest(key));
+    plaintext.extend_from_slice(&data);
+
+    let mut entry_cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+    let mut cocoon = Cocoon::new(dek);
+    cocoon
+        .dump(plaintext, &mut entry_cursor)
+        .map_err(|error| StorageError::FailedToEncryptData { error })?;
+    Ok(entry_cursor.into_inner())
+}
+
+fn decrypt_value(dek: &[u8], key: &[u8], data: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+    let mut entry_cursor = Cursor::new(data);
+
+    let cocoon = Cocoon::new(dek);
+    let mut plaintext = cocoon
+        .parse(&mut entry_cursor)
+        .map_err(|error| StorageError::FailedToDecryptData { error })?;
+
+    // A value written before values were bound to their key is the plaintext itself. It is
+    // rewritten bound by its next write, a `migrate` that moves it or `rotate_dek`.
+    if plaintext.first() != Some(&KEY_BOUND_MARKER) {
+        return Ok(plaintext);
+    }
+    // A value copied over from another key carries that key's hash.
+    let header_len = 1 + KEY_HASH_LEN;
+    if plaintext.len() < header_len || plaintext[1..header_len] != Sha256::digest(key)[..] {
+        return Err(StorageError::ValueKeyMismatch);
+    }
+    plaintext.drain(..header_len);
+
+    Ok(plaintext)
+}
+
+/// Returns the total size of the files under `path`, including its subdirectories.
+fn dir_size(path: &Path) -> Result<u64, StorageError> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Removes the header `write_with_ttl` puts in front of a value. Returns `None` if the value
+/// expired at or before `now`.
+fn strip_expiry(mut data: Vec<u8>, now: u64) -> Option<Vec<u8>> {
+    if !has_expiry(&data) {
+        return Some(data);
+    }
+
+    let mut expires_at = [0u8; 8];
+    expires_at.copy_from_slice(&data[1..EXPIRY_HEADER_LEN]);
+    if u64::from_be_bytes(expires_at) <= now {
+        return None;
+    }
+    data.drain(..EXPIRY_HEADER_LEN);
+    Some(data)
+}
+
+fn has_expiry(data: &[u8]) -> bool {
+    data.len() >= EXPIRY_HEADER_LEN && data[0] == EXPIRY_MARKER
+}
+
+/// Returns `StorageError::Conflict` for the RocksDB errors a retry can get past: a lock wait that
+/// timed out, a deadlock or a write conflict. Any other error is wrapped with `other`.
+fn conflict_or(error: rocksdb::Error, other: fn(rocksdb::Error) -> StorageError) -> StorageError {
+    match error.kind() {
+        rocksdb::ErrorKind::Busy | rocksdb::ErrorKind::TimedOut | rocksdb::ErrorKind::TryAgain => {
+            StorageError::Conflict(error)
+        }
+        _ => other(error),
+    }
+}
+
+/// Returns `StorageError::Locked` when the storage could not be opened because another process
+/// holds its RocksDB `LOCK` file, which RocksDB reports as an IO error.
+fn open_error(error: rocksdb::Error) -> StorageError {
+    if error.kind() == rocksdb::ErrorKind::IOError && error.as_ref().contains("lock") {
+        StorageError::Locked(error)
+    } else {
+        StorageError::CreationError(error)
+    }
+}
+
+/// Rejects keys in the namespace of the internal keys.
+fn check_reserved(key: &str) -> Result<(), StorageError> {
+    if key.starts_with(RESERVED_PREFIX) {
+        return Err(StorageError::ReservedKey(key.to_string()));
+    }
+    Ok(())
+}
+
+/// Returns the wrapped DEK of a storage created before the DEK moved under `RESERVED_PREFIX`.
+fn legacy_dek(db: &TransactionDB) -> Result<Option<Vec<u8>>, StorageError> {
+    let dek = db.get(LEGACY_DEK_KEY).map_err(StorageError::ReadError)?;
+    Ok(dek.filter(|dek| dek.starts_with(&COCOON_MAGIC)))
+}
+
+/// Moves the DEK, and the replacement DEK of an interrupted rotation, to their reserved keys.
+/// A read-only storage cannot be migrated.
+fn migrate_legacy_dek(db: &TransactionDB, read_only: bool) -> Result<(), StorageError> {
+    if db.get(DEK_KEY).map_err(StorageError::ReadError)?.is_some() || legacy_dek(db)?.is_none() {
+        return Ok(());
+    }
+    if read_only {
+        return Err(StorageError::ReadOnly);
+    }
+
+    let tx = db.transaction();
+    for (legacy_key, key) in [
+        (LEGACY_DEK_KEY, DEK_KEY),
+        (LEGACY_DEK_ROTATION_KEY, DEK_ROTATION_KEY),
+    ] {
+        if let Some(dek) = tx.get(legacy_key).map_err(StorageError::ReadError)? {
+            tx.put(key, dek).map_err(StorageError::WriteError)?;
+            tx.delete(legacy_key).map_err(StorageError::WriteError)?;
+        }
+    }
+    tx.commit().map_err(StorageError::CommitError)
+}
+
+/// Parses the backup id and the number of entries restored from a restore checkpoint.
+fn parse_restore_checkpoint(checkpoint: &[u8]) -> Option<(u64, u64)> {
+    let checkpoint = String::from_utf8_lossy(checkpoint);
+    let (backup_id, restored) = checkpoint.split_once(':')?;
+    Some((backup_id.parse().ok()?, restored.parse().ok()?))
+}
+
+/// Moves the checkpoint of a restore interrupted before it moved under `RESERVED_PREFIX` to its
+/// reserved key. A user value under the legacy key is left alone, since it does not parse as a
+/// checkpoint: encrypted values are ciphertext, and plaintext ones are rarely two numbers.
+fn migrate_legacy_restore_checkpoint(db: &TransactionDB) -> Result<(), StorageError> {
+    let Some(checkpoint) = db
+        .get(LEGACY_RESTORE_CHECKPOINT_KEY)
+        .map_err(StorageError::ReadError)?
+    else {
+        return Ok(());
+    };
+    if parse_restore_checkpoint(&checkpoint).is_none() {
+        return Ok(());
+    }
+
+    let tx = db.transaction();
+    tx.put(RESTORE_CHECKPOINT_KEY, checkpoint)
+        .map_err(StorageError::WriteError)?;
+    tx.delete(LEGACY_RESTORE_CHECKPOINT_KEY)
+        .map_err(StorageError::WriteError)?;
+    tx.commit().map_err(StorageError::CommitError)
+}
+
+/// A key and value as RocksDB stores them.
+type StoredEntry = (Box<[u8]>, Box<[u8]>);
+
+/// Yields the entries of `iter` as they are stored, leaving out the internal keys. Every scan over
+/// the entries of the user goes through it.
+fn user_entries<'a>(
+    iter: rocksdb::DBIteratorWithThreadMode<'a, TransactionDB>,
+) -> impl Iterator<Item = Result<StoredEntry, StorageError>> + 'a {
+    iter.map(|entry| entry.map_err(StorageError::ReadError))
+        .filter(|entry| !matches!(entry, Ok((k, _)) if is_internal_key(k)))
+}
+
+/// Keys the storage writes for itself, unencrypted and outside the key cipher.
+fn is_internal_key(key: &[u8]) -> bool {
+    key == DEK_KEY.as_bytes()
+        || key == DEK_ROTATION_KEY.as_bytes()
+        || key == RESTORE_CHECKPOINT_KEY.as_bytes()
+}
+
+fn password_mac(dek: &[u8], password: &Secret<String>) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(dek).expect("HMAC accepts keys of any length");
+    mac.update(password.expose_secret().as_bytes());
+    mac
+}
+
+fn password_tag(dek: &[u8], password: &Secret<String>) -> [u8; 32] {
+    password_mac(dek, password).finalize().into_bytes().into()
+}
+
+/// Encrypts a DEK with `password`, with age scrypt when `kdf_params` sets a DEK work factor and
+/// with Cocoon otherwise.
+fn wrap_dek(
+    dek: &[u8],
+    password: &Secret<String>,
+    kdf_params: &KdfParams,
+) -> Result<Vec<u8>, StorageError> {
+    if let Some(work_factor) = kdf_params.dek_work_factor {
+        check_work_factor(work_factor)?;
+        let mut recipient = scrypt::Recipient::new(dek_passphrase(password));
+        recipient.set_work_factor(work_factor);
+        return age::encrypt(&recipient, dek).map_err(StorageError::DekEncryptFailed);
+    }
+
+    let mut entry_cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+    let mut cocoon = Cocoon::new(password.expose_secret().as_bytes());
+    cocoon
+        .dump(dek.to_vec(), &mut entry_cursor)
+        .map_err(|error| StorageError::FailedToEncryptData { error })?;
+    Ok(entry_cursor.into_inner())
+}
+
+/// Decrypts a DEK written by `wrap_dek` in either format. `max_work_factor` overrides the highest
+/// scrypt work factor accepted for DEKs wrapped with age.
+fn unwrap_dek(
+    encrypted_dek: Vec<u8>,
+    password: &Secret<String>,
+    max_work_factor: Option<u8>,
+) -> Result<Zeroizing<Vec<u8>>, StorageError> {
+    if encrypted_dek.starts_with(AGE_MAGIC) {
+        let mut identity = scrypt::Identity::new(dek_passphrase(password));
+        if let Some(max_work_factor) = max_work_factor {
+            check_work_factor(max_work_factor)?;
+            identity.set_max_work_factor(max_work_factor);
+        }
+        return match age::decrypt(&identity, &encrypted_dek) {
+            Ok(dek) => Ok(Zeroizing::new(dek)),
+            Err(age::DecryptError::DecryptionFailed | age::DecryptError::NoMatchingKeys) => {
+                Err(StorageError::WrongPassword)
+            }
+            Err(error) => Err(StorageError::DekDecryptFailed(error)),
+        };
+    }
+
+    let mut entry_cursor = Cursor::new(encrypted_dek);
+
+    let cocoon = Cocoon::new(password.expose_secret().as_bytes());
+    cocoon
+        .parse(&mut entry_cursor)
+        .map(Zeroizing::new)
+        .map_err(|_| StorageError::WrongPassword)
+}
+
+/// Consistent view of a `Storage` at the time `Storage::snapshot` was called. Values are
+/// decrypted with the storage's current DEK.
+pub struct StorageSnapshot<'a, C = JsonCodec> {
+    storage: &'a Storage<C>,
+    snapshot: rocksdb::SnapshotWithThreadMode<'a, TransactionDB>,
+    sequence: u64,
+}
+
+impl<C: Codec> StorageSnapshot<'_, C> {
+    /// RocksDB sequence number the snapshot was taken at, so a report built from it can cite
+    /// the exact state it read.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn read(&self, key: &str) -> Result<Option<String>, StorageError> {
+        self.read_bytes(key)?
+            .map(|data| String::from_utf8(data).map_err(|_| StorageError::ConversionError))
+            .transpose()
+    }
+
+    fn read_bytes(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        check_reserved(key)?;
+        let storage = self.storage;
+        match self
+            .snapshot
+            .get(storage.encode_key(key.as_bytes()))
+            .map_err(StorageError::ReadError)?
+        {
+            Some(data) => storage.open_value(key.as_bytes(), data),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get<K, V>(&self, key: K) -> Result<Option<V>, StorageError>
+    where
+        K: AsRef<str>,
+        V: DeserializeOwned,
+    {
+        match self.read_bytes(key.as_ref())? {
+            Some(value) => Ok(Some(C::decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn keys(&self) -> Result<Vec<String>, StorageError> {
+        user_entries(self.snapshot.iterator(rocksdb::IteratorMode::Start))
+            .map(|entry| {
+                let (k, _) = entry?;
+                self.storage.decode_listed_key(&k)
+            })
+            .collect()
+    }
+
+    pub fn partial_compare(&self, key: &str) -> Result<Vec<(String, String)>, StorageError> {
+        let storage = self.storage;
+        let (mode, read_options) = storage.prefix_scan(key);
+        storage
+            .filter_prefix(self.snapshot.iterator_opt(mode, read_options), key)
+            .filter_map(|entry| {
+                entry
+                    .and_then(|(k, v)| storage.decode_entry(k, v))
+                    .transpose()
+            })
+            .collect()
+    }
+}
+
+/// Uses the given policy config, falling back to the one in the storage config and then to the
+/// default policy.
+pub(crate) fn password_policy(
+    config: &StorageConfig,
+    password_policy_config: Option<PasswordPolicyConfig>,
+) -> Result<PasswordPolicy, StorageError> {
+    match password_policy_config.or_else(|| config.password_policy.clone()) {
+        Some(config) => PasswordPolicy::try_new(config),
+        None => Ok(PasswordPolicy::default()),
+    }
+}
+
+fn dek_passphrase(password: &Secret<String>) -> SecretString {
+    SecretString::new(password.expose_secret().clone().into())
+}
+
+fn new_backup_id() -> Result<BackupId, StorageError> {
+    let mut backup_id = BackupId::default();
+    OsRng.try_fill_bytes(&mut backup_id)?;
+    Ok(backup_id)
+}
+
+/// Writes a DEK file, with the id of its backup in front of the encrypted DEK.
+fn write_dek_file<P: AsRef<Path>>(
+    dek_path: P,
+    backup_id: Option<&BackupId>,
+    encrypted_dek: &[u8],
+) -> Result<(), StorageError> {
+    let mut dek_file = create_private_file(dek_path)?;
+    if let Some(backup_id) = backup_id {
+        dek_file.write_all(&BACKUP_ID_MAGIC)?;
+        dek_file.write_all(backup_id)?;
+    }
+    dek_file.write_all(encrypted_dek)?;
+    Ok(())
+}
+
+/// Reads a DEK file, returning the id of its backup, which DEK files written before backup ids
+/// were added lack, and the encrypted DEK.
+fn read_dek_file<P: AsRef<Path>>(
+    dek_path: &P,
+) -> Result<(Option<BackupId>, Vec<u8>), StorageError> {
+    let (backup_id, mut dek_file) = read_backup_id(File::open(dek_path)?)?;
+    let mut encrypted_dek = Vec::new();
+    dek_file.read_to_end(&mut encrypted_dek)?;
+    Ok((backup_id, encrypted_dek))
+}
+
+fn verify_backup_entries<R: Read>(
+    mut backup_reader: BackupFileReader<R>,
+) -> Result<BackupStats, StorageError> {
+    let mut stats = BackupStats::default();
+    let format = backup_reader.read_format()?;
+    for_each_backup_entry(&mut backup_reader, format, |key, value| {
+        stats.entries += 1;
+        stats.bytes += (key.len() + value.len()) as u64;
+        Ok(())
+    })?;
+    stats.created_at = backup_reader.created_at();
+    stats.sequence = backup_reader.sequence();
+
+    Ok(stats)
+}
+
+/// Decrypts a backup stream whose `format` was already read and calls `on_entry` with every
+/// key-value pair. The entry count and checksum of backups with a manifest are checked once every
+/// entry has been read.
+fn for_each_backup_entry<R, F>(
+    backup_reader: &mut BackupFileReader<R>,
+    format: BackupFormat,
+    mut on_entry: F,
+) -> Result<(), StorageError>
+where
+    R: Read,
+    F: FnMut(&[u8], &[u8]) -> Result<(), StorageError>,
+{
+    // Backups written before the manifest was added may hold the source DEKs under their legacy
+    // keys, which must not be restored as values.
+    let mut on_entry = |key: &[u8], value: &[u8]| {
+        let legacy_dek =
+            key == LEGACY_DEK_KEY.as_bytes() || key == LEGACY_DEK_ROTATION_KEY.as_bytes();
+        if legacy_dek && format != BackupFormat::BinaryWithManifest {
+            return Ok(());
+        }
+        on_entry(key, value)
+    };
+    match format {
+        BackupFormat::Binary | BackupFormat::BinaryWithManifest => loop {
+            let record = backup_reader
+                .read_record()
+                .map_err(|error| match error.kind() {
+                    ErrorKind::UnexpectedEof => {
+                        StorageError::CorruptBackup("truncated record".to_string())
+                    }
+                    ErrorKind::InvalidData => StorageError::CorruptBackup(error.to_string()),
+                    _ => StorageError::IoError(error),
+                })?;
+            match record {
+                Some((key, value)) => on_entry(&key, &value)?,
+                None => break,
+            }
+        },
+        BackupFormat::LegacyHex => {
+            let mut buf = Vec::new();
+            while backup_reader.read_until(b';', &mut buf)? != 0 {
+                if buf.pop() != Some(b';') {
+                    return Err(StorageError::CorruptBackup("truncated record".to_string()));
+                }
+                let mut parts = buf.splitn(2, |&b| b == b',');
+                let (Some(key), Some(value)) = (parts.next(), parts.next()) else {
+                    return Err(StorageError::CorruptBackup(
+                        "record without a value".to_string(),
+                    ));
+                };
+                let key = hex::decode(key)
+                    .map_err(|_| StorageError::CorruptBackup("invalid hex key".to_string()))?;
+                let value = hex::decode(value)
+                    .map_err(|_| StorageError::CorruptBackup("invalid hex value".to_string()))?;
+                on_entry(&key, &value)?;
+                buf.clear();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn report_progress(progress: Option<&dyn Fn(u64)>, count: u64) {
+    if let Some(progress) = progress {
+        progress(count);
+    }
+}
+
+/// Creates the storage directory and its missing parents, since RocksDB only creates the last
+/// component of the path.
+fn create_storage_dir(path: &str) -> Result<(), StorageError> {
+    let invalid_path = |reason: String| StorageError::InvalidPath {
+        path: path.to_string(),
+        reason,
+    };
+    if Path::new(path).is_file() {
+        return Err(invalid_path("path is a file".to_string()));
+    }
+    fs::create_dir_all(path).map_err(|error| invalid_path(error.to_string()))
+}
+
+/// Encrypted stream of a backup opened by `open_backup`, after its backup id.
+type BackupStream = AfterBackupId<BufReader<Box<dyn Read>>>;
+
+/// Opens a backup file, or the parts written by `Storage::backup_split` when `path` is a
+/// directory, returning its backup id and the encrypted stream that follows it.
+fn open_backup<P: AsRef<Path>>(path: P) -> Result<(Option<BackupId>, BackupStream), StorageError> {
+    let path = path.as_ref();
+    let reader: Box<dyn Read> = if path.is_dir() {
+        Box::new(PartReader::open(path)?)
+    } else {
+        Box::new(File::open(path)?)
+    };
+    Ok(read_backup_id(BufReader::new(reader))?)
+}
+
+/// Opens a backup along with its DEK file, failing with `BackupDekMismatch` before either is
+/// decrypted if the DEK file was written for another backup. Files written before backup ids
+/// were added are not checked.
+fn open_backup_with_dek<P: AsRef<Path>>(
+    backup_path: &P,
+    dek_path: &P,
+    password: &Secret<String>,
+    max_work_factor: Option<u8>,
+) -> Result<(BackupStream, Zeroizing<Vec<u8>>), StorageError> {
+    let (backup_id, backup_file) = open_backup(backup_path)?;
+    let (dek_backup_id, encrypted_dek) = read_dek_file(dek_path)?;
+    if let (Some(backup_id), Some(dek_backup_id)) = (backup_id, dek_backup_id) {
+        if backup_id != dek_backup_id {
+            return Err(StorageError::BackupDekMismatch);
+        }
+    }
+    let dek = unwrap_dek(encrypted_dek, password, max_work_factor)?;
+    Ok((backup_file, dek))
+}
+
+fn create_options(config: &StorageConfig) -> rocksdb::Options {
+    let mut options = rocksdb::Options::default();
+    let mut block_options = rocksdb::BlockBasedOptions::default();
+    let mut custom_block_options = false;
+
+    if let Some(prefix_length) = config.prefix_length {
+        options.set_prefix_extractor(rocksdb::SliceTransform::create_fixed_prefix(prefix_length));
+        options.set_memtable_prefix_bloom_ratio(0.1);
+        block_options.set_bloom_filter(10.0, false);
+        custom_block_options = true;
+    }
+
+    if let Some(rocksdb_options) = &config.rocksdb {
+        if let Some(block_cache_mb) = rocksdb_options.block_cache_mb {
+            block_options.set_block_cache(&rocksdb::Cache::new_lru_cache(block_cache_mb * MIB));
+            custom_block_options = true;
+        }
+        if let Some(compression) = rocksdb_options.compression {
+            options.set_compression_type(match compression {
+                Compression::None => rocksdb::DBCompressionType::None,
+                Compression::Snappy => rocksdb::DBCompressionType::Snappy,
+                Compression::Lz4 => rocksdb::DBCompressionType::Lz4,
+                Compression::Zstd => rocksdb::DBCompressionType::Zstd,
+            });
+        }
+        if let Some(write_buffer_mb) = rocksdb_options.write_buffer_mb {
+            options.set_write_buffer_size(write_buffer_mb * MIB);
+        }
+    }
+
+    if custom_block_options {
+        options.set_block_based_table_factory(&block_options);
+    }
+
+    if let Some(merge_operator) = config.merge_operator {
+        merge_operator::register(&mut options, merge_operator);
+    }
+
+    options
+}
+
+fn write_options(config: &StorageConfig) -> rocksdb::WriteOptions {
+    let mut write_options = rocksdb::WriteOptions::default();
+    write_options.set_sync(config.sync_writes);
+    write_options.disable_wal(config.disable_wal);
+    write_options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_io::{BACKUP_FORMAT_VERSION, BACKUP_FORMAT_VERSION_WITHOUT_MANIFEST};
+    use crate::codec::CborCodec;
+    use crate::password_policy::{PasswordPolicy, PolicyViolation};
+    use crate::storage_config::{
+        CharacterSets, PasswordPolicyConfig, RocksDbOptions, DEFAULT_MAX_PASSWORD_LENGTH,
+    };
+    use rand::{rng, RngCore};
+    use redact::Secret;
+    use std::cell::Cell;
+    use std::env;
+
+    fn temp_storage() -> PathBuf {
+        let dir = env::temp_dir();
+        let mut rang = rng();
+        let index = rang.next_u32();
+        dir.join(format!("storage_{}.db", index))
+    }
+
+    fn temp_backup() -> (PathBuf, PathBuf) {
+        let dir = env::temp_dir();
+        let mut rang = rng();
+        let index = rang.next_u32();
+        (
+            dir.join(format!("backup_{}", index)),
+            dir.join(format!("dek_{}", index)),
+        )
+    }
+
+    fn create_path_and_storage(
+        is_encrypted: bool,
+    ) -> Result<(PathBuf, StorageConfig, Storage), StorageError> {
+        let path = &temp_storage();
+
+        let password = if is_encrypted {
+            Some("password".to_string())
+        } else {
+            None
+        };
+
+        let config = StorageConfig {
+            path: path.to_string_lossy().to_string(),
+            password: password.map(|p| Secret::from(p)),
+            kdf_params: KdfParams::default(),
+            encrypt_keys: false,
+            password_policy: None,
+            rocksdb: None,
+            prefix_length: None,
+            cache_capacity: None,
+            backup_batch_size: None,
+            metrics: false,
+            max_key_length: None,
+            max_value_bytes: None,
+            compression: ValueCompression::None,
+            sync_writes: false,
+            disable_wal: false,
+            merge_operator: None,
+            lossy_utf8: false,
+        };
+
+        let storage = Storage::new_with_policy(
+            &config,
+            Some(PasswordPolicyConfig {
+                min_length: 1,
+                min_number_of_special_chars: 0,
+                min_number_of_uppercase: 0,
+                min_number_of_digits: 0,
+                min_number_of_lowercase: 0,
+                max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+                character_sets: CharacterSets::default(),
+            }),
+        )?;
+
+        Ok((path.clone(), config, storage))
+    }
+
+    #[test]
+    fn test_new_storage_starts_empty() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        assert!(store.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_stream() -> Result<(), StorageError> {
+        for encrypted in [false, true] {
+            let (_, _, store) = create_path_and_storage(encrypted)?;
+            let value = "trace".repeat(100_000);
+            store.write("test1", &value)?;
+
+            let mut reader = store.read_stream("test1")?.expect("value was written");
+            let mut chunk = [0u8; 5];
+            reader.read_exact(&mut chunk)?;
+            assert_eq!(&chunk, b"trace");
+            let mut rest = String::new();
+            reader.read_to_string(&mut rest)?;
+            assert_eq!(rest.len(), value.len() - 5);
+
+            assert!(store.read_stream("missing")?.is_none());
+            Storage::delete_db_files(store)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_if_changed() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        assert!(store.write_if_changed("test1", "test_value1")?);
+
+        let sequence = store.current_sequence();
+        assert!(!store.write_if_changed("test1", "test_value1")?);
+        assert_eq!(store.current_sequence(), sequence);
+
+        assert!(store.write_if_changed("test1", "test_value2")?);
+        assert_eq!(store.read("test1")?, Some("test_value2".to_string()));
+
+        store.write_with_ttl("test2", "test_value2", Duration::from_secs(3600), None)?;
+        assert!(store.write_if_changed("test2", "test_value2")?);
+        assert!(!store.write_if_changed("test2", "test_value2")?);
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_value_to_storage() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        store.write("test", "test_value")?;
+        assert_eq!(store.read("test").unwrap(), Some("test_value".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_a_value() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        store.write("test", "test_value")?;
+        assert_eq!(store.read("test")?, Some("test_value".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_value() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        store.write("test", "test_value")?;
+        assert_eq!(store.read("test")?, Some("test_value".to_string()));
+        store.delete("test")?;
+        assert_eq!(store.read("test")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_multiple_answers() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        store.write("test1", "test_value1")?;
+        store.write("test2", "test_value2")?;
+        store.write("test3", "test_value3")?;
+        store.write("tes4", "test_value4")?;
+
+        let result = store.partial_compare("test")?;
+        assert_eq!(
+            result,
+            vec![
+                ("test1".to_string(), "test_value1".to_string()),
+                ("test2".to_string(), "test_value2".to_string()),
+                ("test3".to_string(), "test_value3".to_string())
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_has_key() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        store.write("test1", "test_value1")?;
+        assert!(store.has_key("test1")?);
+        assert!(!store.has_key("test2")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_storage() -> Result<(), StorageError> {
+        let (_, config, store) = create_path_and_storage(false)?;
+        store.write("test1", "test_value1")?;
+        drop(store);
+
+        let open_store = Storage::open(&config);
+        assert!(open_store.is_ok());
+        assert_eq!(
+            open_store.as_ref().unwrap().read("test1")?,
+            Some("test_value1".to_string())
+        );
+
+        Storage::delete_db_files(open_store.unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_password_policy_lowercase_and_max_length() -> Result<(), StorageError> {
+        let policy = PasswordPolicyConfig {
+            min_length: 2,
+            min_number_of_special_chars: 0,
+            min_number_of_uppercase: 0,
+            min_number_of_digits: 0,
+            min_number_of_lowercase: 2,
+            max_length: 10,
+            character_sets: CharacterSets::default(),
+        };
+        let config_with_password = |password: &str| StorageConfig {
+            path: temp_storage().to_string_lossy().to_string(),
+            password: Some(Secret::from(password.to_string())),
+            kdf_params: KdfParams::default(),
+            encrypt_keys: false,
+            password_policy: None,
+            rocksdb: None,
+            prefix_length: None,
+            cache_capacity: None,
+            backup_batch_size: None,
+            metrics: false,
+            max_key_length: None,
+            max_value_bytes: None,
+            compression: ValueCompression::None,
+            sync_writes: false,
+            disable_wal: false,
+            merge_operator: None,
+            lossy_utf8: false,
+        };
+
+        for password in ["PASSWORD123", "Ab", "abcdefghijk"] {
+            assert!(matches!(
+                Storage::new_with_policy(&config_with_password(password), Some(policy.clone())),
+                Err(StorageError::WeakPassword(_))
+            ));
+        }
+
+        let store = Storage::new_with_policy(&config_with_password("Password"), Some(policy))?;
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_password_policy_counts_characters() -> Result<(), StorageError> {
+        let policy = PasswordPolicyConfig {
+            min_length: 12,
+            min_number_of_special_chars: 0,
+            min_number_of_uppercase: 0,
+            min_number_of_digits: 0,
+            min_number_of_lowercase: 0,
+            max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+            character_sets: CharacterSets::default(),
+        };
+        let config_with_password = |password: &str| StorageConfig {
+            path: temp_storage().to_string_lossy().to_string(),
+            password: Some(Secret::from(password.to_string())),
+            kdf_params: KdfParams::default(),
+            encrypt_keys: false,
+            password_policy: None,
+            rocksdb: None,
+            prefix_length: None,
+            cache_capacity: None,
+            backup_batch_size: None,
+            metrics: false,
+            max_key_length: None,
+            max_value_bytes: None,
+            compression: ValueCompression::None,
+            sync_writes: false,
+            disable_wal: false,
+            merge_operator: None,
+            lossy_utf8: false,
+        };
+
+        // 4 characters, 12 bytes.
+        assert!(matches!(
+            Storage::new_with_policy(&config_with_password("€€€€"), Some(policy.clone())),
+            Err(StorageError::WeakPassword(_))
+        ));
+
+        // 12 characters, 24 bytes.
+        let store = Storage::new_with_policy(&config_with_password("ñáéíóúüñáéíó"), Some(policy))?;
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_weak_password_reports_violations() {
+        let config = StorageConfig {
+            path: temp_storage().to_string_lossy().to_string(),
+            password: Some(Secret::from("abc".to_string())),
+            kdf_params: KdfParams::default(),
+            encrypt_keys: false,
+            password_policy: None,
+            rocksdb: None,
+            prefix_length: None,
+            cache_capacity: None,
+            backup_batch_size: None,
+            metrics: false,
+            max_key_length: None,
+            max_value_bytes: None,
+            compression: ValueCompression::None,
+            sync_writes: false,
+            disable_wal: false,
+            merge_operator: None,
+            lossy_utf8: false,
+        };
+
+        let Err(StorageError::WeakPassword(violations)) = Storage::new(&config) else {
+            panic!("expected a weak password error");
+        };
+        assert_eq!(
+            violations,
+            vec![
+                PolicyViolation::TooShort { needed: 12, got: 3 },
+                PolicyViolation::NotEnoughSpecialChars { needed: 3, got: 0 },
+                PolicyViolation::NotEnoughUppercase { needed: 3, got: 0 },
+                PolicyViolation::NotEnoughDigits { needed: 3, got: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_durability() -> Result<(), StorageError> {
+        for (sync_writes, disable_wal) in [(true, false), (false, true)] {
+            let path = temp_storage();
+            let mut config = StorageConfig::new(path.to_string_lossy().to_string(), None);
+            config.sync_writes = sync_writes;
+            config.disable_wal = disable_wal;
+
+            let store = Storage::new(&config)?;
+            store.write("test1", "test_value1")?;
+            let transaction_id = store.begin_transaction();
+            store.transactional_write("test2", "test_value2", transaction_id)?;
+            store.commit_transaction(transaction_id)?;
+            drop(store);
+
+            // Without the write-ahead log, the writes are flushed when the storage is closed.
+            let store = Storage::open(&config)?;
+            assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+            assert_eq!(store.read("test2")?, Some("test_value2".to_string()));
+            Storage::delete_db_files(store)?;
+        }
+
+        let mut config = StorageConfig::new(temp_storage().to_string_lossy().to_string(), None);
+        config.sync_writes = true;
+        config.disable_wal = true;
+        assert!(matches!(
+            Storage::new(&config),
+            Err(StorageError::InvalidConfig(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge() -> Result<(), StorageError> {
+        let mut config = StorageConfig::new(temp_storage().to_string_lossy().to_string(), None);
+        config.merge_operator = Some(MergeOperator::JsonArrayAppend);
+        let store = Storage::new(&config)?;
+        store.merge("events", "1", None)?;
+        store.merge("events", "2", None)?;
+        let transaction_id = store.begin_transaction();
+        store.merge("events", "\"three\"", Some(transaction_id))?;
+        store.commit_transaction(transaction_id)?;
+        assert_eq!(store.read("events")?, Some("[1,2,\"three\"]".to_string()));
+
+        store.write("single", "\"a\"")?;
+        store.merge("single", "[4]", None)?;
+        assert_eq!(store.read("single")?, Some("[\"a\",[4]]".to_string()));
+        assert!(matches!(
+            store.merge("events", "not json", None),
+            Err(StorageError::SerializationError)
+        ));
+        drop(store);
+
+        let store = Storage::open(&config)?;
+        assert_eq!(store.read("events")?, Some("[1,2,\"three\"]".to_string()));
+        Storage::delete_db_files(store)?;
+
+        let mut config = StorageConfig::new(temp_storage().to_string_lossy().to_string(), None);
+        config.merge_operator = Some(MergeOperator::LastWriteWins);
+        let store = Storage::new(&config)?;
+        store.write("key", "first")?;
+        store.merge("key", "second", None)?;
+        store.merge("key", "third", None)?;
+        assert_eq!(store.read("key")?, Some("third".to_string()));
+        Storage::delete_db_files(store)?;
+
+        let (_, _, store) = create_path_and_storage(false)?;
+        assert!(matches!(
+            store.merge("key", "value", None),
+            Err(StorageError::InvalidConfig(_))
+        ));
+        Storage::delete_db_files(store)?;
+
+        let mut config = StorageConfig::new(
+            temp_storage().to_string_lossy().to_string(),
+            Some(Secret::from("ABC!@#123pass".to_string())),
+        );
+        config.merge_operator = Some(MergeOperator::JsonArrayAppend);
+        assert!(matches!(
+            Storage::new(&config),
+            Err(StorageError::InvalidConfig(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_password_policy_config_validate() -> Result<(), StorageError> {
+        let policy = PasswordPolicyConfig {
+            min_length: 5,
+            min_number_of_special_chars: 1,
+            min_number_of_uppercase: 1,
+            min_number_of_digits: 3,
+            min_number_of_lowercase: 0,
+            max_length: 5,
+            character_sets: CharacterSets::default(),
+        };
+        policy.validate()?;
+
+        let too_many_digits = PasswordPolicyConfig {
+            min_number_of_digits: 100,
+            ..policy.clone()
+        };
+        let too_short = PasswordPolicyConfig {
+            max_length: 4,
+            ..policy.clone()
+        };
+        for invalid in [too_many_digits, too_short] {
+            assert!(matches!(
+                invalid.validate(),
+                Err(StorageError::InvalidConfig(_))
+            ));
+            assert!(PasswordPolicy::try_new(invalid.clone()).is_err());
+
+            let path = temp_storage();
+            let config = StorageConfig::new(
+                path.to_string_lossy().to_string(),
+                Some(Secret::from("password".to_string())),
+            );
+            assert!(matches!(
+                Storage::new_with_policy(&config, Some(invalid.clone())),
+                Err(StorageError::InvalidConfig(_))
+            ));
+            assert!(matches!(
+                Storage::new_with_password_policy(&config, PasswordPolicy::new(invalid.clone())),
+                Err(StorageError::InvalidConfig(_))
+            ));
+            assert!(matches!(
+                Storage::builder()
+                    .path(path.to_string_lossy())
+                    .password_policy(invalid)
+                    .create_if_missing(true)
+                    .open(),
+                Err(StorageError::InvalidConfig(_))
+            ));
+            assert!(!path.exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_password_policy_character_sets() -> Result<(), StorageError> {
+        let config = PasswordPolicyConfig {
+            min_length: 4,
+            min_number_of_special_chars: 1,
+            min_number_of_uppercase: 1,
+            min_number_of_digits: 0,
+            min_number_of_lowercase: 0,
+            max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+            character_sets: CharacterSets {
+                special: Some("@€".to_string()),
+                uppercase: Some("ÄÖÜ".to_string()),
+                ..CharacterSets::default()
+            },
+        };
+        let policy = PasswordPolicy::try_new(config.clone())?;
+
+        assert!(policy.is_valid("Äbc€"));
+        assert!(policy.is_valid("abÖ@"));
+        assert_eq!(
+            policy.check("Abc!"),
+            Err(vec![
+                PolicyViolation::NotEnoughSpecialChars { needed: 1, got: 0 },
+                PolicyViolation::NotEnoughUppercase { needed: 1, got: 0 },
+            ])
+        );
+
+        let empty_set = PasswordPolicyConfig {
+            character_sets: CharacterSets {
+                special: Some(String::new()),
+                ..CharacterSets::default()
+            },
+            ..config
+        };
+        assert!(matches!(
+            empty_set.validate(),
+            Err(StorageError::InvalidConfig(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_password_policy_with_validator() -> Result<(), StorageError> {
+        let policy = PasswordPolicy::try_new(PasswordPolicyConfig {
+            min_length: 5,
+            min_number_of_special_chars: 0,
+            min_number_of_uppercase: 0,
+            min_number_of_digits: 5,
+            min_number_of_lowercase: 0,
+            max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+            character_sets: CharacterSets::default(),
+        })?
+        .with_validator(|password| password != "12345");
+        let config_with_password = |password: &str| StorageConfig {
+            path: temp_storage().to_string_lossy().to_string(),
+            password: Some(Secret::from(password.to_string())),
+            kdf_params: KdfParams::default(),
+            encrypt_keys: false,
+            password_policy: None,
+            rocksdb: None,
+            prefix_length: None,
+            cache_capacity: None,
+            backup_batch_size: None,
+            metrics: false,
+            max_key_length: None,
+            max_value_bytes: None,
+            compression: ValueCompression::None,
+            sync_writes: false,
+            disable_wal: false,
+            merge_operator: None,
+            lossy_utf8: false,
+        };
+
+        let Err(StorageError::WeakPassword(violations)) =
+            Storage::new_with_password_policy(&config_with_password("12345"), policy.clone())
+        else {
+            panic!("expected a weak password error");
+        };
+        assert_eq!(violations, vec![PolicyViolation::RejectedByValidator]);
+
+        let store = Storage::new_with_password_policy(&config_with_password("54321"), policy)?;
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_config_from_file() -> Result<(), StorageError> {
+        let path = temp_storage();
+        let toml_path = path.with_extension("toml");
+        fs::write(
+            &toml_path,
+            format!(
+                "path = {:?}\npassword = \"password\"\nencrypt_keys = true\n\n\
+                 [password_policy]\nmin_length = 1\nmin_number_of_special_chars = 0\n\
+                 min_number_of_uppercase = 0\nmin_number_of_digits = 0\n",
+                path.to_string_lossy()
+            ),
+        )?;
+        let json_path = path.with_extension("json");
+        fs::write(&json_path, r#"{"path": "storage.db"}"#)?;
+
+        let config = StorageConfig::from_file(&toml_path)?;
+        assert!(config.encrypt_keys);
+        assert_eq!(
+            config.password_policy.as_ref().map(|p| p.min_length),
+            Some(1)
+        );
+        let store = Storage::new(&config)?;
+        store.write("test1", "test_value1")?;
+        Storage::delete_db_files(store)?;
+
+        let config = StorageConfig::from_file(&json_path)?;
+        assert_eq!(config.path, "storage.db");
+        assert!(config.password.is_none());
+
+        fs::write(&json_path, "path = \"storage.db\"")?;
+        assert!(matches!(
+            StorageConfig::from_file(&json_path),
+            Err(StorageError::InvalidConfig(_))
+        ));
+
+        fs::remove_file(toml_path)?;
+        fs::remove_file(json_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_with_rocksdb_options() -> Result<(), StorageError> {
+        let mut config = StorageConfig::new(temp_storage().to_string_lossy().to_string(), None);
+        config.rocksdb = Some(RocksDbOptions {
+            block_cache_mb: Some(8),
+            compression: Some(Compression::Lz4),
+            write_buffer_mb: Some(4),
+        });
+
+        let store = Storage::new(&config)?;
+        store.write("test1", "test_value1")?;
+        drop(store);
+
+        let store = Storage::open(&config)?;
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+        Storage::delete_db_files(store)?;
+
+        let config: StorageConfig =
+            toml::from_str("path = \"storage.db\"\n\n[rocksdb]\ncompression = \"zstd\"\n")
+                .map_err(|e| StorageError::InvalidConfig(e.to_string()))?;
+        let rocksdb = config.rocksdb.unwrap_or_default();
+        assert_eq!(rocksdb.compression, Some(Compression::Zstd));
+        assert!(rocksdb.block_cache_mb.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_compare_with_prefix_length() -> Result<(), StorageError> {
+        let mut config = StorageConfig::new(temp_storage().to_string_lossy().to_string(), None);
+        config.prefix_length = Some(9);
+        let store = Storage::new(&config)?;
+
+        for i in 0..3 {
+            for j in 0..3 {
+                store.write(&format!("bitvmx/{}/topic_{}", i, j), "value")?;
+            }
+        }
+        store.write("other", "value")?;
+
+        let keys = store.partial_compare_keys("bitvmx/1/topic_")?;
+        assert_eq!(
+            keys,
+            vec!["bitvmx/1/topic_0", "bitvmx/1/topic_1", "bitvmx/1/topic_2"]
+        );
+        assert_eq!(store.partial_compare_keys("bitvmx/1/topic_2")?.len(), 1);
+        assert_eq!(store.partial_compare_keys("bitvmx/")?.len(), 9);
+        assert_eq!(store.partial_compare("oth")?.len(), 1);
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_builder() -> Result<(), StorageError> {
+        let path = temp_storage().to_string_lossy().to_string();
+        let password = Secret::from("password".to_string());
+        let policy = PasswordPolicyConfig {
+            min_length: 1,
+            min_number_of_special_chars: 0,
+            min_number_of_uppercase: 0,
+            min_number_of_digits: 0,
+            min_number_of_lowercase: 0,
+            max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+            character_sets: CharacterSets::default(),
+        };
+
+        assert!(Storage::builder().path(path.as_str()).open().is_err());
+
+        let store = Storage::builder()
+            .path(path.as_str())
+            .password(password.clone())
+            .password_policy(policy.clone())
+            .create_if_missing(true)
+            .open()?;
+        store.write("test1", "test_value1")?;
+        drop(store);
+
+        let store = Storage::builder()
+            .path(path.as_str())
+            .password(password)
+            .password_policy(policy)
+            .read_only(true)
+            .open()?;
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+        assert!(matches!(
+            store.write("test2", "test_value2"),
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(store.delete("test1"), Err(StorageError::ReadOnly)));
+        assert!(matches!(
+            Storage::builder()
+                .path(path.as_str())
+                .read_only(true)
+                .create_if_missing(true)
+                .open(),
+            Err(StorageError::InvalidConfig(_))
+        ));
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_inexistent_storage() -> Result<(), StorageError> {
+        let path = &temp_storage();
+
+        let config = StorageConfig {
+            path: path.to_string_lossy().to_string(),
+            password: Some(Secret::from("password")),
+            kdf_params: KdfParams::default(),
+            encrypt_keys: false,
+            password_policy: None,
+            rocksdb: None,
+            prefix_length: None,
+            cache_capacity: None,
+            backup_batch_size: None,
+            metrics: false,
+            max_key_length: None,
+            max_value_bytes: None,
+            compression: ValueCompression::None,
+            sync_writes: false,
+            disable_wal: false,
+            merge_operator: None,
+            lossy_utf8: false,
+        };
+        let open_store = Storage::open(&config);
+        assert!(open_store.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_keys_in_range() -> Result<(), StorageError> {
+        for encrypt_keys in [false, true] {
+            let path = temp_storage().to_string_lossy().to_string();
+            let mut config =
+                StorageConfig::new(path, Some(Secret::from("ABC!@#123pass".to_string())));
+            config.encrypt_keys = encrypt_keys;
+            let store = Storage::new(&config)?;
+            for key in ["job/1", "job/2", "job/3", "job/4"] {
+                store.write(key, "value")?;
+            }
+
+            assert_eq!(
+                store.keys_in_range(Some("job/2"), Some("job/4"), false)?,
+                vec!["job/2", "job/3"]
+            );
+            assert_eq!(
+                store.keys_in_range(Some("job/2"), Some("job/4"), true)?,
+                vec!["job/3", "job/2"]
+            );
+            assert_eq!(
+                store.keys_in_range(Some("job/"), None, true)?,
+                vec!["job/4", "job/3", "job/2", "job/1"]
+            );
+            assert_eq!(
+                store.keys_in_range(None, Some("job/2"), false)?,
+                vec!["job/1"]
+            );
+            assert!(store.keys_in_range(Some("b"), Some("a"), true)?.is_empty());
+
+            Storage::delete_db_files(store)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_keys() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        store.write("test1", "test_value1")?;
+        store.write("test2", "test_value2")?;
+        store.write("test3", "test_value3")?;
+        store.write("tes4", "test_value4")?;
+
+        let keys = store.keys()?;
+        assert_eq!(keys.len(), 4);
+        assert!(keys.contains(&"test1".to_string()));
+        assert!(keys.contains(&"test2".to_string()));
+        assert!(keys.contains(&"test3".to_string()));
+        assert!(keys.contains(&"tes4".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lossy_utf8_keys() -> Result<(), StorageError> {
+        let (_, mut config, store) = create_path_and_storage(false)?;
+        store.write("test1", "test_value1")?;
+        store.db.put(b"test\xff", "test_value2")?;
+
+        assert!(matches!(store.keys(), Err(StorageError::ConversionError)));
+        assert!(matches!(
+            store.partial_compare_keys("test"),
+            Err(StorageError::ConversionError)
+        ));
+        drop(store);
+
+        config.lossy_utf8 = true;
+        let store = Storage::new(&config)?;
+        let expected = vec!["test1".to_string(), "test\u{fffd}".to_string()];
+        assert_eq!(store.keys()?, expected);
+        assert_eq!(store.partial_compare_keys("test")?, expected);
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_keys_and_prefix() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.write("test1", "test_value1")?;
+        store.write("test2", "test_value2")?;
+        store.write("tes4", "test_value4")?;
+
+        let first = store
+            .iter_keys()
+            .find(|key| matches!(key, Ok(key) if key.starts_with("test")));
+        assert_eq!(first.transpose()?, Some("test1".to_string()));
+
+        let mut entries = store.iter_prefix("test");
+        assert_eq!(
+            entries.next().transpose()?,
+            Some(("test1".to_string(), "test_value1".to_string()))
+        );
+        assert_eq!(
+            entries.next().transpose()?,
+            Some(("test2".to_string(), "test_value2".to_string()))
+        );
+        assert!(entries.next().is_none());
+        drop(entries);
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_entries() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.write("test1", "test_value1")?;
+
+        let entries = store.raw_entries().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().any(|(k, _)| k == DEK_KEY.as_bytes()));
+        let (_, value) = entries
+            .iter()
+            .find(|(k, _)| k == b"test1")
+            .expect("test1 is stored under its plaintext key");
+        assert_ne!(value, b"test_value1");
+        assert_eq!(store.decrypt_data(b"test1", value.clone())?, b"test_value1");
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_entries_and_values() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.write("test1", "test_value1")?;
+        store.write("test2", "test_value2")?;
+
+        let entries = store.entries().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            entries,
+            vec![
+                ("test1".to_string(), "test_value1".to_string()),
+                ("test2".to_string(), "test_value2".to_string()),
+            ]
+        );
+        let values = store.values().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(values, vec!["test_value1", "test_value2"]);
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_count() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        assert_eq!(store.count(true)?, 0);
+
+        store.write("test1", "test_value1")?;
+        store.write("test2", "test_value2")?;
+        store.write("tes4", "test_value4")?;
+
+        assert_eq!(store.count(true)?, 3);
+        // The estimate also counts the internal DEK entries.
+        assert!(store.count(false)? >= 3);
+        assert_eq!(store.count_prefix("test")?, 2);
+        assert_eq!(store.count_prefix("other")?, 0);
+
+        // No scan yields the internal DEK entries.
+        assert_eq!(store.count_prefix("")?, 3);
+        assert_eq!(store.iter_keys().count(), 3);
+        assert_eq!(store.iter_prefix("").count(), 3);
+        assert_eq!(store.keys_in_range(None, None, false)?.len(), 3);
+        assert_eq!(store.snapshot().keys()?.len(), 3);
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        store.write("test1", "test_value1")?;
+        store.write("test2", "test_value2")?;
+
+        let stats = store.stats()?;
+        assert_eq!(stats.estimate_num_keys, 2);
+        assert!(stats.disk_size > 0);
+        assert_eq!(stats.disk_size, store.disk_size()?);
+
+        assert!(store.property("rocksdb.stats")?.is_some());
+        assert!(store.property("rocksdb.no-such-property")?.is_none());
+        assert!(store
+            .property_int("rocksdb.cur-size-all-mem-tables")?
+            .is_some_and(|size| size > 0));
+        assert_eq!(
+            store.property_int("rocksdb.num-live-sst-files")?,
+            Some(stats.num_live_sst_files)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_and_compact_range() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.write("test1", "test_value1")?;
+        store.write("test2", "test_value2")?;
+        assert_eq!(store.property_int("rocksdb.num-live-sst-files")?, Some(0));
+
+        let store = store.flush()?;
+        assert!(store.property_int("rocksdb.num-live-sst-files")? > Some(0));
+
+        store.delete("test1")?;
+        let store = store.compact_range(Some("test1"), None)?;
+        assert_eq!(store.read("test1")?, None);
+        assert_eq!(store.read("test2")?, Some("test_value2".to_string()));
+
+        let transaction_id = store.begin_transaction();
+        store.transactional_write("test3", "test_value3", transaction_id)?;
+        let Err(ReopenError {
+            storage: Some(store),
+            error: StorageError::TransactionsOpen(1),
+        }) = store.flush()
+        else {
+            panic!("expected the storage back with a transactions open error");
+        };
+        let store = *store;
+        store.commit_transaction(transaction_id)?;
+        assert_eq!(store.read("test3")?, Some("test_value3".to_string()));
+
+        let store = store.flush()?;
+        assert_eq!(store.read("test3")?, Some("test_value3".to_string()));
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_reclaim_space() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        let value = "v".repeat(10_000);
+        for i in 0..200 {
+            store.write(&format!("job/{}", i), &value)?;
+        }
+        store.delete_prefix("job/")?;
+
+        let cancel = AtomicBool::new(true);
+        let ranges = RefCell::new(0);
+        let (store, report) =
+            store.reclaim_space(&cancel, Some(&|done| *ranges.borrow_mut() = done))?;
+        assert!(report.cancelled);
+        assert_eq!(report.bytes_freed(), 0);
+        assert_eq!(*ranges.borrow(), 0);
+
+        cancel.store(false, Ordering::Relaxed);
+        let (store, report) =
+            store.reclaim_space(&cancel, Some(&|done| *ranges.borrow_mut() = done))?;
+        assert!(!report.cancelled);
+        assert_eq!(*ranges.borrow(), 1);
+        assert!(report.bytes_freed() > 0);
+
+        assert_eq!(store.count(true)?, 0);
+        store.write("test1", "test_value1")?;
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_integrity_and_repair() -> Result<(), StorageError> {
+        let (_, config, store) = create_path_and_storage(false)?;
+        store.write("test1", "test_value1")?;
+        store.check_integrity()?;
+        drop(store);
+
+        let store = Storage::open_and_repair(&config)?;
+        store.check_integrity()?;
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_sorted_keys() -> Result<(), StorageError> {
+        for encrypt_keys in [false, true] {
+            let path = temp_storage().to_string_lossy().to_string();
+            let mut config =
+                StorageConfig::new(path, Some(Secret::from("ABC!@#123pass".to_string())));
+            config.encrypt_keys = encrypt_keys;
+            let store = Storage::new(&config)?;
+            for key in ["b", "c", "a"] {
+                store.write(key, "value")?;
+            }
+            assert_eq!(store.sorted_keys()?, vec!["a", "b", "c"]);
+            Storage::delete_db_files(store)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_current_sequence() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        let before = store.current_sequence();
+        store.write("test1", "test_value1")?;
+        let snapshot = store.snapshot();
+        assert!(snapshot.sequence() > before);
+        assert_eq!(snapshot.sequence(), store.current_sequence());
+
+        store.write("test2", "test_value2")?;
+        assert!(store.current_sequence() > snapshot.sequence());
+        assert_eq!(snapshot.read("test2")?, None);
+        drop(snapshot);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_ignores_later_writes() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.set("test1", "test_value1", None)?;
+        store.set("test2", "test_value2", None)?;
+
+        let snapshot = store.snapshot();
+        store.set("test1", "changed", None)?;
+        store.set("test3", "test_value3", None)?;
+        store.delete("test2")?;
+
+        assert_eq!(
+            snapshot.get::<&str, String>("test1")?,
+            Some("test_value1".to_string())
+        );
+        assert_eq!(snapshot.read("test2")?, Some("\"test_value2\"".to_string()));
+        assert_eq!(snapshot.read("test3")?, None);
+        assert_eq!(snapshot.partial_compare("test")?.len(), 2);
+        assert!(!snapshot.keys()?.contains(&"test3".to_string()));
+        drop(snapshot);
+
+        assert_eq!(store.partial_compare("test")?.len(), 2);
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        for i in 0..1500 {
+            store.write(&format!("job_{}", i), &format!("value{}", i))?;
+        }
+        store.write("settings", "value")?;
+
+        let to_new_scheme = |key: &str| key.strip_prefix("job_").map(|id| format!("job/{}", id));
+        assert_eq!(store.migrate(to_new_scheme)?, 1500);
+        assert_eq!(store.read("job_7")?, None);
+        assert_eq!(store.read("job/7")?, Some("value7".to_string()));
+        assert_eq!(store.read("job/1499")?, Some("value1499".to_string()));
+        assert_eq!(store.read("settings")?, Some("value".to_string()));
+        assert_eq!(store.migrate(to_new_scheme)?, 0);
+
+        store.write("a", "value_a")?;
+        assert!(matches!(
+            store.migrate(|key| (key == "settings").then(|| "a".to_string())),
+            Err(StorageError::InvalidKey(_))
+        ));
+        assert_eq!(store.read("a")?, Some("value_a".to_string()));
+        assert_eq!(store.read("settings")?, Some("value".to_string()));
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_and_copy() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.write("old/test1", "test_value1")?;
+        store.write("old/test2", "test_value2")?;
+
+        store.copy("old/test1", "new/test1", None)?;
+        assert_eq!(store.read("old/test1")?, Some("test_value1".to_string()));
+        assert_eq!(store.read("new/test1")?, Some("test_value1".to_string()));
+
+        let transaction_id = store.begin_transaction();
+        store.rename("old/test2", "new/test2", Some(transaction_id))?;
+        assert_eq!(store.read("new/test2")?, None);
+        store.commit_transaction(transaction_id)?;
+        assert_eq!(store.read("old/test2")?, None);
+        assert_eq!(store.read("new/test2")?, Some("test_value2".to_string()));
+
+        assert!(matches!(
+            store.rename("missing", "new/missing", None),
+            Err(StorageError::NotFound { kind: "Key", .. })
+        ));
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.write("test1", "test_value1")?;
+
+        store.apply(vec![
+            WriteOp::Put {
+                key: "test2".to_string(),
+                value: "test_value2".to_string(),
+            },
+            WriteOp::Delete {
+                key: "test1".to_string(),
+            },
+        ])?;
+        assert_eq!(store.read("test1")?, None);
+        assert_eq!(store.read("test2")?, Some("test_value2".to_string()));
+
+        // A failing write discards the writes before it.
+        let result = store.apply(vec![
+            WriteOp::Put {
+                key: "test3".to_string(),
+                value: "test_value3".to_string(),
+            },
+            WriteOp::Delete {
+                key: "test2".to_string(),
+            },
+            WriteOp::Put {
+                key: "".to_string(),
+                value: "value".to_string(),
+            },
+        ]);
+        assert!(matches!(result, Err(StorageError::InvalidKey(_))));
+        assert_eq!(store.read("test3")?, None);
+        assert_eq!(store.read("test2")?, Some("test_value2".to_string()));
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_batch_lenient() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        let items = [("job/1", 1), ("", 2), ("job/3", 3), ("\0job", 4)];
+        let results = store.set_batch_lenient(&items)?;
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(StorageError::InvalidKey(_))));
+        assert!(results[2].is_ok());
+        assert!(matches!(results[3], Err(StorageError::ReservedKey(_))));
+        assert_eq!(store.get::<_, u32>("job/1")?, Some(1));
+        assert_eq!(store.get::<_, u32>("job/3")?, Some(3));
+
+        let scoped = store.scoped("tenant/");
+        let results = scoped.set_batch_lenient(&[("a", "x"), ("b", "y")])?;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(store.get::<_, String>("tenant/b")?, Some("y".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scoped_store() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.write("other/test1", "other_value")?;
+
+        let jobs = store.scoped("jobs/");
+        jobs.write("test1", "test_value1")?;
+        jobs.set("test2", "test_value2", None)?;
+
+        assert_eq!(store.read("jobs/test1")?, Some("test_value1".to_string()));
+        assert_eq!(jobs.read("test1")?, Some("test_value1".to_string()));
+        assert_eq!(
+            jobs.get::<&str, String>("test2")?,
+            Some("test_value2".to_string())
+        );
+        assert_eq!(jobs.read("other/test1")?, None);
+        assert_eq!(jobs.keys()?, vec!["test1", "test2"]);
+        assert_eq!(
+            jobs.partial_compare("test1")?,
+            vec![("test1".to_string(), "test_value1".to_string())]
+        );
+
+        jobs.delete("test1")?;
+        assert_eq!(store.read("jobs/test1")?, None);
+        assert_eq!(store.read("other/test1")?, Some("other_value".to_string()));
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+
+        assert_eq!(store.entry("test1", None).or_insert("first")?, "first");
+        assert_eq!(store.entry("test1", None).or_insert("second")?, "first");
+        let value = store
+            .entry("test1", None)
+            .and_modify(|value| value.push_str("_changed"))?
+            .or_insert("unused")?;
+        assert_eq!(value, "first_changed");
+        store
+            .entry("missing", None)
+            .and_modify(|value| value.push('!'))?;
+        assert_eq!(store.read("missing")?, None);
+
+        let transaction_id = store.begin_transaction();
+        let counter = store
+            .typed_entry::<u64>("counter", Some(transaction_id))
+            .and_modify(|counter| *counter += 1)?
+            .or_insert_with(|| 10)?;
+        assert_eq!(counter, 10);
+        store
+            .typed_entry::<u64>("counter", Some(transaction_id))
+            .and_modify(|counter| *counter += 1)?;
+        assert_eq!(store.get::<&str, u64>("counter")?, None);
+        store.commit_transaction(transaction_id)?;
+        assert_eq!(store.get::<&str, u64>("counter")?, Some(11));
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_storage() -> Result<(), StorageError> {
+        use crate::async_storage::AsyncStorage;
+
+        let (path, _, store) = create_path_and_storage(true)?;
+        let store = AsyncStorage::new(store);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .map_err(StorageError::IoError)?;
+
+        runtime.block_on(async {
+            store.write("test1", "test_value1").await?;
+            assert_eq!(store.read("test1").await?, Some("test_value1".to_string()));
+
+            let transaction_id = store.begin_transaction().await?;
+            store.set("test2", 2u64, Some(transaction_id)).await?;
+            assert_eq!(store.get::<u64>("test2").await?, None);
+            store.commit_transaction(transaction_id).await?;
+            assert_eq!(store.get::<u64>("test2").await?, Some(2));
+
+            store.delete("test1").await?;
+            assert!(!store.has_key("test1").await?);
+            Ok::<(), StorageError>(())
+        })?;
+
+        drop(store);
+        fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_cache() -> Result<(), StorageError> {
+        let path = temp_storage().to_string_lossy().to_string();
+        let mut config = StorageConfig::new(path, None);
+        config.cache_capacity = Some(2);
+        let store = Storage::new(&config)?;
+        let cached = |key: &str| store.cache.as_ref().unwrap().borrow_mut().get(key);
+
+        store.write("test1", "test_value1")?;
+        store.write("test2", "test_value2")?;
+        store.write("test3", "test_value3")?;
+        store.read("test1")?;
+        store.read("test2")?;
+        store.read("test3")?;
+        assert_eq!(cached("test1"), None);
+        assert_eq!(cached("test3"), Some(b"test_value3".to_vec()));
+
+        store.write("test3", "changed")?;
+        assert_eq!(store.read("test3")?, Some("changed".to_string()));
+
+        let transaction_id = store.begin_transaction();
+        store.transactional_write("test3", "in_transaction", transaction_id)?;
+        assert_eq!(store.read("test3")?, Some("changed".to_string()));
+        store.commit_transaction(transaction_id)?;
+        assert_eq!(store.read("test3")?, Some("in_transaction".to_string()));
+
+        store.delete("test3")?;
+        assert_eq!(store.read("test3")?, None);
+
+        store.write_with_ttl("test4", "test_value4", Duration::from_secs(3600), None)?;
+        store.read("test4")?;
+        assert_eq!(cached("test4"), None);
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_and_delete_prefix() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.write("job/1", "test_value1")?;
+        store.write("job/2", "test_value2")?;
+        store.write("job/3", "test_value3")?;
+        store.write("other", "test_value4")?;
+
+        assert_eq!(
+            store.range("job/2", "other")?,
+            vec![
+                ("job/2".to_string(), "test_value2".to_string()),
+                ("job/3".to_string(), "test_value3".to_string()),
+            ]
+        );
+        assert!(store.range("b", "a")?.is_empty());
+
+        assert_eq!(store.delete_prefix("job/")?, 3);
+        assert_eq!(store.keys()?.len(), 2);
+        assert_eq!(store.read("other")?, Some("test_value4".to_string()));
+        assert_eq!(store.delete_prefix("")?, 1);
+        assert!(store.db.get(DEK_KEY)?.is_some());
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_first_and_last_key() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        assert_eq!(store.first_key()?, None);
+        assert_eq!(store.last_key()?, None);
+
+        store.write("b", "test_value1")?;
+        store.write("a", "test_value2")?;
+        store.write("c", "test_value3")?;
+        assert_eq!(store.first_key()?, Some("a".to_string()));
+        assert_eq!(store.last_key()?, Some("c".to_string()));
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_contains() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.write("test1", "test_value1")?;
+        store.write("test3", "test_value3")?;
+
+        assert_eq!(
+            store.multi_contains(&["test1", "test2", "test3"])?,
+            vec![true, false, true]
+        );
+        assert!(store.multi_contains(&[])?.is_empty());
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_with_ttl() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        let hour = Duration::from_secs(3600);
+
+        store.write_with_ttl("test1", "test_value1", hour, None)?;
+        store.write_with_ttl("test2", "test_value2", Duration::ZERO, None)?;
+        store.write_with_ttl("test3", "test_value3", Duration::ZERO, None)?;
+        store.write("test4", "test_value4")?;
+
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+        assert_eq!(
+            store.partial_compare("test")?,
+            vec![
+                ("test1".to_string(), "test_value1".to_string()),
+                ("test4".to_string(), "test_value4".to_string()),
+            ]
+        );
+
+        assert!(store.has_key("test2")?);
+        assert_eq!(store.read("test2")?, None);
+        assert!(!store.has_key("test2")?);
+
+        assert_eq!(store.purge_expired()?, 1);
+        assert!(!store.has_key("test3")?);
+        assert_eq!(store.count(true)?, 2);
+        assert_eq!(store.purge_expired()?, 0);
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_value_store_delete() -> Result<(), StorageError> {
+        fn remove<S: KeyValueStore>(
+            store: &S,
+            key: &str,
+            transaction_id: Option<Uuid>,
+        ) -> Result<(), StorageError> {
+            store.delete(key, transaction_id)
+        }
+
+        let (_guard, store) = Storage::open_temp()?;
+        store.set("test1", "test_value1", None)?;
+        store.set("test2", "test_value2", None)?;
+        store.set("jobs/test3", "test_value3", None)?;
+
+        remove(&store, "test1", None)?;
+        assert!(!store.has_key("test1")?);
+
+        let transaction_id = store.begin_transaction();
+        remove(&store, "test2", Some(transaction_id))?;
+        assert!(store.has_key("test2")?);
+        store.commit_transaction(transaction_id)?;
+        assert!(!store.has_key("test2")?);
+
+        remove(&store.scoped("jobs/"), "test3", None)?;
+        assert!(!store.has_key("jobs/test3")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_merge() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+
+        let document = serde_json::json!({
+            "name": "job",
+            "status": { "state": "pending", "attempts": 0 },
+            "error": "timeout",
+        });
+        store.set("test1", &document, None)?;
+
+        let patch = serde_json::json!({
+            "status": { "state": "done", "finished": true },
+            "error": null,
+            "tags": ["a"],
+        });
+        let updated: Value = store.update_merge("test1", &patch, None)?;
+        let expected = serde_json::json!({
+            "name": "job",
+            "status": { "state": "done", "attempts": 0, "finished": true },
+            "tags": ["a"],
+        });
+        assert_eq!(updated, expected);
+        assert_eq!(store.get::<&str, Value>("test1")?, Some(expected));
+
+        let replaced: Value = store.update_merge("test1", &serde_json::json!("text"), None)?;
+        assert_eq!(replaced, Value::from("text"));
+        assert!(matches!(
+            store.update_merge::<&str, Value>("missing", &patch, None),
+            Err(StorageError::NotFound { .. })
+        ));
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_cbor_codec() -> Result<(), StorageError> {
+        let path = temp_storage().to_string_lossy().to_string();
+        let store = Storage::builder()
+            .path(path.as_str())
+            .create_if_missing(true)
+            .codec::<CborCodec>()
+            .open()?;
+
+        let value = ("test_value".to_string(), vec![0u8, 1, 2, 255]);
+        store.set("test1", value.clone(), None)?;
+        assert_eq!(store.get::<&str, (String, Vec<u8>)>("test1")?, Some(value));
+        assert!(
+            serde_json::from_slice::<serde_json::Value>(&store.read_bytes("test1")?.unwrap())
+                .is_err()
+        );
+
+        store.typed_entry::<u64>("counter", None).or_insert(1)?;
+        store
+            .typed_entry::<u64>("counter", None)
+            .and_modify(|counter| *counter += 1)?;
+        assert_eq!(store.get::<&str, u64>("counter")?, Some(2));
+
+        let updates = HashMap::from([("field", Value::from(1))]);
+        assert!(matches!(
+            store.update::<&str, Value>("test1", &updates, None),
+            Err(StorageError::UnsupportedByCodec {
+                operation: "update",
+                codec: "cbor"
+            })
+        ));
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_commit() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        let transaction_id = store.begin_transaction();
+        store.transactional_write("test1", "test_value1", transaction_id)?;
+        store.transactional_write("test2", "test_value2", transaction_id)?;
+        store.commit_transaction(transaction_id)?;
+
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+        assert_eq!(store.read("test2")?, Some("test_value2".to_string()));
+        assert_eq!(store.read("test3")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_rollback() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        let transaction_id = store.begin_transaction();
+        store.transactional_write("test1", "test_value1", transaction_id)?;
+        store.transactional_write("test2", "test_value2", transaction_id)?;
+        store.rollback_transaction(transaction_id)?;
+
+        assert_eq!(store.read("test1")?, None);
+        assert_eq!(store.read("test2")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_not_found_names_missing_key() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        let transaction_id = store.begin_transaction();
+        store.rollback_transaction(transaction_id)?;
+
+        let error = store.commit_transaction(transaction_id).unwrap_err();
+        assert!(matches!(
+            error,
+            StorageError::NotFound {
+                kind: "Transaction",
+                ..
+            }
+        ));
+        assert!(error.to_string().contains(&transaction_id.to_string()));
+
+        let error = store
+            .update::<&str, serde_json::Value>("missing_key", &HashMap::new(), None)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Value not found: missing_key");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_tx() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.set("test1", "test_value1", None)?;
+
+        let transaction_id = store.begin_transaction();
+        store.set("test1", "updated", Some(transaction_id))?;
+        store.set("test2", "test_value2", Some(transaction_id))?;
+        assert_eq!(
+            store.get_tx::<_, String>("test1", transaction_id)?,
+            Some("updated".to_string())
+        );
+        assert_eq!(
+            store.get_tx::<_, String>("test2", transaction_id)?,
+            Some("test_value2".to_string())
+        );
+        assert_eq!(
+            store.get::<_, String>("test1")?,
+            Some("test_value1".to_string())
+        );
+        assert_eq!(store.get::<_, String>("test2")?, None);
+        store.commit_transaction(transaction_id)?;
+
+        assert!(matches!(
+            store.get_tx::<_, String>("test1", transaction_id),
+            Err(StorageError::NotFound { .. })
+        ));
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_transactional_delete() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        store.write("test1", "test_value1")?;
+        let transaction_id = store.begin_transaction();
+        store.transactional_delete("test1", transaction_id).unwrap();
+        store.commit_transaction(transaction_id).unwrap();
+
+        assert_eq!(store.read("test1").unwrap(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_non_commited_transactions_should_not_appear() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        let transaction_id = store.begin_transaction();
+        store
+            .transactional_write("test1", "test_value1", transaction_id)
+            .unwrap();
+        store
+            .transactional_write("test2", "test_value2", transaction_id)
+            .unwrap();
+        store.commit_transaction(transaction_id).unwrap();
+
+        let second_transaction_id = store.begin_transaction();
+        store
+            .transactional_write("test3", "test_value3", second_transaction_id)
+            .unwrap();
+
+        assert_eq!(
+            store.read("test1").unwrap(),
+            Some("test_value1".to_string())
+        );
+        assert_eq!(
+            store.read("test2").unwrap(),
+            Some("test_value2".to_string())
+        );
+        assert_eq!(store.read("test3").unwrap(), None);
+        store.rollback_transaction(second_transaction_id).unwrap();
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypt_and_decrypt() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.set("test1", "test_value1", None)?;
+        let data = store.get::<String, String>("test1".to_string())?;
+        assert!(data.is_some());
+        assert_eq!(data.unwrap(), "test_value1");
+
+        store.set("test1", "test_value2", None)?;
+        let data = store.get::<String, String>("test1".to_string())?;
+        assert!(data.is_some());
+        assert_eq!(data.unwrap(), "test_value2");
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_value_moved_to_another_key_fails_to_decrypt() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.write("test1", "test_value1")?;
+        store.write("test2", "test_value2")?;
+
+        let encrypted = store.db.get("test1")?.unwrap();
+        store.db.put("test2", encrypted)?;
+
+        assert!(matches!(
+            store.read("test2"),
+            Err(StorageError::ValueKeyMismatch)
+        ));
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_values_written_before_key_binding() -> Result<(), StorageError> {
+        let (_, config, store) = create_path_and_storage(true)?;
+        let password = config.password.clone().unwrap();
+        let dek = unwrap_dek(store.db.get(DEK_KEY)?.unwrap(), &password, None)?;
+
+        // Values used to be encrypted on their own, without the marker and the key hash.
+        for (key, value) in [("test1", "test_value1"), ("test2", "test_value2")] {
+            let mut encrypted = Vec::new();
+            Cocoon::new(&dek)
+                .dump(value.as_bytes().to_vec(), &mut encrypted)
+                .map_err(|error| StorageError::FailedToEncryptData { error })?;
+            store.db.put(key, encrypted)?;
+        }
+        drop(store);
+
+        let policy = PasswordPolicyConfig {
+            min_length: 1,
+            min_number_of_special_chars: 0,
+            min_number_of_uppercase: 0,
+            min_number_of_digits: 0,
+            min_number_of_lowercase: 0,
+            max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+            character_sets: CharacterSets::default(),
+        };
+        let store = Storage::open_with_policy(&config, Some(policy))?;
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+        assert_eq!(store.read("test2")?, Some("test_value2".to_string()));
+
+        store.write("test1", "test_value3")?;
+        let encrypted = store.db.get("test1")?.unwrap();
+        let plaintext = Cocoon::new(&dek)
+            .parse(&mut Cursor::new(encrypted))
+            .map_err(|error| StorageError::FailedToDecryptData { error })?;
+        assert_eq!(plaintext[0], KEY_BOUND_MARKER);
+        assert_eq!(store.read("test1")?, Some("test_value3".to_string()));
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup() -> Result<(), StorageError> {
+        let (backup_path, dek_path) = temp_backup();
+        let password = Secret::from("password");
+        let (_, _, store) = create_path_and_storage(false)?;
+        store.write("test1", "test_value1")?;
+        store.write("test2", "test_value2")?;
+        store.backup(&backup_path, &dek_path, password)?;
+        assert!(backup_path.exists());
+        assert!(dek_path.exists());
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_files_are_private() -> Result<(), StorageError> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (backup_path, dek_path) = temp_backup();
+        fs::write(&dek_path, "")?;
+        fs::set_permissions(&dek_path, fs::Permissions::from_mode(0o644))?;
+        let (_guard, store) = Storage::open_temp()?;
+        store.write("test1", "test_value1")?;
+        store.backup(&backup_path, &dek_path, Secret::from("password"))?;
+
+        let mode = |path: &PathBuf| -> Result<u32, StorageError> {
+            Ok(fs::metadata(path)?.permissions().mode() & 0o777)
+        };
+        assert_eq!(mode(&dek_path)?, 0o600);
+        assert_eq!(mode(&backup_path)?, 0o600);
+
+        store.change_backup_password(
+            &dek_path,
+            Secret::from("password"),
+            Secret::from("new_password"),
+        )?;
+        assert_eq!(mode(&dek_path)?, 0o600);
+
+        fs::remove_file(backup_path)?;
+        fs::remove_file(dek_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_split() -> Result<(), StorageError> {
+        let (backup_dir, dek_path) = temp_backup();
+        let password = Secret::from("password");
+        let (_, config, store) = create_path_and_storage(false)?;
+        for i in 0..50 {
+            store.write(&format!("key{}", i), &format!("value{}", i))?;
+        }
+
+        // Leftover parts of a longer backup must not be read as part of the new one.
+        fs::create_dir_all(&backup_dir)?;
+        for index in 0..100 {
+            fs::write(backup_dir.join(format!("backup.{:03}", index)), "stale")?;
+        }
+        let parts = store.backup_split(&backup_dir, 256, &dek_path, password.clone())?;
+        assert!(parts > 1);
+        for index in 0..parts {
+            let part = backup_dir.join(format!("backup.{:03}", index));
+            assert!(fs::metadata(part)?.len() <= 256);
+        }
+        assert!(!backup_dir.join(format!("backup.{:03}", parts)).exists());
+
+        let stats = Storage::verify_backup(&backup_dir, &dek_path, password.clone())?;
+        assert_eq!(stats.entries, 50);
+
+        Storage::delete_db_files(store)?;
+        let store = Storage::new(&config)?;
+        store.restore_backup(&backup_dir, &dek_path, password.clone(), RestoreMode::Merge)?;
+        assert_eq!(store.count(true)?, 50);
+        assert_eq!(store.read("key49")?, Some("value49".to_string()));
+
+        assert!(matches!(
+            store.backup_split(&backup_dir, 0, &dek_path, password),
+            Err(StorageError::InvalidConfig(_))
+        ));
+
+        Storage::delete_db_files(store)?;
+        fs::remove_dir_all(backup_dir)?;
+        fs::remove_file(dek_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_backup() -> Result<(), StorageError> {
+        let (backup_path, dek_path) = temp_backup();
+        let password = Secret::from("password");
+        let (_, config, store) = create_path_and_storage(false)?;
+        store.write("test1", "test_value1")?;
+        store.write("test2", "test_value2")?;
+        store.backup(&backup_path, &dek_path, password.clone())?;
+
+        Storage::delete_db_files(store)?;
+        let store = Storage::new(&config)?;
+        let report = store.restore_backup(&backup_path, &dek_path, password, RestoreMode::Merge)?;
+
+        assert_eq!(
+            report,
+            RestoreReport {
+                entries_restored: 2,
+                bytes_read: 2 * ("test1".len() + "test_value1".len()) as u64,
+            }
+        );
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+        assert_eq!(store.read("test2")?, Some("test_value2".to_string()));
+
+        Storage::delete_db_files(store)?;
+        fs::remove_file(backup_path)?;
+        fs::remove_file(dek_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_more_than_1000_values_to_backup() -> Result<(), StorageError> {
+        let quantity = 1500;
+        let (backup_path, dek_path) = temp_backup();
+        let password = Secret::from("password");
+        let (_, config, store) = create_path_and_storage(false)?;
+        for i in 0..quantity {
+            store.write(&format!("test{}", i), &format!("test_value{}", i))?;
+        }
+        store.backup(&backup_path, &dek_path, password.clone())?;
+        assert!(backup_path.exists());
+
+        Storage::delete_db_files(store)?;
+
+        let store = Storage::new(&config)?;
+        store.restore_backup(&backup_path, &dek_path, password, RestoreMode::Merge)?;
+
+        for i in 0..quantity {
+            assert_eq!(
+                store.read(&format!("test{}", i))?,
+                Some(format!("test_value{}", i).to_string())
+            );
+        }
+
+        Storage::delete_db_files(store)?;
+        fs::remove_file(backup_path)?;
+        fs::remove_file(dek_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_from_reader_in_batches() -> Result<(), StorageError> {
+        let quantity = 25;
+        let (backup_path, dek_path) = temp_backup();
+        let password = Secret::from("password");
+        let (_, config, store) = create_path_and_storage(false)?;
+        for i in 0..quantity {
+            store.write(&format!("test{}", i), &format!("test_value{}", i))?;
+        }
+        store.backup(&backup_path, &dek_path, password.clone())?;
+        Storage::delete_db_files(store)?;
+
+        let (_, encrypted_dek) = read_dek_file(&dek_path)?;
+        let dek = unwrap_dek(encrypted_dek, &password, None)?.to_vec();
+        let backup = Cursor::new(fs::read(&backup_path)?);
+
+        let store = Storage::new(&config)?;
+        store.restore_from(backup, dek, RestoreMode::Merge, Some(10))?;
+
+        for i in 0..quantity {
+            assert_eq!(
+                store.read(&format!("test{}", i))?,
+                Some(format!("test_value{}", i))
+            );
+        }
+
+        Storage::delete_db_files(store)?;
+        fs::remove_file(backup_path)?;
+        fs::remove_file(dek_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_values_with_delimiters() -> Result<(), StorageError> {
+        let (backup_path, dek_path) = temp_backup();
+        let password = Secret::from("password");
+        let (_, config, store) = create_path_and_storage(false)?;
+        store.write("key,with;delimiters", "value;with,delimiters")?;
+        store.backup(&backup_path, &dek_path, password.clone())?;
+        Storage::delete_db_files(store)?;
+
+        let store = Storage::new(&config)?;
+        store.restore_backup(&backup_path, &dek_path, password, RestoreMode::Merge)?;
+        assert_eq!(
+            store.read("key,with;delimiters")?,
+            Some("value;with,delimiters".to_string())
+        );
+
+        Storage::delete_db_files(store)?;
+        fs::remove_file(backup_path)?;
+        fs::remove_file(dek_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_legacy_hex_backup() -> Result<(), StorageError> {
+        for encrypted in [false, true] {
+            let (_, _, store) = create_path_and_storage(encrypted)?;
+            // Legacy backups hold the values as stored, encrypted in an encrypted storage.
+            let mut records = String::new();
+            for (key, value) in [("test1", "test_value1"), ("test2", "test_value2")] {
+                store.write(key, value)?;
+                let stored = store.db.get(key)?.unwrap();
+                records.push_str(&format!("{},{};", hex::encode(key), hex::encode(stored)));
+                store.delete(key)?;
+            }
+
+            let dek = vec![7u8; 32];
+            let passphrase = age::secrecy::SecretString::new(hex::encode(&dek).into());
+            let mut legacy_backup = Vec::new();
+            let mut writer =
+                age::Encryptor::with_user_passphrase(passphrase).wrap_output(&mut legacy_backup)?;
+            writer.write_all(records.as_bytes())?;
+            writer.finish()?;
+
+            store.restore_from(Cursor::new(legacy_backup), dek, RestoreMode::Merge, None)?;
+
+            assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+            assert_eq!(store.read("test2")?, Some("test_value2".to_string()));
+            Storage::delete_db_files(store)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_backup() -> Result<(), StorageError> {
+        let (backup_path, dek_path) = temp_backup();
+        let password = Secret::from("password");
+        let (_guard, store) = Storage::open_temp()?;
+        store.write("test1", "test_value1")?;
+        store.write("test2", "test_value2")?;
+        store.backup(&backup_path, &dek_path, password.clone())?;
+
+        let stats = Storage::verify_backup(&backup_path, &dek_path, password)?;
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.bytes, 32);
+
+        let result = Storage::verify_backup(&backup_path, &dek_path, Secret::from("wrong"));
+        assert!(matches!(result, Err(StorageError::WrongPassword)));
+
+        fs::remove_file(backup_path)?;
+        fs::remove_file(dek_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_consistent_backup() -> Result<(), StorageError> {
+        let (backup_path, dek_path) = temp_backup();
+        let password = Secret::from("ABC!@#123pass".to_string());
+        let backup_password = Secret::from("XYZ$%^456word".to_string());
+        let mut config = StorageConfig::new(
+            temp_storage().to_string_lossy().to_string(),
+            Some(password.clone()),
+        );
+        config.backup_batch_size = Some(1);
+        let store = Storage::new(&config)?;
+        for i in 0..3 {
+            store.write(&format!("test{}", i), &format!("value{}", i))?;
+        }
+        let sequence = store.current_sequence();
+
+        // Writes and a DEK rotation made while the backup runs must not reach it.
+        let progress = |backed_up: u64| {
+            if backed_up == 1 {
+                store.write("test3", "value3").unwrap();
+                store.delete("test2").unwrap();
+                store.write("test0", "changed").unwrap();
+                store.rotate_dek(password.clone()).unwrap();
+            }
+        };
+        let stats = store.consistent_backup(
+            &backup_path,
+            &dek_path,
+            backup_password.clone(),
+            Some(&progress),
+        )?;
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.sequence, Some(sequence));
+        assert_eq!(
+            Storage::verify_backup(&backup_path, &dek_path, backup_password.clone())?,
+            stats
+        );
+        assert_eq!(store.read("test0")?, Some("changed".to_string()));
+        Storage::delete_db_files(store)?;
+
+        let store = Storage::new(&config)?;
+        store.restore_backup(&backup_path, &dek_path, backup_password, RestoreMode::Merge)?;
+        assert_eq!(store.read("test0")?, Some("value0".to_string()));
+        assert_eq!(store.read("test2")?, Some("value2".to_string()));
+        assert_eq!(store.read("test3")?, None);
+
+        Storage::delete_db_files(store)?;
+        fs::remove_file(backup_path)?;
+        fs::remove_file(dek_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_manifest() -> Result<(), StorageError> {
+        let (backup_path, dek_path) = temp_backup();
+        let password = Secret::from("password");
+        let (_, _, store) = create_path_and_storage(false)?;
+        store.write("test1", "test_value1")?;
+
+        let before = unix_millis(SystemTime::now());
+        store.backup(&backup_path, &dek_path, password.clone())?;
+        let after = unix_millis(SystemTime::now());
+        Storage::delete_db_files(store)?;
+
+        let stats = Storage::verify_backup(&backup_path, &dek_path, password)?;
+        assert_eq!(stats.entries, 1);
+        assert!(stats
+            .created_at
+            .is_some_and(|created_at| (before..=after).contains(&created_at)));
+
+        // Backups written before the manifest was added are still accepted.
+        let dek = vec![7u8; 32];
+        let passphrase = age::secrecy::SecretString::new(hex::encode(&dek).into());
+        let mut old_backup = Vec::new();
+        let mut writer =
+            age::Encryptor::with_user_passphrase(passphrase).wrap_output(&mut old_backup)?;
+        writer.write_all(&[BACKUP_FORMAT_VERSION_WITHOUT_MANIFEST])?;
+        for field in ["test1", "test_value1"] {
+            writer.write_all(&(field.len() as u32).to_le_bytes())?;
+            writer.write_all(field.as_bytes())?;
+        }
+        writer.finish()?;
+
+        let (_, _, store) = create_path_and_storage(false)?;
+        store.restore_from(Cursor::new(old_backup), dek, RestoreMode::Merge, None)?;
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+
+        Storage::delete_db_files(store)?;
+        fs::remove_file(backup_path)?;
+        fs::remove_file(dek_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_tampered_backup() -> Result<(), StorageError> {
+        let dek = vec![7u8; 32];
+
+        // A record slipped in without going through `write_record` is missing from the count.
+        let mut tampered_backup = BackupFileWriter::new(Vec::new(), &dek, None, 0, &[0; 16])?;
+        tampered_backup.write_record(b"test1", b"test_value1")?;
+        for field in ["test2", "test_value2"] {
+            tampered_backup.write_all(&(field.len() as u32).to_le_bytes())?;
+            tampered_backup.write_all(field.as_bytes())?;
+        }
+        let tampered_backup = tampered_backup.finish()?;
+
+        // A backup cut short after a whole record lacks the trailer.
+        let passphrase = age::secrecy::SecretString::new(hex::encode(&dek).into());
+        let mut truncated_backup = Vec::new();
+        let mut writer =
+            age::Encryptor::with_user_passphrase(passphrase).wrap_output(&mut truncated_backup)?;
+        writer.write_all(&[BACKUP_FORMAT_VERSION])?;
+        writer.write_all(&0u64.to_le_bytes())?;
+        writer.write_all(&0u64.to_le_bytes())?;
+        for field in ["test1", "test_value1"] {
+            writer.write_all(&(field.len() as u32).to_le_bytes())?;
+            writer.write_all(field.as_bytes())?;
+        }
+        writer.finish()?;
+
+        let (_guard, store) = Storage::open_temp()?;
+        for backup in [tampered_backup, truncated_backup] {
+            let result =
+                store.restore_from(Cursor::new(backup), dek.clone(), RestoreMode::Merge, None);
+            assert!(matches!(result, Err(StorageError::CorruptBackup(_))));
+            assert_eq!(store.read("test1")?, None);
+        }
+
+        Ok(())
+    }
+
+    /// Fails every read after the first `remaining` bytes, like a process killed mid-restore.
+    struct InterruptedReader<R> {
+        inner: R,
+        remaining: usize,
+    }
+
+    impl<R: Read> Read for InterruptedReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(std::io::Error::other("restore interrupted"));
+            }
+            let len = buf.len().min(self.remaining);
+            let read = self.inner.read(&mut buf[..len])?;
+            self.remaining -= read;
+            Ok(read)
+        }
+    }
+
+    #[test]
+    fn test_resume_interrupted_restore() -> Result<(), StorageError> {
+        let (backup_path, dek_path) = temp_backup();
+        let password = Secret::from("password".to_string());
+        let (_, _, store) = create_path_and_storage(false)?;
+        for i in 0..200 {
+            store.write(&format!("test{:03}", i), &"x".repeat(1024))?;
+        }
+        store.backup(&backup_path, &dek_path, password.clone())?;
+        Storage::delete_db_files(store)?;
+        let (_, encrypted_dek) = read_dek_file(&dek_path)?;
+        let dek = unwrap_dek(encrypted_dek, &password, None)?;
+
+        let (_, _, store) = create_path_and_storage(false)?;
+        let interrupted = InterruptedReader {
+            inner: File::open(&backup_path)?,
+            remaining: fs::metadata(&backup_path)?.len() as usize / 2,
+        };
+        assert!(store
+            .restore_from(interrupted, dek.to_vec(), RestoreMode::Replace, Some(10))
+            .is_err());
+        assert!(store.restore_checkpoint()?.is_some());
+        assert_eq!(store.read("test000")?, Some("x".repeat(1024)));
+        assert_eq!(store.read("test199")?, None);
+
+        // Entries before the checkpoint are not written again.
+        store.write("test000", "changed")?;
+        store.restore_from(
+            File::open(&backup_path)?,
+            dek.to_vec(),
+            RestoreMode::Replace,
+            Some(10),
+        )?;
+        assert_eq!(store.read("test000")?, Some("changed".to_string()));
+        assert_eq!(store.read("test199")?, Some("x".repeat(1024)));
+        assert_eq!(store.restore_checkpoint()?, None);
+
+        Storage::delete_db_files(store)?;
+        fs::remove_file(backup_path)?;
+        fs::remove_file(dek_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_dek_mismatch() -> Result<(), StorageError> {
+        let (backup_path, dek_path) = temp_backup();
+        let (other_backup_path, other_dek_path) = temp_backup();
+        let password = Secret::from("password".to_string());
+        let (_guard, store) = Storage::open_temp()?;
+        store.write("test1", "test_value1")?;
+        store.backup(&backup_path, &dek_path, password.clone())?;
+        store.backup(&other_backup_path, &other_dek_path, password.clone())?;
+
+        let result = Storage::verify_backup(&backup_path, &other_dek_path, password.clone());
+        assert!(matches!(result, Err(StorageError::BackupDekMismatch)));
+        let result = store.restore_backup(
+            &backup_path,
+            &other_dek_path,
+            password.clone(),
+            RestoreMode::Merge,
+        );
+        assert!(matches!(result, Err(StorageError::BackupDekMismatch)));
+
+        // Changing the password keeps the DEK file paired with its backup.
+        store.change_backup_password(&dek_path, password, Secret::from("other".to_string()))?;
+        let stats =
+            Storage::verify_backup(&backup_path, &dek_path, Secret::from("other".to_string()))?;
+        assert_eq!(stats.entries, 1);
+
+        for path in [backup_path, dek_path, other_backup_path, other_dek_path] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_checkpoint_key_is_reserved() -> Result<(), StorageError> {
+        let (backup_path, dek_path) = temp_backup();
+        let password = Secret::from("password".to_string());
+        let (_, config, store) = create_path_and_storage(false)?;
+        for i in 0..20 {
+            store.write(&format!("test{}", i), &format!("test_value{}", i))?;
+        }
+        // The key of the checkpoint before it was reserved is an ordinary user key now.
+        store.write(LEGACY_RESTORE_CHECKPOINT_KEY, "test_value")?;
+        store.backup(&backup_path, &dek_path, password.clone())?;
+
+        let (_, encrypted_dek) = read_dek_file(&dek_path)?;
+        let dek = unwrap_dek(encrypted_dek, &password, None)?;
+        store.restore_from(
+            File::open(&backup_path)?,
+            dek.to_vec(),
+            RestoreMode::Replace,
+            Some(5),
+        )?;
+        assert_eq!(
+            store.read(LEGACY_RESTORE_CHECKPOINT_KEY)?,
+            Some("test_value".to_string())
+        );
+        assert_eq!(store.restore_checkpoint()?, None);
+
+        // A checkpoint left under the legacy key by an interrupted restore is migrated.
+        store.db.put(LEGACY_RESTORE_CHECKPOINT_KEY, "5:10")?;
+        drop(store);
+        let store = Storage::new(&config)?;
+        assert_eq!(store.restore_checkpoint()?, Some((5, 10)));
+        assert_eq!(store.read(LEGACY_RESTORE_CHECKPOINT_KEY)?, None);
+        assert!(matches!(
+            store.write(RESTORE_CHECKPOINT_KEY, "test_value"),
+            Err(StorageError::ReservedKey(_))
+        ));
+
+        Storage::delete_db_files(store)?;
+        fs::remove_file(backup_path)?;
+        fs::remove_file(dek_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_truncated_backup() -> Result<(), StorageError> {
+        let (backup_path, dek_path) = temp_backup();
+        let password: Secret<String> = Secret::from("password".to_string());
+        let dek = vec![7u8; 32];
+
+        let mut encrypted_dek = Vec::new();
+        Cocoon::new(password.expose_secret().as_bytes())
+            .dump(dek.clone(), &mut encrypted_dek)
+            .map_err(|error| StorageError::FailedToEncryptData { error })?;
+        fs::write(&dek_path, encrypted_dek)?;
+
+        let mut backup_writer =
+            BackupFileWriter::new(File::create(&backup_path)?, &dek, None, 0, &[0; 16])?;
+        backup_writer.write_record(b"test1", b"test_value1")?;
+        backup_writer.write_all(&5u32.to_le_bytes())?;
+        backup_writer.write_all(b"te")?;
+        backup_writer.finish()?;
+
+        let result = Storage::verify_backup(&backup_path, &dek_path, password);
+        assert!(matches!(result, Err(StorageError::CorruptBackup(_))));
+
+        fs::remove_file(backup_path)?;
+        fs::remove_file(dek_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_active_transactions() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        assert_eq!(store.active_transaction_count(), 0);
+
+        let first = store.begin_transaction();
+        let second = store.begin_transaction();
+        let mut ids = store.active_transaction_ids();
+        ids.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(ids, expected);
+        assert_eq!(store.active_transaction_count(), 2);
+
+        store.commit_transaction(first)?;
+        assert_eq!(store.active_transaction_ids(), vec![second]);
+        store.rollback_transaction(second)?;
+        assert_eq!(store.active_transaction_count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback_stale_transactions() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        let stale = store.begin_transaction();
+        store.transactional_write("stale", "value", stale)?;
+        std::thread::sleep(Duration::from_millis(50));
+        let fresh = store.begin_transaction();
+
+        assert_eq!(
+            store.rollback_stale_transactions(Duration::from_millis(25)),
+            1
+        );
+        assert_eq!(store.active_transaction_ids(), vec![fresh]);
+        assert!(matches!(
+            store.commit_transaction(stale),
+            Err(StorageError::NotFound { .. })
+        ));
+        assert_eq!(store.read("stale")?, None);
+
+        // The rolled back transaction no longer holds its lock.
+        store.write("stale", "value")?;
+        store.commit_transaction(fresh)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_retry() -> Result<(), StorageError> {
+        let (_guard, store) = Storage::open_temp()?;
+        let blocker = store.begin_transaction();
+        store.transactional_write("key", "first", blocker)?;
+
+        // The blocking transaction holds the lock on `key` until it commits.
+        let conflicting = store.begin_transaction();
+        assert!(matches!(
+            store.transactional_write("key", "second", conflicting),
+            Err(StorageError::Conflict(_))
+        ));
+        store.rollback_transaction(conflicting)?;
+
+        let attempts = Cell::new(0);
+        let result = store.with_retry(2, |transaction_id| {
+            attempts.set(attempts.get() + 1);
+            store.transactional_write("key", "second", transaction_id)
+        });
+        assert!(matches!(result, Err(StorageError::Conflict(_))));
+        assert_eq!(attempts.get(), 2);
+
+        attempts.set(0);
+        store.with_retry(3, |transaction_id| {
+            attempts.set(attempts.get() + 1);
+            let result = store.transactional_write("key", "second", transaction_id);
+            if attempts.get() == 1 {
+                store.commit_transaction(blocker)?;
+            }
+            result
+        })?;
+        assert_eq!(attempts.get(), 2);
+        assert_eq!(store.read("key")?, Some("second".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_with_retry() -> Result<(), StorageError> {
+        let (_, config, store) = create_path_and_storage(false)?;
+        store.write("test1", "test_value1")?;
+        assert!(matches!(
+            Storage::open(&config),
+            Err(StorageError::Locked(_))
+        ));
+        assert!(matches!(
+            Storage::open_with_retry(&config, 3, Duration::from_millis(10)),
+            Err(StorageError::Locked(_))
+        ));
+        drop(store);
+
+        let store = Storage::open_with_retry(&config, 3, Duration::from_millis(10))?;
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_creates_parent_dirs() -> Result<(), StorageError> {
+        let root = env::temp_dir().join(format!("storage_{}", Uuid::new_v4()));
+        let path = root.join("some/missing/dir/store.db");
+        let config = StorageConfig::new(path.to_string_lossy().to_string(), None);
+        let store = Storage::new(&config)?;
+        store.write("test1", "test_value1")?;
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+        drop(store);
+
+        let file = root.join("file");
+        fs::write(&file, "not a storage")?;
+        let config = StorageConfig::new(file.join("store.db").to_string_lossy().to_string(), None);
+        assert!(matches!(
+            Storage::new(&config),
+            Err(StorageError::InvalidPath { .. })
+        ));
+        let config = StorageConfig::new(file.to_string_lossy().to_string(), None);
+        assert!(matches!(
+            Storage::new(&config),
+            Err(StorageError::InvalidPath { .. })
+        ));
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_temp() -> Result<(), StorageError> {
+        let (guard, store) = Storage::open_temp()?;
+        store.write("test1", "test_value1")?;
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+        let path = guard.path().to_path_buf();
+        assert!(path.exists());
+
+        drop(store);
+        drop(guard);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_value_compression() -> Result<(), StorageError> {
+        let value = r#"{"proof":"00000000000000000000000000000000"}"#.repeat(100);
+        for compression in [ValueCompression::Lz4, ValueCompression::Zstd] {
+            let path = temp_storage().to_string_lossy().to_string();
+            let mut config =
+                StorageConfig::new(path, Some(Secret::from("ABC!@#123pass".to_string())));
+            let store = Storage::new(&config)?;
+            store.write("uncompressed", &value)?;
+            drop(store);
+
+            config.compression = compression;
+            let store = Storage::open(&config)?;
+            store.write("compressed", &value)?;
+            store.write("short", "v")?;
+            store.write_with_ttl("ttl", &value, Duration::from_secs(3600), None)?;
+
+            let stored_len = |key: &str| store.db.get(key).unwrap().unwrap().len();
+            assert!(stored_len("compressed") < stored_len("uncompressed") / 2);
+            for key in ["uncompressed", "compressed", "ttl"] {
+                assert_eq!(store.read(key)?, Some(value.clone()));
+            }
+            assert_eq!(store.read("short")?, Some("v".to_string()));
+
+            Storage::delete_db_files(store)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_existing() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.write("proof/1", "first")?;
+        store.write("proof/3", "third")?;
+        store.write_with_ttl("proof/4", "expired", Duration::ZERO, None)?;
+
+        let existing = store.get_existing(&["proof/1", "proof/2", "proof/3", "proof/4"])?;
+        assert_eq!(
+            existing,
+            HashMap::from([
+                ("proof/1".to_string(), "first".to_string()),
+                ("proof/3".to_string(), "third".to_string()),
+            ])
+        );
+        assert!(store.get_existing(&[])?.is_empty());
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_compare_lenient() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        for i in 0..3 {
+            store.write(&format!("page/{}", i), &format!("value{}", i))?;
+        }
+        store
+            .db
+            .put(store.encode_key(b"page/1"), b"not a ciphertext")
+            .map_err(StorageError::WriteError)?;
+
+        let error = store.partial_compare("page/").unwrap_err();
+        assert!(matches!(error, StorageError::FailedToDecryptData { .. }));
+        assert!(error.to_string().starts_with("Failed to decrypt data: "));
+        let salvaged = store.partial_compare_lenient("page/")?;
+        assert_eq!(
+            salvaged.entries,
+            vec![
+                ("page/0".to_string(), "value0".to_string()),
+                ("page/2".to_string(), "value2".to_string()),
+            ]
+        );
+        assert_eq!(salvaged.failed_keys, vec!["page/1".to_string()]);
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_compare_map() -> Result<(), StorageError> {
+        for encrypt_keys in [false, true] {
+            let path = temp_storage().to_string_lossy().to_string();
+            let mut config =
+                StorageConfig::new(path, Some(Secret::from("ABC!@#123pass".to_string())));
+            config.encrypt_keys = encrypt_keys;
+            let store = Storage::new(&config)?;
+            for i in 0..20 {
+                store.write(&format!("job/{:02}", i), &format!("value{}", i))?;
+            }
+            store.write("other", "value")?;
+
+            let map = store.partial_compare_map("job/")?;
+            assert_eq!(map.len(), 20);
+            assert_eq!(map.get("job/07"), Some(&"value7".to_string()));
+            assert!(map.keys().is_sorted());
+            assert!(!map.contains_key("other"));
+
+            Storage::delete_db_files(store)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_compare_limited() -> Result<(), StorageError> {
+        for encrypt_keys in [false, true] {
+            let path = temp_storage().to_string_lossy().to_string();
+            let mut config =
+                StorageConfig::new(path, Some(Secret::from("ABC!@#123pass".to_string())));
+            config.encrypt_keys = encrypt_keys;
+            let store = Storage::new(&config)?;
+            for i in 0..25 {
+                store.write(&format!("page/{:02}", i), &format!("value{}", i))?;
+            }
+            store.write("other", "value")?;
+
+            let mut pages = Vec::new();
+            let mut cursor: Option<String> = None;
+            loop {
+                let page = store.partial_compare_limited("page/", cursor.as_deref(), 10)?;
+                let Some((last, _)) = page.last() else {
+                    break;
+                };
+                cursor = Some(last.clone());
+                pages.push(page);
+            }
+            assert_eq!(
+                pages.iter().map(Vec::len).collect::<Vec<_>>(),
+                vec![10, 10, 5]
+            );
+            assert_eq!(pages[1][0], ("page/10".to_string(), "value10".to_string()));
+            assert_eq!(pages.concat(), store.partial_compare("page/")?);
+
+            let page = store.partial_compare_limited("page/", Some("page/05a"), 2)?;
+            assert_eq!(
+                page.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>(),
+                vec!["page/06", "page/07"]
+            );
+            assert!(store
+                .partial_compare_limited("page/", Some("zzz"), 10)?
+                .is_empty());
+
+            Storage::delete_db_files(store)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_value_bytes() -> Result<(), StorageError> {
+        let path = temp_storage().to_string_lossy().to_string();
+        let mut config = StorageConfig::new(path, Some(Secret::from("ABC!@#123pass".to_string())));
+        config.max_value_bytes = Some(1024);
+        let store = Storage::new(&config)?;
+
+        let largest_value = "v".repeat(1024);
+        store.write("test1", &largest_value)?;
+        assert_eq!(store.read("test1")?, Some(largest_value.clone()));
+        store.write_with_ttl("test2", &largest_value, Duration::from_secs(60), None)?;
+
+        let oversized_value = "v".repeat(1025);
+        assert!(matches!(
+            store.write("test3", &oversized_value),
+            Err(StorageError::ValueTooLarge {
+                size: 1025,
+                limit: 1024
+            })
+        ));
+        assert!(matches!(
+            store.set("test3", &oversized_value, None),
+            Err(StorageError::ValueTooLarge { .. })
+        ));
+        assert!(!store.has_key("test3")?);
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_keys() -> Result<(), StorageError> {
+        let path = temp_storage().to_string_lossy().to_string();
+        let mut config = StorageConfig::new(path, None);
+        config.max_key_length = Some(8 * 1024);
+        let store = Storage::new(&config)?;
+
+        assert!(matches!(
+            store.write("", "value"),
+            Err(StorageError::InvalidKey(_))
+        ));
+        let tx = store.begin_transaction();
+        assert!(matches!(
+            store.transactional_write("", "value", tx),
+            Err(StorageError::InvalidKey(_))
+        ));
+        store.rollback_transaction(tx)?;
+        assert!(matches!(
+            store.set("", "value", None),
+            Err(StorageError::InvalidKey(_))
+        ));
+
+        let longest_key = "k".repeat(8 * 1024);
+        store.write(&longest_key, "value")?;
+        assert_eq!(store.read(&longest_key)?, Some("value".to_string()));
+        let oversized_key = "k".repeat(8 * 1024 + 1);
+        assert!(matches!(
+            store.write(&oversized_key, "value"),
+            Err(StorageError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            store.rename(&longest_key, &oversized_key, None),
+            Err(StorageError::InvalidKey(_))
+        ));
+        assert!(!store.has_key(&oversized_key)?);
+
+        Storage::delete_db_files(store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics() -> Result<(), StorageError> {
+        let path = temp_storage().to_string_lossy().to_string();
+        let mut config = StorageConfig::new(path, None);
+        config.metrics = true;
+        config.cache_capacity = Some(10);
+        let store = Storage::new(&config)?;
+
+        store.write("key", "value")?;
+        store.read("key")?;
+        store.read("key")?;
+        store.delete("key")?;
+        let tx = store.begin_transaction();
+        store.transactional_write("other", "value", tx)?;
+        store.commit_transaction(tx)?;
+        let tx = store.begin_transaction();
+        store.rollback_transaction(tx)?;
+
+        let metrics = store.metrics();
+        assert_eq!(
+            metrics,
+            StorageMetrics {
+                reads: 2,
+                writes: 2,
+                deletes: 1,
+                cache_hits: 1,
+                commits: 1,
+                rollbacks: 1,
+            }
+        );
+        let rendered = metrics.render_prometheus();
+        assert!(rendered.contains("# TYPE storage_reads_total counter\nstorage_reads_total 2\n"));
+        assert!(rendered.contains("storage_cache_hits_total 1\n"));
+
+        Storage::delete_db_files(store)?;
 
+        let (_, _, store) = create_path_and_storage(false)?;
+        store.write("key", "value")?;
+        assert_eq!(store.metrics(), StorageMetrics::default());
+        Storage::delete_db_files(store)?;
         Ok(())
     }
 
-    pub fn rollback_transaction(&self, transaction_id: Uuid) -> Result<(), StorageError> {
-        let mut map = self.transactions.borrow_mut();
-        map.remove(&transaction_id)
-            .ok_or(StorageError::NotFound("Transaction".to_string()))?;
-        Ok(())
-    }
+    #[test]
+    fn test_export_and_import_portable() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.write("plain", "value")?;
+        store.write_bytes("binary", vec![0xff, 0x00, 0xfe, 0x80], None)?;
+        store.write_with_ttl("expiring", "value", Duration::from_secs(3600), None)?;
 
-    fn encrypt_data(&self, data: Vec<u8>) -> Result<Vec<u8>, StorageError> {
-        let mut entry_cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
-        let mut cocoon = Cocoon::new(self.password.as_ref().unwrap());
-        cocoon
-            .dump(data, &mut entry_cursor)
-            .map_err(|error| StorageError::FailedToEncryptData { error })?;
-        Ok(entry_cursor.into_inner())
-    }
+        let mut export = Vec::new();
+        assert_eq!(store.export_portable(&mut export)?, 3);
 
-    fn decrypt_data(&self, data: Vec<u8>) -> Result<Vec<u8>, StorageError> {
-        let mut entry_cursor = Cursor::new(data);
+        let (_, _, other) = create_path_and_storage(true)?;
+        assert_eq!(other.import_portable(export.as_slice())?, 3);
+        for key in ["plain", "binary", "expiring"] {
+            assert_eq!(other.read_bytes(key)?, store.read_bytes(key)?);
+        }
+        let stored = other.db.get(other.encode_key(b"expiring"))?.unwrap();
+        assert!(has_expiry(&other.unseal_value(b"expiring", stored)?));
 
-        let cocoon = Cocoon::new(self.password.as_ref().unwrap());
-        cocoon
-            .parse(&mut entry_cursor)
-            .map_err(|error| StorageError::FailedToDecryptData { error })
-    }
-}
+        let (_, _, empty) = create_path_and_storage(false)?;
+        assert!(matches!(
+            empty.import_portable(&export[..export.len() - 1]),
+            Err(StorageError::InvalidExport { record: 3, .. })
+        ));
+        assert!(matches!(
+            empty.import_portable(b"key,value\n".as_slice()),
+            Err(StorageError::InvalidExport { record: 0, .. })
+        ));
+        assert_eq!(empty.count(true)?, 0);
 
-impl KeyValueStore for Storage {
-    fn get<K, V>(&self, key: K) -> Result<Option<V>, StorageError>
-    where
-        K: AsRef<str>,
-        V: DeserializeOwned,
-    {
-        let key = key.as_ref();
-        let value = self.read(key)?;
+        Storage::delete_db_files(store)?;
+        Storage::delete_db_files(other)?;
+        Storage::delete_db_files(empty)?;
+        Ok(())
+    }
 
-        match value {
-            Some(value) => {
-                let value =
-                    serde_json::from_str(&value).map_err(|_| StorageError::ConversionError)?;
-                Ok(Some(value))
-            }
-            None => Ok(None),
+    #[test]
+    fn test_export_and_import_csv() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.write("plain", "value")?;
+        store.write("comma", "a,b")?;
+        store.write("quote", "say \"hi\"")?;
+        store.write("multi\nline", "first\r\nsecond")?;
+
+        let mut csv = Vec::new();
+        assert_eq!(store.export_csv(&mut csv)?, 4);
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("key,value\r\n"));
+        assert!(csv.contains("comma,\"a,b\"\r\n"));
+        assert!(csv.contains("quote,\"say \"\"hi\"\"\"\r\n"));
+
+        let (_, _, other) = create_path_and_storage(false)?;
+        assert_eq!(other.import_csv(csv.as_bytes())?, 4);
+        for key in ["plain", "comma", "quote", "multi\nline"] {
+            assert_eq!(other.read(key)?, store.read(key)?);
         }
-    }
 
-    fn set<K, V>(&self, key: K, value: V, transaction_id: Option<Uuid>) -> Result<(), StorageError>
-    where
-        K: AsRef<str>,
-        V: Serialize,
-    {
-        let key = key.as_ref();
-        let value = serde_json::to_string(&value).map_err(|_| StorageError::ConversionError)?;
+        let malformed = "key,value\nnew,value\nbroken,\"unterminated\n";
+        assert!(matches!(
+            other.import_csv(malformed.as_bytes()),
+            Err(StorageError::InvalidCsv { line: 3, .. })
+        ));
+        assert_eq!(other.read("new")?, None);
 
-        match transaction_id {
-            Some(id) => Ok(self.transactional_write(key, &value, id)?),
-            None => Ok(self.write(key, &value)?),
-        }
+        Storage::delete_db_files(store)?;
+        Storage::delete_db_files(other)?;
+        Ok(())
     }
 
-    fn update<K, V>(
-        &self,
-        id: K,
-        updates: &HashMap<&str, Value>,
-        transaction_id: Option<Uuid>,
-    ) -> Result<V, StorageError>
-    where
-        K: AsRef<str> + std::marker::Copy,
-        V: Serialize + DeserializeOwned + Clone,
-    {
-        // 1. Fetch the existing value from the database
-        let value: Option<V> = self.get(id)?;
-
-        if let Some(value) = value {
-            // 2. Convert the existing value into a JSON object
-            let mut json_value =
-                serde_json::to_value(&value).map_err(|_| StorageError::SerializationError)?;
+    #[test]
+    fn test_backup_batch_size() -> Result<(), StorageError> {
+        let (backup_path, dek_path) = temp_backup();
+        let password = Secret::from("password");
+        let path = temp_storage().to_string_lossy().to_string();
+        let mut config = StorageConfig::new(path, None);
+        config.backup_batch_size = Some(10);
+        let store = Storage::new(&config)?;
 
-            // 3. Apply the updates
-            if let Some(json_object) = json_value.as_object_mut() {
-                for (key, update) in updates {
-                    json_object.insert(key.to_string(), update.clone());
-                }
-            } else {
-                return Err(StorageError::SerializationError);
-            }
+        for i in 0..25 {
+            store.write(&format!("test{}", i), &format!("test_value{}", i))?;
+        }
 
-            // 4. Convert the updated JSON object back to V
-            let updated_value: V =
-                serde_json::from_value(json_value).map_err(|_| StorageError::SerializationError)?;
+        let flushes = RefCell::new(Vec::new());
+        store.backup_with_progress(
+            &backup_path,
+            &dek_path,
+            password,
+            Some(&|count| flushes.borrow_mut().push(count)),
+        )?;
+        assert_eq!(*flushes.borrow(), vec![10, 20, 25]);
 
-            // 5. Save the updated value back to the database
-            self.set(id, updated_value.clone(), transaction_id)?;
+        Storage::delete_db_files(store)?;
+        fs::remove_file(backup_path)?;
+        fs::remove_file(dek_path)?;
+        Ok(())
+    }
 
-            Ok(updated_value)
-        } else {
-            Err(StorageError::NotFound("Value".to_string()))
+    #[test]
+    fn test_backup_and_restore_report_progress() -> Result<(), StorageError> {
+        let quantity = 1500;
+        let (backup_path, dek_path) = temp_backup();
+        let password = Secret::from("password");
+        let (_, config, store) = create_path_and_storage(false)?;
+        for i in 0..quantity {
+            store.write(&format!("test{}", i), &format!("test_value{}", i))?;
         }
-    }
-}
 
-fn create_options() -> rocksdb::Options {
-    let options = rocksdb::Options::default();
-    options
-}
+        let backup_progress = RefCell::new(Vec::new());
+        store.backup_with_progress(
+            &backup_path,
+            &dek_path,
+            password.clone(),
+            Some(&|count| backup_progress.borrow_mut().push(count)),
+        )?;
+        assert_eq!(backup_progress.borrow().len(), 2);
+        assert_eq!(backup_progress.borrow().last(), Some(&quantity));
+        Storage::delete_db_files(store)?;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::storage_config::PasswordPolicyConfig;
-    use rand::{rng, RngCore};
-    use redact::Secret;
-    use std::env;
+        let restore_progress = RefCell::new(Vec::new());
+        let store = Storage::new(&config)?;
+        store.restore_backup_with_progress(
+            &backup_path,
+            &dek_path,
+            password,
+            RestoreMode::Merge,
+            Some(&|count| restore_progress.borrow_mut().push(count)),
+        )?;
+        assert_eq!(*restore_progress.borrow(), vec![1000, quantity]);
 
-    fn temp_storage() -> PathBuf {
-        let dir = env::temp_dir();
-        let mut rang = rng();
-        let index = rang.next_u32();
-        dir.join(format!("storage_{}.db", index))
+        Storage::delete_db_files(store)?;
+        fs::remove_file(backup_path)?;
+        fs::remove_file(dek_path)?;
+        Ok(())
     }
 
-    fn temp_backup() -> (PathBuf, PathBuf) {
-        let dir = env::temp_dir();
-        let mut rang = rng();
-        let index = rang.next_u32();
-        (
-            dir.join(format!("backup_{}", index)),
-            dir.join(format!("dek_{}", index)),
-        )
-    }
+    #[test]
+    fn test_restore_backup_modes() -> Result<(), StorageError> {
+        let (backup_path, dek_path) = temp_backup();
+        let password = Secret::from("password");
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.write("test1", "test_value1")?;
+        store.backup(&backup_path, &dek_path, password.clone())?;
+        store.write("test1", "local_value1")?;
+        store.write("local", "local_value")?;
+
+        store.restore_backup(
+            &backup_path,
+            &dek_path,
+            password.clone(),
+            RestoreMode::Merge,
+        )?;
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+        assert_eq!(store.read("local")?, Some("local_value".to_string()));
 
-    fn create_path_and_storage(
-        is_encrypted: bool,
-    ) -> Result<(PathBuf, StorageConfig, Storage), StorageError> {
-        let path = &temp_storage();
+        store.restore_backup(&backup_path, &dek_path, password, RestoreMode::Replace)?;
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+        assert_eq!(store.read("local")?, None);
+        assert!(store.db.get(DEK_KEY)?.is_some());
 
-        let password = if is_encrypted {
-            Some("password".to_string())
-        } else {
-            None
-        };
+        Storage::delete_db_files(store)?;
+        fs::remove_file(backup_path)?;
+        fs::remove_file(dek_path)?;
+        Ok(())
+    }
 
-        let config = StorageConfig {
-            path: path.to_string_lossy().to_string(),
-            password: password.map(|p| Secret::from(p)),
-        };
+    #[test]
+    fn test_restore_encrypted_backup_with_different_password() -> Result<(), StorageError> {
+        let (backup_path, dek_path) = temp_backup();
+        let password = Secret::from("password");
+        let (_, _, store) = create_path_and_storage(true)?;
+        store.write("test1", "test_value1")?;
+        store.write("test2", "test_value2")?;
+        store.backup(&backup_path, &dek_path, password.clone())?;
+        Storage::delete_db_files(store)?;
 
-        let storage = Storage::new_with_policy(
-            &config,
+        let store = Storage::new_with_policy(
+            &StorageConfig {
+                path: temp_storage().to_string_lossy().to_string(),
+                password: Some(Secret::from("other_password".to_string())),
+                kdf_params: KdfParams::default(),
+                encrypt_keys: false,
+                password_policy: None,
+                rocksdb: None,
+                prefix_length: None,
+                cache_capacity: None,
+                backup_batch_size: None,
+                metrics: false,
+                max_key_length: None,
+                max_value_bytes: None,
+                compression: ValueCompression::None,
+                sync_writes: false,
+                disable_wal: false,
+                merge_operator: None,
+                lossy_utf8: false,
+            },
             Some(PasswordPolicyConfig {
                 min_length: 1,
                 min_number_of_special_chars: 0,
                 min_number_of_uppercase: 0,
                 min_number_of_digits: 0,
+                min_number_of_lowercase: 0,
+                max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+                character_sets: CharacterSets::default(),
             }),
         )?;
+        store.restore_backup(&backup_path, &dek_path, password, RestoreMode::Merge)?;
 
-        Ok((path.clone(), config, storage))
-    }
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+        assert_eq!(store.read("test2")?, Some("test_value2".to_string()));
 
-    #[test]
-    fn test_new_storage_starts_empty() -> Result<(), StorageError> {
-        let (_, _, store) = create_path_and_storage(false)?;
-        assert!(store.is_empty());
         Storage::delete_db_files(store)?;
+        fs::remove_file(backup_path)?;
+        fs::remove_file(dek_path)?;
         Ok(())
     }
 
     #[test]
-    fn test_add_value_to_storage() -> Result<(), StorageError> {
-        let (_, _, store) = create_path_and_storage(false)?;
-        store.write("test", "test_value")?;
-        assert_eq!(store.read("test").unwrap(), Some("test_value".to_string()));
+    fn test_backup_to_recipients() -> Result<(), StorageError> {
+        let (backup_path, _) = temp_backup();
+        let identity = x25519::Identity::generate();
+        let (_, config, store) = create_path_and_storage(false)?;
+        store.write("test1", "test_value1")?;
+        store.backup_to_recipients(&backup_path, vec![identity.to_public()])?;
         Storage::delete_db_files(store)?;
-        Ok(())
-    }
 
-    #[test]
-    fn test_read_a_value() -> Result<(), StorageError> {
-        let (_, _, store) = create_path_and_storage(false)?;
-        store.write("test", "test_value")?;
-        assert_eq!(store.read("test")?, Some("test_value".to_string()));
+        let store = Storage::new(&config)?;
+        let other_identity = x25519::Identity::generate();
+        assert!(matches!(
+            store.restore_backup_with_identity(&backup_path, &other_identity, RestoreMode::Merge),
+            Err(StorageError::BackupDecryptFailed(_))
+        ));
+
+        store.restore_backup_with_identity(&backup_path, &identity, RestoreMode::Merge)?;
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+
         Storage::delete_db_files(store)?;
+        fs::remove_file(backup_path)?;
         Ok(())
     }
 
     #[test]
-    fn test_delete_value() -> Result<(), StorageError> {
-        let (_, _, store) = create_path_and_storage(false)?;
-        store.write("test", "test_value")?;
-        assert_eq!(store.read("test")?, Some("test_value".to_string()));
-        store.delete("test")?;
-        assert_eq!(store.read("test")?, None);
+    fn test_restore_backup_with_identity_file() -> Result<(), StorageError> {
+        use age::secrecy::ExposeSecret;
+
+        let (backup_path, identity_path) = temp_backup();
+        let identity = x25519::Identity::generate();
+        fs::write(&identity_path, identity.to_string().expose_secret())?;
+
+        let (_, config, store) = create_path_and_storage(false)?;
+        store.write("test1", "test_value1")?;
+        store.backup_to_recipients(&backup_path, vec![identity.to_public()])?;
         Storage::delete_db_files(store)?;
+
+        let stats = Storage::verify_backup_with_identity_file(&backup_path, &identity_path)?;
+        assert_eq!(stats.entries, 1);
+
+        let store = Storage::new(&config)?;
+        store.restore_backup_with_identity_file(
+            &backup_path,
+            &identity_path,
+            RestoreMode::Merge,
+        )?;
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+
+        Storage::delete_db_files(store)?;
+        fs::remove_file(backup_path)?;
+        fs::remove_file(identity_path)?;
         Ok(())
     }
 
     #[test]
-    fn test_find_multiple_answers() -> Result<(), StorageError> {
-        let (_, _, store) = create_path_and_storage(false)?;
+    fn test_rotate_dek() -> Result<(), StorageError> {
+        let (_, config, store) = create_path_and_storage(true)?;
+        let password: Secret<String> = Secret::from("password".to_string());
         store.write("test1", "test_value1")?;
         store.write("test2", "test_value2")?;
+        let old_dek = unwrap_dek(store.db.get(DEK_KEY)?.unwrap(), &password, None)?;
+
+        assert!(matches!(
+            store.rotate_dek(Secret::from("wrong_password".to_string())),
+            Err(StorageError::WrongPassword)
+        ));
+        store.rotate_dek(password.clone())?;
+
+        let new_dek = unwrap_dek(store.db.get(DEK_KEY)?.unwrap(), &password, None)?;
+        assert_ne!(old_dek, new_dek);
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
         store.write("test3", "test_value3")?;
-        store.write("tes4", "test_value4")?;
+        drop(store);
 
-        let result = store.partial_compare("test")?;
-        assert_eq!(
-            result,
-            vec![
-                ("test1".to_string(), "test_value1".to_string()),
-                ("test2".to_string(), "test_value2".to_string()),
-                ("test3".to_string(), "test_value3".to_string())
-            ]
-        );
+        let store = Storage::new_with_policy(
+            &config,
+            Some(PasswordPolicyConfig {
+                min_length: 1,
+                min_number_of_special_chars: 0,
+                min_number_of_uppercase: 0,
+                min_number_of_digits: 0,
+                min_number_of_lowercase: 0,
+                max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+                character_sets: CharacterSets::default(),
+            }),
+        )?;
+        assert_eq!(store.read("test2")?, Some("test_value2".to_string()));
+        assert_eq!(store.read("test3")?, Some("test_value3".to_string()));
 
         Storage::delete_db_files(store)?;
         Ok(())
     }
 
     #[test]
-    fn test_has_key() -> Result<(), StorageError> {
-        let (_, _, store) = create_path_and_storage(false)?;
+    fn test_rotate_dek_resumes_interrupted_rotation() -> Result<(), StorageError> {
+        let (_, _, store) = create_path_and_storage(true)?;
+        let password: Secret<String> = Secret::from("password".to_string());
         store.write("test1", "test_value1")?;
-        assert!(store.has_key("test1")?);
-        assert!(!store.has_key("test2")?);
+        store.write("test2", "test_value2")?;
+
+        // Leave the store as if a rotation had stopped after rewriting test1.
+        let new_dek = vec![7u8; 32];
+        store.db.put(
+            DEK_ROTATION_KEY,
+            wrap_dek(&new_dek, &password, &KdfParams::default())?,
+        )?;
+        store.db.put(
+            "test1",
+            encrypt_value(&new_dek, b"test1", b"test_value1".to_vec())?,
+        )?;
+
+        store.rotate_dek(password.clone())?;
+
+        assert_eq!(
+            *unwrap_dek(store.db.get(DEK_KEY)?.unwrap(), &password, None)?,
+            new_dek
+        );
+        assert!(store.db.get(DEK_ROTATION_KEY)?.is_none());
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+        assert_eq!(store.read("test2")?, Some("test_value2".to_string()));
+
         Storage::delete_db_files(store)?;
         Ok(())
     }
 
     #[test]
-    fn test_open_storage() -> Result<(), StorageError> {
-        let (_, config, store) = create_path_and_storage(false)?;
-        store.write("test1", "test_value1")?;
+    fn test_change_password() -> Result<(), StorageError> {
+        let (path, _, store) = create_path_and_storage(true)?;
+        store.set("test1", "test_value1", None)?;
+
+        store.change_password(Secret::from("password"), Secret::from("new_password"))?;
+        assert!(matches!(
+            store.change_password(Secret::from("password"), Secret::from("other_password")),
+            Err(StorageError::WrongPassword)
+        ));
+        assert!(store.verify_password(Secret::from("new_password".to_string()))?);
+        assert!(!store.verify_password(Secret::from("password".to_string()))?);
+
         drop(store);
 
-        let open_store = Storage::open(&config);
-        assert!(open_store.is_ok());
+        let store = Storage::new_with_policy(
+            &StorageConfig {
+                path: path.to_string_lossy().to_string(),
+                password: Some(Secret::from("new_password")),
+                kdf_params: KdfParams::default(),
+                encrypt_keys: false,
+                password_policy: None,
+                rocksdb: None,
+                prefix_length: None,
+                cache_capacity: None,
+                backup_batch_size: None,
+                metrics: false,
+                max_key_length: None,
+                max_value_bytes: None,
+                compression: ValueCompression::None,
+                sync_writes: false,
+                disable_wal: false,
+                merge_operator: None,
+                lossy_utf8: false,
+            },
+            Some(PasswordPolicyConfig {
+                min_length: 1,
+                min_number_of_special_chars: 0,
+                min_number_of_uppercase: 0,
+                min_number_of_digits: 0,
+                min_number_of_lowercase: 0,
+                max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+                character_sets: CharacterSets::default(),
+            }),
+        )?;
+
         assert_eq!(
-            open_store.as_ref().unwrap().read("test1")?,
+            store.get::<String, String>("test1".to_string())?,
             Some("test_value1".to_string())
         );
+        Storage::delete_db_files(store)?;
 
-        Storage::delete_db_files(open_store.unwrap())?;
-        Ok(())
-    }
-
-    #[test]
-    fn test_open_inexistent_storage() -> Result<(), StorageError> {
-        let path = &temp_storage();
-
-        let config = StorageConfig {
-            path: path.to_string_lossy().to_string(),
-            password: Some(Secret::from("password")),
-        };
-        let open_store = Storage::open(&config);
-        assert!(open_store.is_err());
         Ok(())
     }
 
     #[test]
-    fn test_keys() -> Result<(), StorageError> {
-        let (_, _, store) = create_path_and_storage(false)?;
+    fn test_open_with_options() -> Result<(), StorageError> {
+        let (_, config, store) = create_path_and_storage(false)?;
         store.write("test1", "test_value1")?;
-        store.write("test2", "test_value2")?;
-        store.write("test3", "test_value3")?;
-        store.write("tes4", "test_value4")?;
-
-        let keys = store.keys()?;
-        assert_eq!(keys.len(), 4);
-        assert!(keys.contains(&"test1".to_string()));
-        assert!(keys.contains(&"test2".to_string()));
-        assert!(keys.contains(&"test3".to_string()));
-        assert!(keys.contains(&"tes4".to_string()));
+        drop(store);
 
+        let mut options = rocksdb::Options::default();
+        options.set_write_buffer_size(MIB);
+        let store = Storage::open_with_options(&config, options)?;
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
         Storage::delete_db_files(store)?;
-        Ok(())
-    }
 
-    #[test]
-    fn test_transaction_commit() -> Result<(), StorageError> {
-        let (_, _, store) = create_path_and_storage(false)?;
-        let transaction_id = store.begin_transaction();
-        store.transactional_write("test1", "test_value1", transaction_id)?;
-        store.transactional_write("test2", "test_value2", transaction_id)?;
-        store.commit_transaction(transaction_id)?;
+        let (_, config, store) = create_path_and_storage(true)?;
+        store.write("test1", "test_value1")?;
+        drop(store);
 
+        let policy = PasswordPolicyConfig {
+            min_length: 1,
+            min_number_of_special_chars: 0,
+            min_number_of_uppercase: 0,
+            min_number_of_digits: 0,
+            min_number_of_lowercase: 0,
+            max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+            character_sets: CharacterSets::default(),
+        };
+        let store = Storage::open_with_options_and_policy(
+            &config,
+            rocksdb::Options::default(),
+            Some(policy),
+        )?;
         assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
-        assert_eq!(store.read("test2")?, Some("test_value2".to_string()));
-        assert_eq!(store.read("test3")?, None);
-
         Storage::delete_db_files(store)?;
+
+        let missing = StorageConfig::new(temp_storage().to_string_lossy().to_string(), None);
+        assert!(Storage::open_with_options(&missing, rocksdb::Options::default()).is_err());
         Ok(())
     }
 
     #[test]
-    fn test_transaction_rollback() -> Result<(), StorageError> {
-        let (_, _, store) = create_path_and_storage(false)?;
-        let transaction_id = store.begin_transaction();
-        store.transactional_write("test1", "test_value1", transaction_id)?;
-        store.transactional_write("test2", "test_value2", transaction_id)?;
-        store.rollback_transaction(transaction_id)?;
-
-        assert_eq!(store.read("test1")?, None);
-        assert_eq!(store.read("test2")?, None);
-
-        Storage::delete_db_files(store)?;
+    fn test_is_encrypted() -> Result<(), StorageError> {
+        for is_encrypted in [false, true] {
+            let (path, _, store) = create_path_and_storage(is_encrypted)?;
+            store.write("test1", "test_value1")?;
+            assert_eq!(store.is_encrypted(), is_encrypted);
+            assert_eq!(
+                Storage::store_is_encrypted(&path.to_string_lossy())?,
+                is_encrypted
+            );
+            Storage::delete_db_files(store)?;
+        }
         Ok(())
     }
 
     #[test]
-    fn test_transactional_delete() -> Result<(), StorageError> {
-        let (_, _, store) = create_path_and_storage(false)?;
+    fn test_reserved_dek_key() -> Result<(), StorageError> {
+        let policy = PasswordPolicyConfig {
+            min_length: 1,
+            min_number_of_special_chars: 0,
+            min_number_of_uppercase: 0,
+            min_number_of_digits: 0,
+            min_number_of_lowercase: 0,
+            max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+            character_sets: CharacterSets::default(),
+        };
+        let (_, config, store) = create_path_and_storage(true)?;
+        store.write("DEK", "user value")?;
         store.write("test1", "test_value1")?;
-        let transaction_id = store.begin_transaction();
-        store.transactional_delete("test1", transaction_id).unwrap();
-        store.commit_transaction(transaction_id).unwrap();
+        assert!(matches!(
+            store.write(DEK_KEY, "value"),
+            Err(StorageError::ReservedKey(_))
+        ));
+        assert!(matches!(
+            store.delete(DEK_KEY),
+            Err(StorageError::ReservedKey(_))
+        ));
+        assert!(matches!(
+            store.read(DEK_KEY),
+            Err(StorageError::ReservedKey(_))
+        ));
+        assert!(matches!(
+            store.has_key(DEK_KEY),
+            Err(StorageError::ReservedKey(_))
+        ));
+        assert!(matches!(
+            store.get_existing(&["test1", DEK_KEY]),
+            Err(StorageError::ReservedKey(_))
+        ));
+        drop(store);
 
-        assert_eq!(store.read("test1").unwrap(), None);
+        let store = Storage::open_with_policy(&config, Some(policy.clone()))?;
+        assert_eq!(store.read("DEK")?, Some("user value".to_string()));
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+
+        // A storage created before the DEK moved keeps it under `DEK`.
+        let dek = store
+            .db
+            .get(DEK_KEY)
+            .map_err(StorageError::ReadError)?
+            .unwrap();
+        store.db.delete("DEK").map_err(StorageError::WriteError)?;
+        store.db.delete(DEK_KEY).map_err(StorageError::WriteError)?;
+        store
+            .db
+            .put(LEGACY_DEK_KEY, dek)
+            .map_err(StorageError::WriteError)?;
+        drop(store);
+
+        let without_password = StorageConfig {
+            password: None,
+            ..config.clone()
+        };
+        assert!(matches!(
+            Storage::open(&without_password),
+            Err(StorageError::PasswordRequired)
+        ));
+        let store = Storage::open_with_policy(&config, Some(policy))?;
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+        assert!(store.db.get(DEK_KEY)?.is_some());
+        assert!(!store.has_key(LEGACY_DEK_KEY)?);
 
         Storage::delete_db_files(store)?;
         Ok(())
     }
 
     #[test]
-    fn test_non_commited_transactions_should_not_appear() -> Result<(), StorageError> {
-        let (_, _, store) = create_path_and_storage(false)?;
-        let transaction_id = store.begin_transaction();
-        store
-            .transactional_write("test1", "test_value1", transaction_id)
-            .unwrap();
-        store
-            .transactional_write("test2", "test_value2", transaction_id)
-            .unwrap();
-        store.commit_transaction(transaction_id).unwrap();
+    fn test_open_with_mismatched_password() -> Result<(), StorageError> {
+        let policy = PasswordPolicyConfig {
+            min_length: 1,
+            min_number_of_special_chars: 0,
+            min_number_of_uppercase: 0,
+            min_number_of_digits: 0,
+            min_number_of_lowercase: 0,
+            max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+            character_sets: CharacterSets::default(),
+        };
+        let (_, config, store) = create_path_and_storage(true)?;
+        store.write("test1", "test_value1")?;
+        drop(store);
 
-        let second_transaction_id = store.begin_transaction();
-        store
-            .transactional_write("test3", "test_value3", second_transaction_id)
-            .unwrap();
+        let without_password = StorageConfig {
+            password: None,
+            ..config.clone()
+        };
+        assert!(matches!(
+            Storage::open(&without_password),
+            Err(StorageError::PasswordRequired)
+        ));
+        let store = Storage::open_with_policy(&config, Some(policy.clone()))?;
+        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
+        Storage::delete_db_files(store)?;
 
-        assert_eq!(
-            store.read("test1").unwrap(),
-            Some("test_value1".to_string())
-        );
-        assert_eq!(
-            store.read("test2").unwrap(),
-            Some("test_value2".to_string())
-        );
-        assert_eq!(store.read("test3").unwrap(), None);
-        store.rollback_transaction(second_transaction_id).unwrap();
+        let (_, config, store) = create_path_and_storage(false)?;
+        store.write("test1", "test_value1")?;
+        drop(store);
 
+        let with_password = StorageConfig {
+            password: Some(Secret::from("password".to_string())),
+            ..config.clone()
+        };
+        assert!(matches!(
+            Storage::open_with_policy(&with_password, Some(policy)),
+            Err(StorageError::NotEncrypted)
+        ));
+        let store = Storage::open(&config)?;
+        assert!(store.db.get(DEK_KEY)?.is_none());
         Storage::delete_db_files(store)?;
+
         Ok(())
     }
 
     #[test]
-    fn test_encrypt_and_decrypt() -> Result<(), StorageError> {
+    fn test_verify_password() -> Result<(), StorageError> {
         let (_, _, store) = create_path_and_storage(true)?;
         store.set("test1", "test_value1", None)?;
-        let data = store.get::<String, String>("test1".to_string())?;
-        assert!(data.is_some());
-        assert_eq!(data.unwrap(), "test_value1");
 
-        store.set("test1", "test_value2", None)?;
-        let data = store.get::<String, String>("test1".to_string())?;
-        assert!(data.is_some());
-        assert_eq!(data.unwrap(), "test_value2");
+        assert!(store.verify_password(Secret::from("password".to_string()))?);
+        assert!(!store.verify_password(Secret::from("wrong_password".to_string()))?);
 
         Storage::delete_db_files(store)?;
-        Ok(())
-    }
 
-    #[test]
-    fn test_backup() -> Result<(), StorageError> {
-        let (backup_path, dek_path) = temp_backup();
-        let password = Secret::from("password");
         let (_, _, store) = create_path_and_storage(false)?;
-        store.write("test1", "test_value1")?;
-        store.write("test2", "test_value2")?;
-        store.backup(&backup_path, &dek_path, password)?;
-        assert!(backup_path.exists());
-        assert!(dek_path.exists());
+        assert!(matches!(
+            store.verify_password(Secret::from("password".to_string())),
+            Err(StorageError::NoPasswordSet)
+        ));
+        assert!(store.db.get(DEK_KEY)?.is_none());
+        Storage::delete_db_files(store)?;
 
         Ok(())
     }
 
     #[test]
-    fn test_restore_backup() -> Result<(), StorageError> {
+    fn test_storage_with_kdf_params() -> Result<(), StorageError> {
         let (backup_path, dek_path) = temp_backup();
-        let password = Secret::from("password");
-        let (_, config, store) = create_path_and_storage(false)?;
-        store.write("test1", "test_value1")?;
-        store.write("test2", "test_value2")?;
+        let password: Secret<String> = Secret::from("password".to_string());
+        let policy = PasswordPolicyConfig {
+            min_length: 1,
+            min_number_of_special_chars: 0,
+            min_number_of_uppercase: 0,
+            min_number_of_digits: 0,
+            min_number_of_lowercase: 0,
+            max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+            character_sets: CharacterSets::default(),
+        };
+        let config = StorageConfig {
+            path: temp_storage().to_string_lossy().to_string(),
+            password: Some(password.clone()),
+            kdf_params: KdfParams {
+                dek_work_factor: Some(15),
+                backup_work_factor: Some(15),
+                max_work_factor: None,
+            },
+            encrypt_keys: false,
+            password_policy: None,
+            rocksdb: None,
+            prefix_length: None,
+            cache_capacity: None,
+            backup_batch_size: None,
+            metrics: false,
+            max_key_length: None,
+            max_value_bytes: None,
+            compression: ValueCompression::None,
+            sync_writes: false,
+            disable_wal: false,
+            merge_operator: None,
+            lossy_utf8: false,
+        };
+
+        let store = Storage::new_with_policy(&config, Some(policy.clone()))?;
+        assert!(store.db.get(DEK_KEY)?.unwrap().starts_with(AGE_MAGIC));
+        store.set("test1", "test_value1", None)?;
         store.backup(&backup_path, &dek_path, password.clone())?;
+        drop(store);
 
-        Storage::delete_db_files(store)?;
-        let store = Storage::new(&config)?;
-        store.restore_backup(&backup_path, &dek_path, password)?;
+        // A store configured with lower costs still opens and restores what the costlier
+        // configuration wrote, as long as its limit allows it.
+        let mut lower_config = StorageConfig::new(config.path.clone(), Some(password.clone()));
+        lower_config.kdf_params = KdfParams {
+            dek_work_factor: None,
+            backup_work_factor: Some(10),
+            max_work_factor: Some(15),
+        };
+        let store = Storage::new_with_policy(&lower_config, Some(policy.clone()))?;
+        store.delete("test1")?;
+        store.restore_backup(
+            &backup_path,
+            &dek_path,
+            password.clone(),
+            RestoreMode::Merge,
+        )?;
+        assert_eq!(
+            store.get::<&str, String>("test1")?,
+            Some("test_value1".to_string())
+        );
+        drop(store);
 
-        assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
-        assert_eq!(store.read("test2")?, Some("test_value2".to_string()));
+        let mut capped_config = lower_config.clone();
+        capped_config.kdf_params.max_work_factor = Some(12);
+        assert!(matches!(
+            Storage::new_with_policy(&capped_config, Some(policy.clone())),
+            Err(StorageError::DekDecryptFailed(
+                age::DecryptError::ExcessiveWork { required: 15, .. }
+            ))
+        ));
+
+        let default_config = StorageConfig::new(config.path.clone(), Some(password));
+        let store = Storage::new_with_policy(&default_config, Some(policy))?;
+        assert_eq!(
+            store.get::<&str, String>("test1")?,
+            Some("test_value1".to_string())
+        );
 
         Storage::delete_db_files(store)?;
         fs::remove_file(backup_path)?;
@@ -922,62 +6805,90 @@ mod tests {
     }
 
     #[test]
-    fn test_more_than_1000_values_to_backup() -> Result<(), StorageError> {
-        let quantity = 1500;
+    fn test_encrypted_keys() -> Result<(), StorageError> {
         let (backup_path, dek_path) = temp_backup();
-        let password = Secret::from("password");
-        let (_, config, store) = create_path_and_storage(false)?;
-        for i in 0..quantity {
-            store.write(&format!("test{}", i), &format!("test_value{}", i))?;
-        }
-        store.backup(&backup_path, &dek_path, password.clone())?;
-        assert!(backup_path.exists());
+        let password: Secret<String> = Secret::from("password".to_string());
+        let policy = PasswordPolicyConfig {
+            min_length: 1,
+            min_number_of_special_chars: 0,
+            min_number_of_uppercase: 0,
+            min_number_of_digits: 0,
+            min_number_of_lowercase: 0,
+            max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+            character_sets: CharacterSets::default(),
+        };
+        let config = StorageConfig {
+            path: temp_storage().to_string_lossy().to_string(),
+            password: Some(password.clone()),
+            kdf_params: KdfParams::default(),
+            encrypt_keys: true,
+            password_policy: None,
+            rocksdb: None,
+            prefix_length: None,
+            cache_capacity: None,
+            backup_batch_size: None,
+            metrics: false,
+            max_key_length: None,
+            max_value_bytes: None,
+            compression: ValueCompression::None,
+            sync_writes: false,
+            disable_wal: false,
+            merge_operator: None,
+            lossy_utf8: false,
+        };
 
-        Storage::delete_db_files(store)?;
+        let store = Storage::new_with_policy(&config, Some(policy.clone()))?;
+        store.write("job/1/a", "value1")?;
+        store.write("job/1/b", "value2")?;
+        store.write("job/2/a", "value3")?;
+        store.delete("job/2/a")?;
 
-        let store = Storage::new(&config)?;
-        store.restore_backup(&backup_path, &dek_path, password)?;
+        assert_eq!(store.read("job/1/a")?, Some("value1".to_string()));
+        assert!(store.has_key("job/1/b")?);
+        assert!(!store.has_key("job/2/a")?);
 
-        for i in 0..quantity {
-            assert_eq!(
-                store.read(&format!("test{}", i))?,
-                Some(format!("test_value{}", i).to_string())
-            );
+        let mut keys = store.partial_compare_keys("job/1/")?;
+        keys.sort();
+        assert_eq!(keys, vec!["job/1/a".to_string(), "job/1/b".to_string()]);
+
+        let mut iter = store.db.iterator(rocksdb::IteratorMode::Start);
+        while let Some(Ok((k, _))) = iter.next() {
+            assert!(!k.starts_with(b"job/"));
         }
 
+        store.backup(&backup_path, &dek_path, password.clone())?;
+        let (_, _, plain_store) = create_path_and_storage(false)?;
+        plain_store.restore_backup(&backup_path, &dek_path, password, RestoreMode::Merge)?;
+        assert_eq!(plain_store.read("job/1/b")?, Some("value2".to_string()));
+
         Storage::delete_db_files(store)?;
+        Storage::delete_db_files(plain_store)?;
         fs::remove_file(backup_path)?;
         fs::remove_file(dek_path)?;
-        Ok(())
-    }
-
-    #[test]
-    fn test_change_password() -> Result<(), StorageError> {
-        let (path, _, store) = create_path_and_storage(true)?;
-        store.set("test1", "test_value1", None)?;
-
-        store.change_password(Secret::from("password"), Secret::from("new_password"))?;
-
-        drop(store);
-
-        let store = Storage::new_with_policy(
-            &StorageConfig {
-                path: path.to_string_lossy().to_string(),
-                password: Some(Secret::from("new_password")),
-            },
-            Some(PasswordPolicyConfig {
-                min_length: 1,
-                min_number_of_special_chars: 0,
-                min_number_of_uppercase: 0,
-                min_number_of_digits: 0,
-            }),
-        )?;
 
-        assert_eq!(
-            store.get::<String, String>("test1".to_string())?,
-            Some("test_value1".to_string())
-        );
-        Storage::delete_db_files(store)?;
+        let config = StorageConfig {
+            path: temp_storage().to_string_lossy().to_string(),
+            password: None,
+            kdf_params: KdfParams::default(),
+            encrypt_keys: true,
+            password_policy: None,
+            rocksdb: None,
+            prefix_length: None,
+            cache_capacity: None,
+            backup_batch_size: None,
+            metrics: false,
+            max_key_length: None,
+            max_value_bytes: None,
+            compression: ValueCompression::None,
+            sync_writes: false,
+            disable_wal: false,
+            merge_operator: None,
+            lossy_utf8: false,
+        };
+        assert!(matches!(
+            Storage::new_with_policy(&config, Some(policy)),
+            Err(StorageError::NoPasswordSet)
+        ));
 
         Ok(())
     }
@@ -993,12 +6904,30 @@ mod tests {
             &StorageConfig {
                 path: path.to_string_lossy().to_string(),
                 password: None,
+                kdf_params: KdfParams::default(),
+                encrypt_keys: false,
+                password_policy: None,
+                rocksdb: None,
+                prefix_length: None,
+                cache_capacity: None,
+                backup_batch_size: None,
+                metrics: false,
+                max_key_length: None,
+                max_value_bytes: None,
+                compression: ValueCompression::None,
+                sync_writes: false,
+                disable_wal: false,
+                merge_operator: None,
+                lossy_utf8: false,
             },
             Some(PasswordPolicyConfig {
                 min_length: 1,
                 min_number_of_special_chars: 0,
                 min_number_of_uppercase: 0,
                 min_number_of_digits: 0,
+                min_number_of_lowercase: 0,
+                max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+                character_sets: CharacterSets::default(),
             }),
         )?;
 
@@ -1011,16 +6940,34 @@ mod tests {
             &StorageConfig {
                 path: path.to_string_lossy().to_string(),
                 password: None,
+                kdf_params: KdfParams::default(),
+                encrypt_keys: false,
+                password_policy: None,
+                rocksdb: None,
+                prefix_length: None,
+                cache_capacity: None,
+                backup_batch_size: None,
+                metrics: false,
+                max_key_length: None,
+                max_value_bytes: None,
+                compression: ValueCompression::None,
+                sync_writes: false,
+                disable_wal: false,
+                merge_operator: None,
+                lossy_utf8: false,
             },
             Some(PasswordPolicyConfig {
                 min_length: 1,
                 min_number_of_special_chars: 0,
                 min_number_of_uppercase: 0,
                 min_number_of_digits: 0,
+                min_number_of_lowercase: 0,
+                max_length: DEFAULT_MAX_PASSWORD_LENGTH,
+                character_sets: CharacterSets::default(),
             }),
         )?;
 
-        store.restore_backup(&backup_path, &dek_path, new_password)?;
+        store.restore_backup(&backup_path, &dek_path, new_password, RestoreMode::Merge)?;
 
         assert_eq!(store.read("test1")?, Some("test_value1".to_string()));
 
@@ -0,0 +1,115 @@
+use crate::{
+    codec::{Codec, JsonCodec},
+    error::StorageError,
+    password_policy::PasswordPolicy,
+    storage::{password_policy, Storage},
+    storage_config::StorageConfig,
+};
+use redact::Secret;
+use std::marker::PhantomData;
+
+/// Builds a `Storage` through a single entry point instead of choosing among the `new`/`open`
+/// constructors.
+///
+/// ```ignore
+/// let storage = Storage::builder()
+///     .path("path/to/database")
+///     .password(Secret::from("password".to_string()))
+///     .create_if_missing(true)
+///     .open()?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct StorageBuilder<C = JsonCodec> {
+    config: StorageConfig,
+    password_policy: Option<PasswordPolicy>,
+    create_if_missing: bool,
+    read_only: bool,
+    codec: PhantomData<C>,
+}
+
+impl Default for StorageBuilder {
+    fn default() -> Self {
+        Self::from_config(StorageConfig::new(String::new(), None))
+    }
+}
+
+impl StorageBuilder {
+    /// Starts from an existing config, keeping the settings that have no builder method.
+    pub fn from_config(config: StorageConfig) -> Self {
+        StorageBuilder {
+            config,
+            password_policy: None,
+            create_if_missing: false,
+            read_only: false,
+            codec: PhantomData,
+        }
+    }
+}
+
+impl<C: Codec> StorageBuilder<C> {
+    pub fn path<P: Into<String>>(mut self, path: P) -> Self {
+        self.config.path = path.into();
+        self
+    }
+
+    pub fn password(mut self, password: Secret<String>) -> Self {
+        self.config.password = Some(password);
+        self
+    }
+
+    /// Overrides `StorageConfig::password_policy`. Accepts a `PasswordPolicyConfig` or an already
+    /// built `PasswordPolicy`.
+    pub fn password_policy<P: Into<PasswordPolicy>>(mut self, password_policy: P) -> Self {
+        self.password_policy = Some(password_policy.into());
+        self
+    }
+
+    /// Creates the storage if it does not exist yet. Defaults to `false`, like `Storage::open`.
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Rejects every write with `StorageError::ReadOnly`. RocksDB still opens the storage for
+    /// writing, so it cannot be shared with another process.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets the format `KeyValueStore::get`/`set` store values in. Defaults to `JsonCodec`.
+    pub fn codec<D: Codec>(self) -> StorageBuilder<D> {
+        StorageBuilder {
+            config: self.config,
+            password_policy: self.password_policy,
+            create_if_missing: self.create_if_missing,
+            read_only: self.read_only,
+            codec: PhantomData,
+        }
+    }
+
+    pub fn open(self) -> Result<Storage<C>, StorageError> {
+        if self.config.path.is_empty() {
+            return Err(StorageError::InvalidConfig(
+                "no storage path set".to_string(),
+            ));
+        }
+        if self.read_only && self.create_if_missing {
+            return Err(StorageError::InvalidConfig(
+                "a read-only storage cannot be created".to_string(),
+            ));
+        }
+
+        let password_policy = match self.password_policy {
+            Some(password_policy) => password_policy,
+            None => password_policy(&self.config, None)?,
+        };
+
+        Storage::open_db(
+            &self.config,
+            password_policy,
+            self.create_if_missing,
+            self.read_only,
+        )
+    }
+}
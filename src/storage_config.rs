@@ -1,5 +1,10 @@
+use crate::error::StorageError;
 use redact::Secret;
 use serde::Deserialize;
+use std::{fs, path::Path};
+
+/// Used as `max_length` when a config does not set one, so older configs accept any length.
+pub const DEFAULT_MAX_PASSWORD_LENGTH: usize = usize::MAX;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct PasswordPolicyConfig {
@@ -7,16 +12,249 @@ pub struct PasswordPolicyConfig {
     pub min_number_of_special_chars: usize,
     pub min_number_of_uppercase: usize,
     pub min_number_of_digits: usize,
+    #[serde(default)]
+    pub min_number_of_lowercase: usize,
+    #[serde(default = "default_max_password_length")]
+    pub max_length: usize,
+    #[serde(default)]
+    pub character_sets: CharacterSets,
+}
+
+/// Characters each `min_number_of_*` rule counts. A set left unset keeps the built-in one from
+/// `password_policy`, so a policy can require or exclude specific symbols.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct CharacterSets {
+    #[serde(default)]
+    pub special: Option<String>,
+    #[serde(default)]
+    pub uppercase: Option<String>,
+    #[serde(default)]
+    pub digits: Option<String>,
+    #[serde(default)]
+    pub lowercase: Option<String>,
+}
+
+impl PasswordPolicyConfig {
+    /// Rejects a policy no password can satisfy within `min_length`, because its character class
+    /// minimums add up to more than `min_length`, `max_length` is below `min_length`, or a class
+    /// with a minimum has an empty character set.
+    pub fn validate(&self) -> Result<(), StorageError> {
+        let class_minimums = [
+            self.min_number_of_special_chars,
+            self.min_number_of_uppercase,
+            self.min_number_of_digits,
+            self.min_number_of_lowercase,
+        ]
+        .into_iter()
+        .fold(0usize, usize::saturating_add);
+        if class_minimums > self.min_length {
+            return Err(StorageError::InvalidConfig(format!(
+                "password policy requires {} classed characters but min_length is {}",
+                class_minimums, self.min_length
+            )));
+        }
+        if self.max_length < self.min_length {
+            return Err(StorageError::InvalidConfig(format!(
+                "password policy max_length {} is below min_length {}",
+                self.max_length, self.min_length
+            )));
+        }
+
+        let sets = &self.character_sets;
+        for (name, set, minimum) in [
+            ("special", &sets.special, self.min_number_of_special_chars),
+            ("uppercase", &sets.uppercase, self.min_number_of_uppercase),
+            ("digits", &sets.digits, self.min_number_of_digits),
+            ("lowercase", &sets.lowercase, self.min_number_of_lowercase),
+        ] {
+            if minimum > 0 && set.as_ref().is_some_and(String::is_empty) {
+                return Err(StorageError::InvalidConfig(format!(
+                    "password policy requires {} {} characters but their set is empty",
+                    minimum, name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_max_password_length() -> usize {
+    DEFAULT_MAX_PASSWORD_LENGTH
+}
+
+/// Key derivation cost used when wrapping DEKs with a password.
+///
+/// The defaults keep the previous behavior: Cocoon's PBKDF2 with 100 000 iterations for DEKs and
+/// an age scrypt work factor calibrated to take about one second on the current machine for
+/// backups.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct KdfParams {
+    /// scrypt work factor (log2 of N, between 1 and 63) for wrapping DEKs. When set, DEKs are
+    /// wrapped with age scrypt instead of Cocoon, whose PBKDF2 cost is fixed at 100 000
+    /// iterations and cannot be raised. Both formats keep opening whatever this is set to.
+    #[serde(default)]
+    pub dek_work_factor: Option<u8>,
+    /// scrypt work factor (log2 of N, between 1 and 63) backups are written with.
+    #[serde(default)]
+    pub backup_work_factor: Option<u8>,
+    /// Highest scrypt work factor accepted when unwrapping a DEK or reading a backup, whatever
+    /// the factors above are. Defaults to age's limit of about 16 times the calibrated factor.
+    #[serde(default)]
+    pub max_work_factor: Option<u8>,
+}
+
+/// Compression RocksDB applies to the storage files.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+/// Compression applied to each value before it is encrypted. Encrypted values look random, so
+/// RocksDB cannot compress them itself.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueCompression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+/// How RocksDB combines the operands `Storage::merge` writes with the value of a key.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeOperator {
+    /// Appends each operand, a JSON value, to the JSON array stored under the key. A missing key
+    /// starts an empty array, and a value that is not an array becomes its first item.
+    JsonArrayAppend,
+    /// Replaces the value with the last operand.
+    LastWriteWins,
+}
+
+/// RocksDB tuning knobs. Fields left unset keep the RocksDB defaults.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RocksDbOptions {
+    /// Size of the LRU block cache, in MiB.
+    #[serde(default)]
+    pub block_cache_mb: Option<usize>,
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Size of a memtable before it is flushed to disk, in MiB.
+    #[serde(default)]
+    pub write_buffer_mb: Option<usize>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct StorageConfig {
     pub path: String,
     pub password: Option<Secret<String>>,
+    #[serde(default)]
+    pub kdf_params: KdfParams,
+    /// Encrypts keys as well as values. Requires a password, and must be set the same way every
+    /// time the storage is opened. Keys are encrypted deterministically, so lookups stay direct,
+    /// but they are stored and iterated in ciphertext order: `keys` is no longer sorted, and
+    /// prefix scans read the whole storage.
+    #[serde(default)]
+    pub encrypt_keys: bool,
+    /// Policy applied when no policy is passed to `Storage::new_with_policy`/`open_with_policy`.
+    #[serde(default)]
+    pub password_policy: Option<PasswordPolicyConfig>,
+    #[serde(default)]
+    pub rocksdb: Option<RocksDbOptions>,
+    /// Length of the fixed key prefix used to build prefix bloom filters. Scans for a prefix at
+    /// least this long only visit keys sharing its first `prefix_length` bytes. Has no effect when
+    /// `encrypt_keys` is set.
+    #[serde(default)]
+    pub prefix_length: Option<usize>,
+    /// Number of decrypted values `read` and `get` keep in memory, evicting the least recently
+    /// used one. Values written with a TTL are never cached. No cache when unset or zero.
+    #[serde(default)]
+    pub cache_capacity: Option<usize>,
+    /// Number of entries `backup` writes between two flushes of the backup file, each followed by
+    /// a progress report. Defaults to 1000.
+    #[serde(default)]
+    pub backup_batch_size: Option<usize>,
+    /// Counts reads, writes, deletes, cache hits and transaction outcomes, see `Storage::metrics`.
+    /// Off by default, so the counters cost nothing unless asked for.
+    #[serde(default)]
+    pub metrics: bool,
+    /// Longest key, in bytes, that writes accept. Longer keys are rejected with
+    /// `StorageError::InvalidKey`. No limit when unset.
+    #[serde(default)]
+    pub max_key_length: Option<usize>,
+    /// Largest value, in bytes, that writes accept, checked before the value is compressed and
+    /// encrypted in memory. Larger values are rejected with `StorageError::ValueTooLarge`. No
+    /// limit when unset.
+    #[serde(default)]
+    pub max_value_bytes: Option<usize>,
+    /// Compression applied to values as they are written. Each value records how it was
+    /// compressed, so changing this keeps older values readable.
+    #[serde(default)]
+    pub compression: ValueCompression,
+    /// Syncs the write-ahead log to disk before a commit returns, so a committed write survives an
+    /// operating system crash or power loss. Without it a commit returns once the log is written
+    /// to the OS, which only protects against a crash of the process. Off by default.
+    #[serde(default)]
+    pub sync_writes: bool,
+    /// Skips the write-ahead log. Writes only reach disk when RocksDB flushes its memtables,
+    /// which it does when the storage is closed, so any crash loses every write since the last
+    /// flush. Meant for bulk loads that can be started over. Cannot be combined with
+    /// `sync_writes`.
+    #[serde(default)]
+    pub disable_wal: bool,
+    /// Merge operator registered with RocksDB, which `Storage::merge` writes operands for.
+    /// Merging works on stored values, so it cannot be used with a password. A storage holding
+    /// merged keys has to be opened with the same operator every time.
+    #[serde(default)]
+    pub merge_operator: Option<MergeOperator>,
+    /// Lets `keys` and `partial_compare_keys` return keys that are not valid UTF-8, with the
+    /// invalid bytes replaced by U+FFFD, instead of failing the whole listing with
+    /// `StorageError::ConversionError`. The keys returned may then not read back. Off by default.
+    #[serde(default)]
+    pub lossy_utf8: bool,
 }
 
 impl StorageConfig {
     pub fn new(path: String, password: Option<Secret<String>>) -> Self {
-        Self { path, password }
+        Self {
+            path,
+            password,
+            kdf_params: KdfParams::default(),
+            encrypt_keys: false,
+            password_policy: None,
+            rocksdb: None,
+            prefix_length: None,
+            cache_capacity: None,
+            backup_batch_size: None,
+            metrics: false,
+            max_key_length: None,
+            max_value_bytes: None,
+            compression: ValueCompression::None,
+            sync_writes: false,
+            disable_wal: false,
+            merge_operator: None,
+            lossy_utf8: false,
+        }
+    }
+
+    /// Loads a config from a TOML file, or from JSON if the file extension is not `.toml`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<StorageConfig, StorageError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+
+        let is_toml = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("toml"));
+        if is_toml {
+            toml::from_str(&contents)
+                .map_err(|error| StorageError::InvalidConfig(error.to_string()))
+        } else {
+            serde_json::from_str(&contents)
+                .map_err(|error| StorageError::InvalidConfig(error.to_string()))
+        }
     }
 }
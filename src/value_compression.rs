@@ -0,0 +1,40 @@
+use crate::{error::StorageError, storage_config::ValueCompression};
+
+// Header bytes of compressed values. Like the TTL marker, they can never start UTF-8 text or a
+// well-formed CBOR item, so values written without compression keep reading back as they are.
+const LZ4_MARKER: u8 = 0xfe;
+const ZSTD_MARKER: u8 = 0xfd;
+
+/// Compresses `data` and prepends the header of `compression`. The data is returned unchanged
+/// when compression is off or would not make it smaller.
+pub fn compress(compression: ValueCompression, data: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+    let (marker, compressed) = match compression {
+        ValueCompression::None => return Ok(data),
+        ValueCompression::Lz4 => (LZ4_MARKER, lz4_flex::compress_prepend_size(&data)),
+        ValueCompression::Zstd => (
+            ZSTD_MARKER,
+            zstd::bulk::compress(&data, zstd::DEFAULT_COMPRESSION_LEVEL)?,
+        ),
+    };
+
+    if compressed.len() + 1 >= data.len() {
+        return Ok(data);
+    }
+    let mut value = Vec::with_capacity(compressed.len() + 1);
+    value.push(marker);
+    value.extend_from_slice(&compressed);
+    Ok(value)
+}
+
+/// Reverses `compress` according to the header of `data`, whatever compression the storage is
+/// configured with now.
+pub fn decompress(data: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+    match data.first() {
+        Some(&LZ4_MARKER) => lz4_flex::decompress_size_prepended(&data[1..])
+            .map_err(|_| StorageError::FailedToDecompressData),
+        Some(&ZSTD_MARKER) => {
+            zstd::decode_all(&data[1..]).map_err(|_| StorageError::FailedToDecompressData)
+        }
+        _ => Ok(data),
+    }
+}